@@ -106,6 +106,11 @@ fn boot_lua() -> LuaResult<()> {
 
     weavedrive::preload(lua)?;
     models::bert::preload(lua)?;
+    models::tokenizer::preload(lua)?;
+    models::t5::preload(lua)?;
+    models::text_generation::preload(lua)?;
+    models::translation::preload(lua)?;
+    models::stable_diffusion::preload(lua)?;
     utils::preload_serde_json(lua)?;
     utils::mock_non_deterministic_globals(lua)?;
     aos_process::preload(&lua)?;
@@ -143,6 +148,7 @@ pub extern "C" fn handle(arg0: *const c_char, arg1: *const c_char) -> *const c_c
             },
         }
     };
+    utils::set_message_seed(arg0_str);
     match boot_lua() {
         Ok(_) => (),
         Err(err) => {
@@ -222,6 +228,11 @@ mod tests {
         assert!(loaded.contains_key(".eval").unwrap());
         assert!(loaded.contains_key(".default").unwrap());
         assert!(loaded.contains_key(".handlers").unwrap());
+        assert!(loaded.contains_key("tokenizer").unwrap());
+        assert!(loaded.contains_key("t5").unwrap());
+        assert!(loaded.contains_key("text_generation").unwrap());
+        assert!(loaded.contains_key("translation").unwrap());
+        assert!(loaded.contains_key("text_to_image").unwrap());
     }
 
     // #[test]
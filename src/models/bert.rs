@@ -1,21 +1,164 @@
-use base64::prelude::{BASE64_STANDARD as b64, Engine};
+//! Sentence embeddings and token tasks over the BERT-family encoders candle implements:
+//! BERT, RoBERTa, XLM-RoBERTa, CamemBERT, DistilBERT and JinaBERT.  NomicBERT checkpoints
+//! are rejected, candle 0.5.1 has no implementation of its rotary, SwiGLU encoder.
 use mlua::prelude::*;
 
-use crate::models::common;
+use crate::models::{common, fill_mask, token_classification};
+use crate::models::common::{is_gguf, load_gguf_dequantized, EncodedVector, OutputFormat};
 use candle_transformers::models::bert::{BertModel, Config, HiddenAct, DTYPE};
-use candle_core::{Device, Tensor};
+use candle_transformers::models::{distilbert, jina_bert};
+use candle_core::{DType, Device, Module, Result as CandleResult, Tensor};
 use candle_nn::VarBuilder;
 use mlua::UserData;
 // use rayon::ThreadPoolBuilder;
-use tokenizers::Tokenizer;
+use tokenizers::{Tokenizer, TruncationParams};
 use crate::ao_log;
 
 
 #[derive(serde::Serialize, serde::Deserialize)]
 struct Embedding {
-    data: Vec<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    data: Option<EncodedVector>,
+    /// How `data` is encoded, one of `json`, `base64_f32le`, `int8` or `binary`.
+    output_format: String,
+    /// Number of values per vector, after any `dimensions` truncation.
+    dimensions: usize,
+    /// Dequantization scale for `int8` output.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scale: Option<f32>,
     prompt: String,
     model_id: String,
+    /// Per-window embeddings, only set for `long_text` with `chunk_aggregation = "none"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chunks: Option<Vec<Chunk>>,
+}
+
+/// One window of a long prompt, with the character span of the text it covers.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Chunk {
+    data: EncodedVector,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scale: Option<f32>,
+    start: usize,
+    end: usize,
+    /// Number of (non-special) tokens in the window.
+    tokens: usize,
+}
+
+/// The character span of one window of a long prompt, before its embedding is encoded.
+struct Window {
+    start: usize,
+    end: usize,
+    /// Number of (non-special) tokens in the window.
+    tokens: usize,
+}
+
+/// How the window embeddings of a `long_text` prompt are combined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ChunkAggregation {
+    /// Return every window's embedding with its character span.
+    None,
+    /// Plain average of the window embeddings.
+    Mean,
+    /// Average weighted by the number of tokens in each window.
+    Weighted,
+}
+
+impl ChunkAggregation {
+    fn from_str(s: &str) -> LuaResult<Self> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(ChunkAggregation::None),
+            "mean" => Ok(ChunkAggregation::Mean),
+            "weighted" => Ok(ChunkAggregation::Weighted),
+            _ => Err(LuaError::RuntimeError(
+                format!("invalid chunk_aggregation: {} (expected none, mean or weighted)", s)
+            )),
+        }
+    }
+}
+
+/// The encoder architectures the embedding pipeline can run.
+///
+/// BERT, RoBERTa, XLM-RoBERTa and CamemBERT all share candle's `BertModel` (see
+/// `Args::position_offset` for the RoBERTa position shift), the others use their own candle
+/// implementation.
+pub(crate) enum EncoderModel {
+    Bert(BertModel),
+    DistilBert(distilbert::DistilBertModel),
+    JinaBert(jina_bert::BertModel),
+}
+
+/// Which `EncoderModel` a checkpoint needs, read from `config.json`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Architecture {
+    Bert,
+    DistilBert,
+    JinaBert,
+}
+
+/// The prefix the encoder tensors of a `model_type` are saved under when they have one:
+/// XLM-RoBERTa and CamemBERT checkpoints are `RobertaModel`s and use `roberta.`.
+pub(crate) fn weight_prefix(model_type: &str) -> &str {
+    match model_type {
+        "roberta" | "xlm-roberta" | "camembert" => "roberta",
+        other => other,
+    }
+}
+
+impl Architecture {
+    /// Picks the architecture from `architectures` first, since JinaBERT checkpoints declare
+    /// `model_type = "bert"`, then falls back to `model_type`.
+    fn from_config(config: &serde_json::Value) -> LuaResult<Self> {
+        let architectures: Vec<&str> = config.get("architectures")
+            .and_then(|a| a.as_array())
+            .map(|a| a.iter().filter_map(|a| a.as_str()).collect())
+            .unwrap_or_default();
+        if architectures.iter().any(|a| a.starts_with("JinaBert")) {
+            return Ok(Architecture::JinaBert);
+        }
+        let model_type = config.get("model_type").and_then(|m| m.as_str()).unwrap_or("bert");
+        if model_type == "nomic_bert" || architectures.iter().any(|a| a.starts_with("NomicBert")) {
+            return Err(LuaError::RuntimeError(
+                "NomicBERT is not supported, candle 0.5.1 has no implementation of it".to_string()
+            ));
+        }
+        match model_type {
+            "bert" | "roberta" | "xlm-roberta" | "camembert" => Ok(Architecture::Bert),
+            "distilbert" => Ok(Architecture::DistilBert),
+            "jina_bert" => Ok(Architecture::JinaBert),
+            _ => Err(LuaError::RuntimeError(format!(
+                "unsupported encoder model_type: {} (supported: bert, roberta, xlm-roberta, camembert, distilbert, jina_bert)",
+                model_type
+            ))),
+        }
+    }
+}
+
+impl EncoderModel {
+    /// Returns the last hidden states, `(1, n_tokens, hidden_size)`, attending to every token.
+    fn forward(&self, token_ids: &Tensor) -> CandleResult<Tensor> {
+        match self {
+            EncoderModel::Bert(model) => model.forward(token_ids, &token_ids.zeros_like()?),
+            EncoderModel::DistilBert(model) => {
+                let n_tokens = token_ids.dim(1)?;
+                let mask = Tensor::zeros((n_tokens, n_tokens), DType::U8, token_ids.device())?;
+                model.forward(token_ids, &mask)
+            }
+            EncoderModel::JinaBert(model) => model.forward(token_ids),
+        }
+    }
+}
+
+/// tokenizers underflows or panics unless the stride of a `window` with `n_special` special
+/// tokens leaves room for new tokens.
+fn check_chunk_overlap(window: usize, chunk_overlap: usize, n_special: usize) -> LuaResult<()> {
+    if window <= n_special || chunk_overlap >= window - n_special {
+        return Err(LuaError::RuntimeError(format!(
+            "chunk_overlap ({}) must be smaller than chunk_size ({}) minus the {} special tokens",
+            chunk_overlap, window, n_special
+        )));
+    }
+    Ok(())
 }
 
 impl Embedding {
@@ -27,32 +170,95 @@ impl Embedding {
 impl UserData for Embedding {}
 
 #[derive(Debug)]
-struct Args {
+pub(crate) struct Args {
     /// The model to use, check out available models: https://huggingface.co/models?library=sentence-transformers&sort=trending
     // model_id: Option<String>,
-    model: String, //Vec<u8>, //String,
-    config: String,
-    tokenizer: String, //Vec<u8>, //String,
+    pub(crate) model: Vec<u8>,
+    pub(crate) config: Vec<u8>,
+    pub(crate) tokenizer: Vec<u8>,
     // revision: Option<String>,
     /// The text to encode.
-    prompt: String,
+    pub(crate) prompt: String,
     /// Use the pytorch weights rather than the safetensors ones
     // use_pth: bool,
     /// L2 normalization for embeddings. default_value = "true"
-    normalize_embeddings: bool,
+    pub(crate) normalize_embeddings: bool,
     /// Use tanh based approximation for Gelu instead of erf implementation. default_value = "false"
-    approximate_gelu: bool,
-    device: Device,
+    pub(crate) approximate_gelu: bool,
+    /// Split prompts longer than the model's position limit into overlapping windows. default_value = "false"
+    pub(crate) long_text: bool,
+    /// Tokens per window including special tokens, capped at `max_position_embeddings`.
+    pub(crate) chunk_size: Option<usize>,
+    /// Tokens shared between consecutive windows. default_value = "32"
+    pub(crate) chunk_overlap: usize,
+    pub(crate) chunk_aggregation: ChunkAggregation,
+    /// Serialization of the output vectors. default_value = "json"
+    pub(crate) output_format: OutputFormat,
+    /// Matryoshka truncation, keep only this many leading dimensions.
+    pub(crate) dimensions: Option<usize>,
+    pub(crate) device: Device,
 }
 
 impl Args {
-    fn build_config(&self) -> LuaResult<Config> {
-        let config = b64.decode(&self.config)
+    /// Reads the model, config and tokenizer (and optional tuning flags) out of the Lua
+    /// options table.  `prompt` falls back to an empty string, since not every task takes one.
+    pub(crate) fn from_table(table: &LuaTable) -> LuaResult<Self> {
+        let dimensions: Option<usize> = table.get("dimensions")?;
+        if dimensions == Some(0) {
+            return Err(LuaError::RuntimeError("dimensions must be at least 1".to_string()));
+        }
+        Ok(Args {
+            model: common::load_bytes(table.get("model")?, "model")?,
+            config: common::load_bytes(table.get("config")?, "config")?,
+            tokenizer: common::load_bytes(table.get("tokenizer")?, "tokenizer")?,
+            prompt: table.get("prompt").unwrap_or(String::new()),
+            normalize_embeddings: table.get("normalize_embeddings").unwrap_or(true),
+            approximate_gelu: table.get("approximate_gelu").unwrap_or(false),
+            long_text: table.get("long_text").unwrap_or(false),
+            chunk_size: table.get("chunk_size")?,
+            chunk_overlap: table.get("chunk_overlap").unwrap_or(32),
+            chunk_aggregation: ChunkAggregation::from_str(
+                &table.get::<_, Option<String>>("chunk_aggregation")?.unwrap_or(String::from("mean"))
+            )?,
+            output_format: OutputFormat::from_str(
+                &table.get::<_, Option<String>>("output_format")?.unwrap_or(String::from("json"))
+            )?,
+            dimensions,
+            device: Device::Cpu,
+        })
+    }
+
+    /// The decoded `config.json` as untyped JSON, for fields candle's `Config` doesn't
+    /// carry (e.g. `id2label`).
+    pub(crate) fn config_json(&self) -> LuaResult<serde_json::Value> {
+        serde_json::from_slice(&self.config)
             .map_err(|err| {
-                eprintln!("!! Error during b64 decode config\n{}", err);
+                eprintln!("!! Error during serde_json::from_slice\n{}", err);
                 LuaError::external(err)
-            })?;
-        let mut config: Config = serde_json::from_slice(&*config)
+            })
+    }
+
+    /// Longest sequence the model accepts, special tokens included.
+    pub(crate) fn max_positions(&self) -> LuaResult<usize> {
+        let config = self.config_json()?;
+        let max_positions = config.get("max_position_embeddings")
+            .and_then(|m| m.as_u64())
+            .unwrap_or(512) as usize;
+        Ok(max_positions - self.position_offset()?.unwrap_or(0))
+    }
+
+    /// Width of the output embeddings (`hidden_size`, or `dim` for DistilBERT).
+    fn hidden_size(&self) -> LuaResult<usize> {
+        let config = self.config_json()?;
+        config.get("hidden_size")
+            .or_else(|| config.get("dim"))
+            .and_then(|h| h.as_u64())
+            .map(|h| h as usize)
+            .ok_or_else(|| LuaError::RuntimeError("config.json has neither hidden_size nor dim".to_string()))
+    }
+
+    pub(crate) fn build_config(&self) -> LuaResult<Config> {
+        let mut config: Config = serde_json::from_slice(&self.config)
             .map_err(|err| {
                 eprintln!("!! Error during serde_json::from_value\n{}", err);
                 LuaError::external(err)
@@ -60,9 +266,63 @@ impl Args {
         if self.approximate_gelu {
             config.hidden_act = HiddenAct::GeluApproximate;
         };
+        if let Some(offset) = self.position_offset()? {
+            config.max_position_embeddings -= offset;
+        }
+        // `BertModel::load` falls back to tensors under `{model_type}.`.
+        config.model_type = config.model_type.as_deref().map(|m| weight_prefix(m).to_string());
         Ok(config)
     }
-    fn build_model_and_tokenizer(&self, config: Config) -> LuaResult<(BertModel, Tokenizer)> {
+
+    /// RoBERTa-style checkpoints number their positions from `pad_token_id + 1`, whereas
+    /// candle's `BertModel` always counts from 0.  Returns the number of leading rows of the
+    /// position embedding table to skip, if any.
+    fn position_offset(&self) -> LuaResult<Option<usize>> {
+        let config = self.config_json()?;
+        let model_type = config.get("model_type").and_then(|m| m.as_str()).unwrap_or("bert");
+        if !matches!(model_type, "roberta" | "xlm-roberta" | "camembert") {
+            return Ok(None);
+        }
+        let pad_token_id = config.get("pad_token_id").and_then(|p| p.as_u64()).unwrap_or(1);
+        Ok(Some(pad_token_id as usize + 1))
+    }
+    /// Builds the `VarBuilder` over the decoded safetensors (or GGUF) weights, so task heads
+    /// can load their own tensors next to the `BertModel`.
+    pub(crate) fn build_var_builder(&self) -> LuaResult<VarBuilder<'static>> {
+        let offset = self.position_offset()?;
+        let gguf = is_gguf(&self.model);
+        if offset.is_none() && !gguf {
+            return VarBuilder::from_buffered_safetensors(self.model.clone(), DTYPE, &self.device)
+                .map_err(|err| LuaError::external(err));
+        }
+        // candle has no quantized BERT, so GGUF weights are dequantized on load.
+        let mut tensors = if gguf {
+            load_gguf_dequantized(&self.model, &self.device)
+        } else {
+            candle_core::safetensors::load_buffer(&self.model, &self.device)
+        }.map_err(|err| LuaError::external(err))?;
+        if let Some(offset) = offset {
+            // Drop the unused leading position embeddings so positions line up with BertModel's.
+            for (name, tensor) in tensors.iter_mut() {
+                if name.ends_with("embeddings.position_embeddings.weight") {
+                    let rows = tensor.dim(0).map_err(|err| LuaError::external(err))?;
+                    *tensor = tensor.narrow(0, offset, rows - offset)
+                        .map_err(|err| LuaError::external(err))?;
+                }
+            }
+        }
+        Ok(VarBuilder::from_tensors(tensors, DTYPE, &self.device))
+    }
+
+    pub(crate) fn build_tokenizer(&self) -> LuaResult<Tokenizer> {
+        Tokenizer::from_bytes(&self.tokenizer)
+            .map_err(|err| {
+                ao_log(&format!("!! Error on Tokenizer::from_bytes\n{}", err));
+                LuaError::external(err)
+            })
+    }
+
+    fn build_model_and_tokenizer(&self) -> LuaResult<(EncoderModel, Tokenizer)> {
         // let mut config: Config = serde_json::from_str::<Config>(&self.config)
         //     .map_err(|err| LuaError::external(err))?;
         // if self.approximate_gelu {
@@ -79,37 +339,33 @@ impl Args {
         //
         // let tokenizer = Tokenizer::from_bytes(self.tokenizer.clone())//.as_bytes())
         //     .map_err(|err| LuaError::external(err))?;
-        let model_bytes = b64.decode(self.model.clone()).map_err(|e| LuaError::external(e))?;
-        let vb = VarBuilder::from_buffered_safetensors(model_bytes, DTYPE,  &self.device)
-            .map_err(|err| LuaError::external(err))?;
-        let model = BertModel::load(vb, &config)
-            .map_err(|err| {
-                ao_log(&format!("!! Error on BertModel::load()\n{}", err));
-                LuaError::external(err)
-            })?;
-        let tokenizer_bytes = b64.decode(self.tokenizer.clone()).map_err(|e| LuaError::external(e))?;
-        let tokenizer = Tokenizer::from_bytes(tokenizer_bytes)
-            .map_err(|err| {
-                ao_log(&format!("!! Error on Tokenizer::from_bytes\n{}", err));
-                LuaError::external(err)
-            })?;
+        let architecture = Architecture::from_config(&self.config_json()?)?;
+        let vb = self.build_var_builder()?;
+        let model = match architecture {
+            Architecture::Bert => BertModel::load(vb, &self.build_config()?).map(EncoderModel::Bert),
+            Architecture::DistilBert => {
+                let config: distilbert::Config = serde_json::from_value(self.config_json()?)
+                    .map_err(|err| LuaError::external(err))?;
+                distilbert::DistilBertModel::load(vb, &config).map(EncoderModel::DistilBert)
+            }
+            Architecture::JinaBert => {
+                let config: jina_bert::Config = serde_json::from_value(self.config_json()?)
+                    .map_err(|err| LuaError::external(err))?;
+                jina_bert::BertModel::new(vb, &config).map(EncoderModel::JinaBert)
+            }
+        }
+        .map_err(|err| {
+            ao_log(&format!("!! Error loading {:?} model\n{}", architecture, err));
+            LuaError::external(err)
+        })?;
+        let tokenizer = self.build_tokenizer()?;
         Ok((model, tokenizer))
     }
     
-    fn get_embedding(&self) -> LuaResult<Vec<f32>> {
-        let config = self.build_config()
-            .map_err(|err| LuaError::external(err))?;
-        let (model, mut tokenizer) = self.build_model_and_tokenizer(config)
-            .map_err(|err| LuaError::external(err))?;
-
-        let tokenizer = tokenizer
-            .with_padding(None)
-            .with_truncation(None)
-            .map_err(|err| LuaError::external(err))?;
-        let tokens = tokenizer.encode(&*self.prompt, true)
-            .map_err(|err| LuaError::external(err))?
-            .get_ids().to_vec();
-        let token_ids = Tensor::new(&tokens[..], &self.device)
+    /// Runs the model over a single sequence of token ids and mean-pools the hidden states,
+    /// returning a `(1, hidden_size)` tensor (L2 normalized if requested).
+    fn embed_ids(&self, model: &EncoderModel, tokens: &[u32]) -> LuaResult<Tensor> {
+        let token_ids = Tensor::new(tokens, &self.device)
             .map_err(|err| {
                 // If there's a problem here, it is likely just going to panic and won't print this error.
                 // Seems like issue with i64 and thread initialization in latest candle
@@ -121,12 +377,7 @@ impl Args {
                 ao_log(&format!("!! Error on token_ids ... unsqueeze\n {}", err));
                 LuaError::external(err)
             })?;
-        let token_type_ids = token_ids.zeros_like()
-            .map_err(|err| {
-                ao_log(&format!("!! Error on token_type_ids\n {}", err));
-                LuaError::external(err)
-            })?;
-        let embeddings = model.forward(&token_ids, &token_type_ids)
+        let embeddings = model.forward(&token_ids)
             .map_err(|err| {
                 ao_log(&format!("!! Error on model.forward\n {}", err));
                 LuaError::external(err)
@@ -139,12 +390,15 @@ impl Args {
             .map_err(|err| LuaError::external(err))?;
         let embeddings = (emb_sum / (n_tokens as f64))
             .map_err(|err| LuaError::external(err))?;
-        let embeddings = if self.normalize_embeddings {
-            common::normalize_l2(&embeddings).map_err(|err| LuaError::external(err))?
+        if self.normalize_embeddings {
+            common::normalize_l2(&embeddings).map_err(|err| LuaError::external(err))
         } else {
-            embeddings
-        };
-        let embeddings_data = embeddings
+            Ok(embeddings)
+        }
+    }
+
+    fn to_vec(embeddings: &Tensor) -> LuaResult<Vec<f32>> {
+        embeddings
             .flatten_all()
             .map_err(|err| {
                 ao_log(&format!("!! Error on embeddings_data.flatten_all()\n{}", err));
@@ -154,43 +408,167 @@ impl Args {
             .map_err(|err| {
                 ao_log(&format!("!! Error on embeddings_data.to_vec1\n{}", err));
                 LuaError::external(err)
-            })?;
+            })
+    }
+
+    /// Applies the `dimensions` truncation and `output_format` encoding to a final vector.
+    fn encode_output(&self, v: Vec<f32>) -> (EncodedVector, Option<f32>) {
+        let v = match self.dimensions {
+            Some(dimensions) if dimensions < v.len() => {
+                common::truncate_embedding(v, dimensions, self.normalize_embeddings)
+            }
+            _ => v,
+        };
+        self.output_format.encode(&v)
+    }
+
+    fn get_embedding(&self) -> LuaResult<Vec<f32>> {
+        let max_positions = self.max_positions()?;
+        let (model, mut tokenizer) = self.build_model_and_tokenizer()
+            .map_err(|err| LuaError::external(err))?;
+
+        let tokenizer = tokenizer
+            .with_padding(None)
+            .with_truncation(None)
+            .map_err(|err| LuaError::external(err))?;
+        let tokens = tokenizer.encode(&*self.prompt, true)
+            .map_err(|err| LuaError::external(err))?
+            .get_ids().to_vec();
+        if tokens.len() > max_positions {
+            return Err(LuaError::RuntimeError(format!(
+                "the prompt is {} tokens but the model accepts at most {}, set long_text = true to embed it in chunks",
+                tokens.len(),
+                max_positions
+            )));
+        }
+        let embeddings = self.embed_ids(&model, &tokens)?;
+        Self::to_vec(&embeddings)
+    }
+
+    /// Embeds a prompt of any length by sliding an overlapping window over its tokens.
+    ///
+    /// The tokenizer's own truncation-with-stride does the splitting, so every window gets the
+    /// model's special tokens and keeps character offsets into the original prompt.
+    fn get_chunked_embeddings(&self) -> LuaResult<Vec<(Tensor, Window)>> {
+        let max_positions = self.max_positions()?;
+        let window = self.chunk_size
+            .unwrap_or(max_positions)
+            .min(max_positions);
+        let (model, mut tokenizer) = self.build_model_and_tokenizer()
+            .map_err(|err| LuaError::external(err))?;
+
+        let n_special = tokenizer.encode("", true)
+            .map_err(|err| LuaError::external(err))?
+            .len();
+        check_chunk_overlap(window, self.chunk_overlap, n_special)?;
+        let tokenizer = tokenizer
+            .with_padding(None)
+            .with_truncation(Some(TruncationParams {
+                max_length: window,
+                stride: self.chunk_overlap,
+                ..Default::default()
+            }))
+            .map_err(|err| LuaError::external(err))?;
+        let mut encoding = tokenizer.encode_char_offsets(&*self.prompt, true)
+            .map_err(|err| LuaError::external(err))?;
+        let overflowing = encoding.take_overflowing();
+
+        let mut windows = Vec::with_capacity(overflowing.len() + 1);
+        for encoding in std::iter::once(encoding).chain(overflowing) {
+            let embeddings = self.embed_ids(&model, encoding.get_ids())?;
+            let spans: Vec<(usize, usize)> = encoding.get_offsets()
+                .iter()
+                .zip(encoding.get_special_tokens_mask())
+                .filter(|(_, &special)| special == 0)
+                .map(|(&offsets, _)| offsets)
+                .collect();
+            let span = Window {
+                start: spans.first().map(|s| s.0).unwrap_or(0),
+                end: spans.last().map(|s| s.1).unwrap_or(0),
+                tokens: spans.len(),
+            };
+            windows.push((embeddings, span));
+        }
+        ao_log(&format!("Embedded long prompt as {} chunks of up to {} tokens", windows.len(), window));
+        Ok(windows)
+    }
 
-        Ok(embeddings_data)
+    /// Combines window embeddings into one document vector, re-normalizing if requested.
+    fn aggregate_chunks(&self, chunks: &[(Tensor, Window)]) -> LuaResult<Vec<f32>> {
+        let weights: Vec<f32> = chunks.iter()
+            .map(|(_, window)| match self.chunk_aggregation {
+                ChunkAggregation::Weighted => window.tokens as f32,
+                _ => 1.,
+            })
+            .collect();
+        let total: f32 = weights.iter().sum();
+        let embeddings = Tensor::cat(&chunks.iter().map(|(e, _)| e).collect::<Vec<_>>(), 0)
+            .map_err(|err| LuaError::external(err))?;
+        let weights = Tensor::from_vec(weights, (chunks.len(), 1), &self.device)
+            .map_err(|err| LuaError::external(err))?;
+        let embeddings = (embeddings.broadcast_mul(&weights)
+            .map_err(|err| LuaError::external(err))?
+            .sum_keepdim(0)
+            .map_err(|err| LuaError::external(err))?
+            / total.max(1.) as f64)
+            .map_err(|err| LuaError::external(err))?;
+        let embeddings = if self.normalize_embeddings {
+            common::normalize_l2(&embeddings).map_err(|err| LuaError::external(err))?
+        } else {
+            embeddings
+        };
+        Self::to_vec(&embeddings)
     }
 }
 
 fn encode_text(_lua: &Lua, table: LuaTable) -> LuaResult<String> {
-    let model: String = table.get("model")?;
     let model_id: String = table.get("model_id")?;
     // let model_id = "sentence-transformers/all-MiniLM-L6-v2";
     // let model = include_str!("data/sentence-transformers_all-MiniLM-L6-v2/model.safetensors.b64").to_string();
-    // let model = b64.decode(model_b64).map_err(|e| LuaError::external(e))?;
-
     // let config = include_str!("data/sentence-transformers_all-MiniLM-L6-v2/config.json.b64").to_string();
-    let config: String = table.get("config")?;
     // let tokenizer = include_str!("data/sentence-transformers_all-MiniLM-L6-v2/tokenizer.json.b64").to_string();
-    let tokenizer: String = table.get("tokenizer")?;
-    // let tokenizer = b64.decode(tokenizer_b64).map_err(|e| LuaError::external(e))?;
-    
-    let args = Args {
-        model, config, tokenizer,
-        prompt: table.get("prompt").unwrap_or("I forgot to set a prompt somehow??".to_string()),
-        normalize_embeddings: table.get("normalize_embeddings").unwrap_or(true), // L2 normalization for embeddings. default_value = "true"
-        approximate_gelu: table.get("approximate_gelu").unwrap_or(false), // Use tanh based approximation for Gelu instead of erf implementation. default_value = "false"
-        device: Device::Cpu,
-    };
+    let args = Args::from_table(&table)?;
     println!("Prompt provided is: {}", args.prompt);
-    
-    let embeddings = args.get_embedding()
-        .map_err(|err| {
-            eprintln!("Error in encode_text when calling Arg's _encode_text()\n{}", err);
-            LuaError::external(err)
-        })?;
+
+    let (embeddings, chunks) = if args.long_text {
+        let chunks = args.get_chunked_embeddings()?;
+        match args.chunk_aggregation {
+            ChunkAggregation::None => {
+                let chunks = chunks.into_iter()
+                    .map(|(embeddings, window)| {
+                        let (data, scale) = args.encode_output(Args::to_vec(&embeddings)?);
+                        Ok(Chunk { data, scale, start: window.start, end: window.end, tokens: window.tokens })
+                    })
+                    .collect::<LuaResult<Vec<_>>>()?;
+                (None, Some(chunks))
+            }
+            _ => (Some(args.aggregate_chunks(&chunks)?), None),
+        }
+    } else {
+        let embeddings = args.get_embedding()
+            .map_err(|err| {
+                eprintln!("Error in encode_text when calling Arg's _encode_text()\n{}", err);
+                LuaError::external(err)
+            })?;
+        (Some(embeddings), None)
+    };
+    let hidden_size = args.hidden_size()?;
+    let dimensions = args.dimensions.map_or(hidden_size, |d| d.min(hidden_size));
+    let (data, scale) = match embeddings {
+        Some(embeddings) => {
+            let (data, scale) = args.encode_output(embeddings);
+            (Some(data), scale)
+        }
+        None => (None, None),
+    };
     let output = Embedding {
-        data: embeddings,
+        data,
+        output_format: args.output_format.name().to_string(),
+        dimensions,
+        scale,
         prompt: args.prompt,
         model_id: model_id.to_string(),
+        chunks,
     };
     let output_str = output.to_json()
         .map_err(|err| {
@@ -218,6 +596,93 @@ pub fn preload(lua: &Lua) -> LuaResult<()> {
     // let lua_encode_text_func = lua.create_thread(lua.create_function(encode_text)?)?;
     let lua_encode_text_func = lua.create_function(encode_text)?;
     bert_module_table.set("encode_text", lua_encode_text_func)?;
+    bert_module_table.set("token_classification", lua.create_function(token_classification::token_classification)?)?;
+    bert_module_table.set("fill_mask", lua.create_function(fill_mask::fill_mask)?)?;
     loaded.set("bert", bert_module_table)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(chunk_aggregation: ChunkAggregation, normalize_embeddings: bool) -> Args {
+        Args {
+            model: Vec::new(),
+            config: Vec::new(),
+            tokenizer: Vec::new(),
+            prompt: String::new(),
+            normalize_embeddings,
+            approximate_gelu: false,
+            long_text: true,
+            chunk_size: None,
+            chunk_overlap: 0,
+            chunk_aggregation,
+            output_format: OutputFormat::Json,
+            dimensions: None,
+            device: Device::Cpu,
+        }
+    }
+
+    fn with_config(config: serde_json::Value) -> Args {
+        Args { config: config.to_string().into_bytes(), ..args(ChunkAggregation::Mean, true) }
+    }
+
+    fn chunk(values: [f32; 2], tokens: usize) -> (Tensor, Window) {
+        let embeddings = Tensor::new(&[values], &Device::Cpu).unwrap();
+        (embeddings, Window { start: 0, end: 0, tokens })
+    }
+
+    #[test]
+    fn test_check_chunk_overlap() {
+        assert!(check_chunk_overlap(512, 32, 2).is_ok());
+        assert!(check_chunk_overlap(512, 509, 2).is_ok());
+        assert!(check_chunk_overlap(512, 510, 2).is_err());
+        assert!(check_chunk_overlap(2, 0, 2).is_err());
+        assert!(check_chunk_overlap(1, 0, 2).is_err());
+    }
+
+    #[test]
+    fn test_aggregate_chunks() {
+        let chunks = [chunk([1., 0.], 3), chunk([0., 1.], 1)];
+        let mean = args(ChunkAggregation::Mean, false).aggregate_chunks(&chunks).unwrap();
+        assert_eq!(mean, vec![0.5, 0.5]);
+        let weighted = args(ChunkAggregation::Weighted, false).aggregate_chunks(&chunks).unwrap();
+        assert_eq!(weighted, vec![0.75, 0.25]);
+        let normalized = args(ChunkAggregation::Mean, true).aggregate_chunks(&chunks).unwrap();
+        assert!((normalized[0] - 0.5f32.sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_architecture_from_config() {
+        let architecture = |config: serde_json::Value| Architecture::from_config(&config);
+        assert_eq!(architecture(serde_json::json!({})).unwrap(), Architecture::Bert);
+        for model_type in ["bert", "roberta", "xlm-roberta", "camembert"] {
+            assert_eq!(architecture(serde_json::json!({ "model_type": model_type })).unwrap(), Architecture::Bert);
+        }
+        assert_eq!(
+            architecture(serde_json::json!({ "model_type": "distilbert" })).unwrap(),
+            Architecture::DistilBert,
+        );
+        assert_eq!(
+            architecture(serde_json::json!({ "model_type": "bert", "architectures": ["JinaBertForMaskedLM"] })).unwrap(),
+            Architecture::JinaBert,
+        );
+        assert!(architecture(serde_json::json!({ "model_type": "nomic_bert" })).is_err());
+        assert!(architecture(serde_json::json!({ "architectures": ["NomicBertModel"] })).is_err());
+        assert!(architecture(serde_json::json!({ "model_type": "gpt2" })).is_err());
+    }
+
+    #[test]
+    fn test_position_offset() {
+        let xlm_roberta = with_config(serde_json::json!({
+            "model_type": "xlm-roberta", "pad_token_id": 1, "max_position_embeddings": 514
+        }));
+        assert_eq!(xlm_roberta.position_offset().unwrap(), Some(2));
+        assert_eq!(xlm_roberta.max_positions().unwrap(), 512);
+
+        let bert = with_config(serde_json::json!({ "model_type": "bert", "max_position_embeddings": 512 }));
+        assert_eq!(bert.position_offset().unwrap(), None);
+        assert_eq!(bert.max_positions().unwrap(), 512);
+    }
+}
@@ -0,0 +1,149 @@
+use mlua::prelude::*;
+use minijinja::{Environment, ErrorKind};
+use serde_json::Value;
+
+
+/// The chat template of a model, as found in its `tokenizer_config.json`.
+///
+/// Templates are Jinja, rendered with minijinja; the Python string methods they call
+/// (`strip`, `title`, `startswith`...) come from `minijinja_contrib::pycompat`.
+pub struct ChatTemplate {
+    template: String,
+    pub bos_token: Option<String>,
+    pub eos_token: Option<String>,
+}
+
+/// `bos_token`/`eos_token` are either the token itself or an `AddedToken` object.
+fn special_token(config: &Value, name: &str) -> Option<String> {
+    match config.get(name)? {
+        Value::String(token) => Some(token.clone()),
+        Value::Object(token) => token.get("content").and_then(|c| c.as_str()).map(str::to_string),
+        _ => None,
+    }
+}
+
+/// Templates call `raise_exception` to reject malformed conversations.
+fn raise_exception(message: String) -> Result<String, minijinja::Error> {
+    Err(minijinja::Error::new(ErrorKind::InvalidOperation, message))
+}
+
+impl ChatTemplate {
+    /// Reads `tokenizer_config.json`.  `chat_template` may also be a list of named templates,
+    /// in which case `name` picks one and defaults to `default`.
+    pub fn from_config(config: &[u8], name: Option<&str>) -> LuaResult<Self> {
+        let config: Value = serde_json::from_slice(config).map_err(LuaError::external)?;
+        let template = match config.get("chat_template") {
+            Some(Value::String(template)) => template.clone(),
+            Some(Value::Array(templates)) => {
+                let name = name.unwrap_or("default");
+                templates.iter()
+                    .find(|t| t.get("name").and_then(|n| n.as_str()) == Some(name))
+                    .and_then(|t| t.get("template"))
+                    .and_then(|t| t.as_str())
+                    .ok_or_else(|| LuaError::RuntimeError(format!("no chat template named {}", name)))?
+                    .to_string()
+            }
+            _ => return Err(LuaError::RuntimeError(
+                "tokenizer_config.json has no chat_template".to_string()
+            )),
+        };
+        Ok(ChatTemplate {
+            template,
+            bos_token: special_token(&config, "bos_token"),
+            eos_token: special_token(&config, "eos_token"),
+        })
+    }
+
+    /// Renders `messages` (`{ role, content }` objects) into a prompt.  With
+    /// `add_generation_prompt` the template ends with the header of the assistant's turn.
+    /// `extra` holds any other variables the template reads, e.g. `tools`.
+    pub fn render(&self, messages: &Value, add_generation_prompt: bool, extra: &Value) -> LuaResult<String> {
+        let mut env = Environment::new();
+        env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        env.add_function("raise_exception", raise_exception);
+        // Transformers renders chat templates with these two options on.
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        let template = env.template_from_str(&self.template).map_err(LuaError::external)?;
+
+        let mut context = match extra {
+            Value::Object(extra) => extra.clone(),
+            _ => serde_json::Map::new(),
+        };
+        context.insert("messages".to_string(), messages.clone());
+        context.insert("add_generation_prompt".to_string(), Value::Bool(add_generation_prompt));
+        context.insert("bos_token".to_string(), self.bos_token.clone().unwrap_or_default().into());
+        context.insert("eos_token".to_string(), self.eos_token.clone().unwrap_or_default().into());
+        template.render(Value::Object(context)).map_err(LuaError::external)
+    }
+}
+
+impl ChatTemplate {
+    /// Reads `opts.tokenizer_config`, `opts.template` naming one of several templates.
+    pub fn from_table(opts: &LuaTable) -> LuaResult<Self> {
+        let config = crate::models::common::load_bytes(opts.get("tokenizer_config")?, "tokenizer_config")?;
+        let name: Option<String> = opts.get("template")?;
+        ChatTemplate::from_config(&config, name.as_deref())
+    }
+
+    /// `render`, honouring `opts.add_generation_prompt` (default true) and `opts.context`.
+    pub fn render_with_options(&self, lua: &Lua, messages: &Value, opts: &LuaTable) -> LuaResult<String> {
+        let extra: Value = match opts.get::<_, LuaValue>("context")? {
+            LuaValue::Nil => Value::Null,
+            context => lua.from_value(context)?,
+        };
+        let add_generation_prompt = opts.get::<_, Option<bool>>("add_generation_prompt")?.unwrap_or(true);
+        self.render(messages, add_generation_prompt, &extra)
+    }
+}
+
+/// Converts a Lua list of `{ role, content }` tables into a JSON array.
+pub fn messages_from_lua(lua: &Lua, messages: LuaValue) -> LuaResult<Vec<Value>> {
+    match lua.from_value(messages)? {
+        Value::Array(messages) => Ok(messages),
+        // An empty Lua table has no way to tell it's a list.
+        Value::Object(map) if map.is_empty() => Ok(Vec::new()),
+        _ => Err(LuaError::RuntimeError("messages must be a list of { role, content } tables".to_string())),
+    }
+}
+
+/// Reads the template from `opts` and renders `messages` with it.
+pub fn render_from_table(lua: &Lua, messages: LuaValue, opts: &LuaTable) -> LuaResult<(String, ChatTemplate)> {
+    let template = ChatTemplate::from_table(opts)?;
+    let messages = Value::Array(messages_from_lua(lua, messages)?);
+    let prompt = template.render_with_options(lua, &messages, opts)?;
+    Ok((prompt, template))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHATML: &str = r#"{
+        "bos_token": "<s>",
+        "eos_token": { "content": "<|im_end|>", "special": true },
+        "chat_template": "{% for message in messages %}{{ '<|im_start|>' + message['role'] + '\n' + message['content'].strip() + '<|im_end|>' + '\n' }}{% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\n' }}{% endif %}"
+    }"#;
+
+    #[test]
+    fn test_render_chatml() {
+        let template = ChatTemplate::from_config(CHATML.as_bytes(), None).unwrap();
+        assert_eq!(template.eos_token.as_deref(), Some("<|im_end|>"));
+        let messages = serde_json::json!([
+            { "role": "system", "content": "Be brief." },
+            { "role": "user", "content": " Hi! " },
+        ]);
+        let prompt = template.render(&messages, true, &Value::Null).unwrap();
+        assert_eq!(
+            prompt,
+            "<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>user\nHi!<|im_end|>\n<|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn test_raise_exception() {
+        let config = r#"{ "chat_template": "{{ raise_exception('roles must alternate') }}" }"#;
+        let template = ChatTemplate::from_config(config.as_bytes(), None).unwrap();
+        assert!(template.render(&serde_json::json!([]), false, &Value::Null).is_err());
+    }
+}
@@ -1,34 +1,443 @@
 // #[allow(dead_code)]
 // use anyhow::{Result as AnyResult};
 // use candle_core::{Tensor, Result as CandleResult, Error as CandleError, DType, Device};
+use base64::prelude::{BASE64_STANDARD as b64, Engine};
 use candle_core::{Tensor, Result as CandleResult};
+use mlua::prelude::*;
 
 
+/// Reads a binary input (model weights, `tokenizer.json`, `config.json`) from a Lua value,
+/// which may be:
+///
+/// * a base64 string, optionally as a `data:...;base64,` URL,
+/// * the raw bytes in a Lua string,
+/// * a WeaveDrive reference, `{ tx = "<tx id>" }` or `{ path = "/data/<tx id>" }`.
+///
+/// `name` is only used in error messages.
+pub fn load_bytes(value: LuaValue, name: &str) -> LuaResult<Vec<u8>> {
+    match value {
+        LuaValue::String(s) => {
+            let bytes = s.as_bytes();
+            let is_data_url = bytes.starts_with(b"data:");
+            let encoded = if is_data_url {
+                match bytes.windows(8).position(|w| w == b";base64,") {
+                    Some(i) => &bytes[i + 8..],
+                    None => return Err(LuaError::RuntimeError(format!("{} is a data URL without base64 data", name))),
+                }
+            } else {
+                bytes
+            };
+            // Only try base64 when it looks like base64, so raw weights aren't sized up for decoding.
+            let is_base64 = encoded.iter()
+                .take(64)
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'/' | b'='));
+            if is_data_url || is_base64 {
+                return b64.decode(encoded)
+                    .map_err(|err| LuaError::RuntimeError(format!("{} is not valid base64: {}", name, err)));
+            }
+            Ok(bytes.to_vec())
+        }
+        LuaValue::Table(table) => {
+            let path = match (table.get::<_, Option<String>>("path")?, table.get::<_, Option<String>>("tx")?) {
+                (Some(path), _) => path,
+                (None, Some(tx)) => format!("/data/{}", tx),
+                (None, None) => return Err(LuaError::RuntimeError(
+                    format!("{} must be a string or a WeaveDrive reference {{ tx = ... }}", name)
+                )),
+            };
+            crate::weavedrive::read_to_end(&path)
+                .ok_or_else(|| LuaError::RuntimeError(format!("could not read {} from WeaveDrive at {}", name, path)))
+        }
+        other => Err(LuaError::FromLuaConversionError {
+            from: other.type_name(),
+            to: "bytes",
+            message: Some(format!("{} must be a string or a WeaveDrive reference", name)),
+        }),
+    }
+}
+
 // pub fn normalize_l2(v: &Tensor) -> AnyResult<Tensor> {
 pub fn normalize_l2(v: &Tensor) -> CandleResult<Tensor> {
     Ok(v.broadcast_div(&v.sqr()?.sum_keepdim(1)?.sqrt()?)?)
 }
 
-// // pub fn image_preprocess<T: AsRef<std::path::Path>>(path: T) -> anyhow::Result<Tensor> {
-// pub fn image_preprocess<T: AsRef<std::path::Path>>(path: T) -> CandleResult<Tensor> {
-//     let img = image::io::Reader::open(path)?.decode().map_err(|err| CandleError::wrap(err))?;//?;
-//     let (height, width) = (img.height() as usize, img.width() as usize);
-//     let height = height - height % 32;
-//     let width = width - width % 32;
-//     let img = img.resize_to_fill(
-//         width as u32,
-//         height as u32,
-//         image::imageops::FilterType::CatmullRom,
-//     );
-//     let img = img.to_rgb8();
-//     let img = img.into_raw();
-//     let img = Tensor::from_vec(img, (height, width, 3), &Device::Cpu).map_err(|err| CandleError::wrap(err))?
-//         .permute((2, 0, 1)).map_err(|err| CandleError::wrap(err))?
-//         .to_dtype(DType::F32).map_err(|err| CandleError::wrap(err))?
-//         .affine(2. / 255., -1.).map_err(|err| CandleError::wrap(err))?
-//         .unsqueeze(0).map_err(|err| CandleError::wrap(err))?;
-//     Ok(img)
-// }
+/// Keeps the leading `dimensions` values of a (Matryoshka) embedding, L2 re-normalizing the
+/// shortened vector when `normalize` is set.
+pub fn truncate_embedding(mut v: Vec<f32>, dimensions: usize, normalize: bool) -> Vec<f32> {
+    v.truncate(dimensions);
+    if normalize {
+        let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 0. {
+            v.iter_mut().for_each(|x| *x /= norm);
+        }
+    }
+    v
+}
+
+/// An embedding vector as it is written into the output JSON, either a plain number array
+/// or a base64 string of packed values.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum EncodedVector {
+    Floats(Vec<f32>),
+    Packed(String),
+}
+
+/// How embedding vectors are serialized in the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// JSON array of floats.
+    Json,
+    /// Base64 of the raw little-endian `f32` values, lossless.
+    Base64F32Le,
+    /// Base64 of `i8` values, symmetric scalar quantization: `value = q * scale`.
+    Int8,
+    /// Base64 of sign bits packed 8 per byte, most significant bit first (1 when `value > 0`).
+    Binary,
+}
+
+impl OutputFormat {
+    pub fn from_str(s: &str) -> LuaResult<Self> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(OutputFormat::Json),
+            "base64_f32le" => Ok(OutputFormat::Base64F32Le),
+            "int8" => Ok(OutputFormat::Int8),
+            "binary" => Ok(OutputFormat::Binary),
+            _ => Err(LuaError::RuntimeError(
+                format!("invalid output_format: {} (expected json, base64_f32le, int8 or binary)", s)
+            )),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputFormat::Json => "json",
+            OutputFormat::Base64F32Le => "base64_f32le",
+            OutputFormat::Int8 => "int8",
+            OutputFormat::Binary => "binary",
+        }
+    }
+
+    /// Encodes a vector, returning the quantization scale for `Int8`.
+    pub fn encode(&self, v: &[f32]) -> (EncodedVector, Option<f32>) {
+        match self {
+            OutputFormat::Json => (EncodedVector::Floats(v.to_vec()), None),
+            OutputFormat::Base64F32Le => {
+                let bytes: Vec<u8> = v.iter().flat_map(|x| x.to_le_bytes()).collect();
+                (EncodedVector::Packed(b64.encode(bytes)), None)
+            }
+            OutputFormat::Int8 => {
+                let max_abs = v.iter().fold(0f32, |m, x| m.max(x.abs()));
+                let scale = if max_abs > 0. { max_abs / 127. } else { 1. };
+                let bytes: Vec<u8> = v.iter()
+                    .map(|x| (x / scale).round().clamp(-127., 127.) as i8 as u8)
+                    .collect();
+                (EncodedVector::Packed(b64.encode(bytes)), Some(scale))
+            }
+            OutputFormat::Binary => {
+                let mut bytes = vec![0u8; (v.len() + 7) / 8];
+                for (i, x) in v.iter().enumerate() {
+                    if *x > 0. {
+                        bytes[i / 8] |= 0x80 >> (i % 8);
+                    }
+                }
+                (EncodedVector::Packed(b64.encode(bytes)), None)
+            }
+        }
+    }
+}
+
+/// Whether `bytes` hold a GGUF file rather than safetensors.
+pub fn is_gguf(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"GGUF")
+}
+
+/// Reads every tensor of a GGUF buffer, dequantized to F32.
+///
+/// For models without a quantized implementation in candle: this only saves on transfer and
+/// storage, the model then runs at full precision.  Tensor names must follow the original
+/// checkpoint, as written by candle's `tensor-tools quantize`.
+pub fn load_gguf_dequantized(
+    bytes: &[u8],
+    device: &candle_core::Device,
+) -> CandleResult<std::collections::HashMap<String, Tensor>> {
+    let mut cursor = std::io::Cursor::new(bytes);
+    let content = candle_core::quantized::gguf_file::Content::read(&mut cursor)?;
+    let mut tensors = std::collections::HashMap::new();
+    for name in content.tensor_infos.keys() {
+        let tensor = content.tensor(&mut cursor, name, device)?.dequantize(device)?;
+        tensors.insert(name.to_string(), tensor);
+    }
+    Ok(tensors)
+}
+
+/// Sets the logits of every token which would complete an n-gram of size `n` already
+/// present in `tokens` to minus infinity.
+pub fn ban_repeated_ngrams(logits: &mut [f32], tokens: &[u32], n: usize) {
+    if n == 0 || tokens.len() + 1 < n {
+        return;
+    }
+    let prefix = &tokens[tokens.len() + 1 - n..];
+    for window in tokens.windows(n) {
+        if &window[..n - 1] == prefix {
+            if let Some(logit) = logits.get_mut(window[n - 1] as usize) {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+    }
+}
+
+/// Returns the byte index of the earliest stop string in `text`, if any.
+pub fn find_stop(text: &str, stop: &[String]) -> Option<usize> {
+    stop.iter()
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| text.find(s.as_str()))
+        .min()
+}
+
+/// `decoded` cut at the earliest stop string, or without a trailing part which may still
+/// grow into one, so streamed text never runs past what the output keeps.
+fn streamable<'a>(decoded: &'a str, stop: &[String]) -> &'a str {
+    if let Some(at) = find_stop(decoded, stop) {
+        return &decoded[..at];
+    }
+    let held = stop.iter()
+        .filter_map(|s| (1..s.len()).rev().find(|&n| s.is_char_boundary(n) && decoded.ends_with(&s[..n])))
+        .max()
+        .unwrap_or(0);
+    &decoded[..decoded.len() - held]
+}
+
+/// One step of a streamed generation, as passed to the Lua `on_token` callback.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct TokenEvent {
+    /// 0-based position among the generated tokens.
+    pub index: usize,
+    pub id: u32,
+    /// The token as found in the vocabulary, e.g. `▁world`.
+    pub token: String,
+    /// Text added to the decoded output by this token, possibly empty while a multi-byte
+    /// character is incomplete.
+    pub text: String,
+    /// Log-probability of the token under the (penalized, unscaled) model distribution.
+    pub logprob: f32,
+}
+
+/// Feeds generated tokens to an optional Lua callback as text deltas.
+///
+/// The callback is called with a `TokenEvent` table and may return `false` to stop the
+/// generation, any other return value (including none) continues.  Text which may be the
+/// start of a stop string is held back until it isn't, and `finish` sends what is left of it.
+pub struct TokenStream<'lua> {
+    callback: Option<LuaFunction<'lua>>,
+    /// Text decoded so far, to compute the delta of each new token.
+    emitted: String,
+    /// The last event sent, repeated by `finish` with the held back text.
+    last: Option<TokenEvent>,
+}
+
+/// The byte length of the common prefix of `emitted` and `text`, on a character boundary.
+fn shared_prefix(emitted: &str, text: &str) -> usize {
+    emitted.char_indices()
+        .map(|(i, c)| i + c.len_utf8())
+        .take_while(|&end| text.as_bytes().get(..end) == Some(&emitted.as_bytes()[..end]))
+        .last()
+        .unwrap_or(0)
+}
+
+impl<'lua> TokenStream<'lua> {
+    pub fn new(callback: Option<LuaFunction<'lua>>) -> Self {
+        TokenStream { callback, emitted: String::new(), last: None }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.callback.is_some()
+    }
+
+    /// Reports the last token of `generated`, returns whether generation should go on.  The
+    /// text is cut like the output will be for the `stop` strings.
+    pub fn push(
+        &mut self,
+        lua: &'lua Lua,
+        tokenizer: &tokenizers::Tokenizer,
+        generated: &[u32],
+        logprob: f32,
+        stop: &[String],
+    ) -> LuaResult<bool> {
+        let callback = match &self.callback {
+            Some(callback) => callback,
+            None => return Ok(true),
+        };
+        let id = match generated.last() {
+            Some(id) => *id,
+            None => return Ok(true),
+        };
+        let decoded = tokenizer.decode(generated, true).map_err(LuaError::external)?;
+        // Hold back a trailing replacement character, the next token may complete it.
+        let text = if decoded.ends_with('\u{FFFD}') {
+            String::new()
+        } else {
+            // Decoding can rewrite the end of the previous text (e.g. spaces before
+            // punctuation), so the delta starts where the two strings diverge.
+            let decoded = streamable(&decoded, stop);
+            let delta = decoded[shared_prefix(&self.emitted, decoded)..].to_string();
+            self.emitted = decoded.to_string();
+            delta
+        };
+        let event = TokenEvent {
+            index: generated.len() - 1,
+            id,
+            token: tokenizer.id_to_token(id).unwrap_or_default(),
+            text,
+            logprob,
+        };
+        let result: LuaValue = callback.call(lua.to_value(&event)?)?;
+        self.last = Some(event);
+        Ok(!matches!(result, LuaValue::Boolean(false)))
+    }
+
+    /// Sends the rest of the final output `text`, which was held back as the possible start of
+    /// a stop string, as a repeat of the last event.
+    pub fn finish(&mut self, lua: &'lua Lua, text: &str) -> LuaResult<()> {
+        let (callback, last) = match (&self.callback, &self.last) {
+            (Some(callback), Some(last)) => (callback, last),
+            _ => return Ok(()),
+        };
+        let delta = &text[shared_prefix(&self.emitted, text)..];
+        if delta.is_empty() {
+            return Ok(());
+        }
+        let event = TokenEvent { text: delta.to_string(), ..last.clone() };
+        self.emitted = text.to_string();
+        callback.call::<_, LuaValue>(lua.to_value(&event)?)?;
+        Ok(())
+    }
+}
+
+/// One of the most likely tokens at a position, see `TokenLogprob::top`.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct TopLogprob {
+    pub id: u32,
+    pub token: String,
+    pub logprob: f32,
+}
+
+/// A token with its log-probability and, when asked for, the `top` most likely tokens at its
+/// position, most likely first.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct TokenLogprob {
+    pub id: u32,
+    pub token: String,
+    pub logprob: f32,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub top: Vec<TopLogprob>,
+}
+
+impl TokenLogprob {
+    /// Reads the log-probability of `id` from `logits`, the 1-D scores at its position,
+    /// along with the `top_n` best alternatives.
+    pub fn from_logits(logits: &Tensor, tokenizer: &tokenizers::Tokenizer, id: u32, top_n: usize) -> LuaResult<Self> {
+        let logprobs: Vec<f32> = candle_nn::ops::log_softmax(logits, candle_core::D::Minus1)
+            .and_then(|logprobs| logprobs.to_vec1())
+            .map_err(LuaError::external)?;
+        let token = |id: u32| tokenizer.id_to_token(id).unwrap_or_default();
+        let mut top = Vec::new();
+        if top_n > 0 {
+            let mut ranked: Vec<(usize, f32)> = logprobs.iter().copied().enumerate().collect();
+            ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+            top = ranked.into_iter()
+                .take(top_n)
+                .map(|(id, logprob)| TopLogprob { id: id as u32, token: token(id as u32), logprob })
+                .collect();
+        }
+        Ok(TokenLogprob {
+            id,
+            token: token(id),
+            logprob: logprobs.get(id as usize).copied().unwrap_or(f32::NEG_INFINITY),
+            top,
+        })
+    }
+}
+
+/// What the `score` entry points hand back for one text.
+#[derive(serde::Serialize, Debug)]
+pub struct ScoreOutput {
+    /// Sum of the token log-probabilities.
+    pub total_logprob: f64,
+    pub mean_logprob: f64,
+    /// `exp(-mean_logprob)`.
+    pub perplexity: f64,
+    pub tokens: Vec<TokenLogprob>,
+}
+
+impl ScoreOutput {
+    pub fn new(tokens: Vec<TokenLogprob>) -> Self {
+        let total_logprob: f64 = tokens.iter().map(|t| t.logprob as f64).sum();
+        let mean_logprob = if tokens.is_empty() { 0. } else { total_logprob / tokens.len() as f64 };
+        ScoreOutput { total_logprob, mean_logprob, perplexity: (-mean_logprob).exp(), tokens }
+    }
+}
+
+/// Reads the texts to score: `opts.pairs`, a list of `{ source, target }` pairs (either as
+/// named fields or as a 2-element list), or a single `opts[first]` / `opts[second]` pair.
+/// `first` may be left out and a pair may be a lone string, returned as `(None, text)`.  Also returns whether
+/// a list was given.
+pub fn score_pairs(
+    opts: &LuaTable,
+    first: &str,
+    second: &str,
+) -> LuaResult<(Vec<(Option<String>, String)>, bool)> {
+    let pair = |table: LuaTable| -> LuaResult<(Option<String>, String)> {
+        let a: Option<String> = match table.get::<_, Option<String>>(first)? {
+            Some(a) => Some(a),
+            None => table.get(1)?,
+        };
+        let b: Option<String> = match table.get::<_, Option<String>>(second)? {
+            Some(b) => Some(b),
+            None => table.get(2)?,
+        };
+        match (a, b) {
+            (a, Some(b)) => Ok((a, b)),
+            (Some(a), None) => Ok((None, a)),
+            (None, None) => Err(LuaError::RuntimeError(format!("each pair needs a {}", second))),
+        }
+    };
+    match opts.get::<_, Option<Vec<LuaValue>>>("pairs")? {
+        Some(pairs) => {
+            let pairs = pairs.into_iter()
+                .map(|value| match value {
+                    LuaValue::String(text) => Ok((None, text.to_str()?.to_string())),
+                    LuaValue::Table(table) => pair(table),
+                    _ => Err(LuaError::RuntimeError("pairs must be tables or strings".to_string())),
+                })
+                .collect::<LuaResult<_>>()?;
+            Ok((pairs, true))
+        }
+        None => Ok((vec![pair(opts.clone())?], false)),
+    }
+}
+
+/// Decodes an in-memory image (PNG, JPEG or WebP) as a `(1, 3, height, width)` tensor with
+/// values in `[-1, 1]`, as the stable diffusion VAE expects.  The image is scaled to cover
+/// `width` x `height` and center-cropped to it; both must be multiples of 8.
+pub fn image_preprocess(bytes: &[u8], height: usize, width: usize) -> CandleResult<Tensor> {
+    if height % 8 != 0 || width % 8 != 0 {
+        candle_core::bail!("image sizes must be multiples of 8, got {}x{}", width, height)
+    }
+    let img = image::load_from_memory(bytes).map_err(candle_core::Error::wrap)?;
+    let img = img.resize_to_fill(
+        width as u32,
+        height as u32,
+        image::imageops::FilterType::CatmullRom,
+    );
+    let img = img.to_rgb8();
+    let img = img.into_raw();
+    Tensor::from_vec(img, (height, width, 3), &candle_core::Device::Cpu)?
+        .permute((2, 0, 1))?
+        .to_dtype(candle_core::DType::F32)?
+        .affine(2. / 255., -1.)?
+        .unsqueeze(0)
+}
 
 // pub fn load_image<P: AsRef<std::path::Path>>(
 //     p: P,
@@ -121,4 +530,63 @@ pub fn normalize_l2(v: &Tensor) -> CandleResult<Tensor> {
 //     let image = image.resize_to_fill(w as u32, h as u32, image::imageops::FilterType::CatmullRom);
 //     image.save(p).map_err(candle_core::Error::wrap)?;
 //     Ok(())
-// }
\ No newline at end of file
+// }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_format_encode() {
+        let v = [0.5f32, -1.0, 0.25, 0.0, 1.0, -0.1, 0.2, 0.3, -0.4];
+
+        let (packed, scale) = OutputFormat::Int8.encode(&v);
+        let scale = scale.unwrap();
+        let EncodedVector::Packed(packed) = packed else { panic!("int8 should be packed") };
+        let bytes = b64.decode(packed).unwrap();
+        let restored: Vec<f32> = bytes.iter().map(|b| *b as i8 as f32 * scale).collect();
+        for (a, b) in v.iter().zip(restored) {
+            assert!((a - b).abs() <= scale / 2. + f32::EPSILON);
+        }
+
+        let (packed, _) = OutputFormat::Binary.encode(&v);
+        let EncodedVector::Packed(packed) = packed else { panic!("binary should be packed") };
+        assert_eq!(b64.decode(packed).unwrap(), vec![0b1010_1011, 0b0000_0000]);
+
+        let (packed, _) = OutputFormat::Base64F32Le.encode(&v);
+        let EncodedVector::Packed(packed) = packed else { panic!("base64_f32le should be packed") };
+        let bytes = b64.decode(packed).unwrap();
+        assert_eq!(f32::from_le_bytes(bytes[4..8].try_into().unwrap()), -1.0);
+    }
+
+    #[test]
+    fn test_streamable() {
+        let stop = vec!["\nUser:".to_string(), "###".to_string()];
+        assert_eq!(streamable("Hi there", &stop), "Hi there");
+        assert_eq!(streamable("Hi\nUs", &stop), "Hi");
+        assert_eq!(streamable("Hi ##", &stop), "Hi ");
+        assert_eq!(streamable("Hi\nUser: more", &stop), "Hi");
+        assert_eq!(streamable("a ### b\nUser:", &stop), "a ");
+        assert_eq!(streamable("Hi\n", &[]), "Hi\n");
+        assert_eq!(shared_prefix("Hello", "Hello, world"), 5);
+        assert_eq!(shared_prefix("Hello .", "Hello."), 5);
+    }
+
+    #[test]
+    fn test_load_bytes() {
+        let lua = Lua::new();
+        let load = |text: &str| load_bytes(LuaValue::String(lua.create_string(text).unwrap()), "config");
+        assert_eq!(load("e30=").unwrap(), b"{}");
+        assert_eq!(load("data:application/json;base64,e30=").unwrap(), b"{}");
+        assert_eq!(load("{}").unwrap(), b"{}");
+        assert!(load("data:application/json;base64,{}").is_err());
+        assert!(load("data:application/json,{}").is_err());
+        assert!(load("e30").is_err());
+    }
+
+    #[test]
+    fn test_truncate_embedding() {
+        let v = truncate_embedding(vec![3., 4., 12.], 2, true);
+        assert_eq!(v, vec![0.6, 0.8]);
+    }
+}
@@ -0,0 +1,686 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use candle_core::Tensor;
+use mlua::prelude::*;
+use serde_json::Value;
+use tokenizers::Tokenizer;
+
+
+/// How many of the most likely tokens are checked against the constraint at each step.
+/// Less likely tokens are masked out without a check, unless none of these is valid.
+const CANDIDATES: usize = 256;
+
+/// A compiled schema node.  Nodes live in an arena and refer to each other by index, which
+/// keeps parser frames small and lets `$ref` recurse.
+#[derive(Debug, Clone)]
+enum Node {
+    /// Resolved later, only seen while compiling a `$ref`.
+    Pending,
+    Object {
+        properties: Vec<(String, usize)>,
+        required: u64,
+        /// Schema of the properties not listed, if any are allowed.
+        additional: Option<usize>,
+    },
+    Array { items: usize, min_items: usize, max_items: Option<usize> },
+    String { min_length: usize, max_length: Option<usize> },
+    Number { integer: bool },
+    Boolean,
+    Null,
+    /// Exact texts, the serialized values of `enum`/`const` or the `choices` grammar.
+    Literal(Vec<Vec<char>>),
+    Union(Vec<usize>),
+}
+
+/// The node accepting any JSON value, always at index 0.
+const ANY: usize = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum NumberPhase { Minus, Zero, Int, Dot, Frac, Exp, ExpSign, ExpDigits }
+
+impl NumberPhase {
+    fn is_complete(&self) -> bool {
+        matches!(self, NumberPhase::Zero | NumberPhase::Int | NumberPhase::Frac | NumberPhase::ExpDigits)
+    }
+}
+
+/// One level of the pushdown parser.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Frame {
+    /// Expecting a value of this node, after optional whitespace.
+    Value(usize),
+    /// After `{` (`first`) or `,`, expecting a key.
+    ObjectOpen { node: usize, seen: u64, first: bool },
+    ObjectKey { node: usize, seen: u64, key: String },
+    /// After a key, expecting `:`; `property` is `None` for an additional property.
+    ObjectColon { node: usize, seen: u64, property: Option<usize> },
+    /// After a member, expecting `,` or `}`.
+    ObjectNext { node: usize, seen: u64 },
+    ArrayOpen { node: usize },
+    ArrayNext { node: usize, count: usize },
+    /// Inside a string; `escape` is 1 after a backslash and 2 to 5 within `\uXXXX`.
+    String { node: usize, length: usize, escape: u8 },
+    Number { integer: bool, phase: NumberPhase },
+    Literal { node: usize, option: usize, position: usize },
+}
+
+/// Compiles a JSON schema into the node arena.
+struct Compiler<'a> {
+    root: &'a Value,
+    nodes: Vec<Node>,
+    refs: Vec<(String, usize)>,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(root: &'a Value) -> Self {
+        let mut nodes = vec![Node::Pending];
+        let object = nodes.len();
+        nodes.push(Node::Object { properties: Vec::new(), required: 0, additional: Some(ANY) });
+        let array = nodes.len();
+        nodes.push(Node::Array { items: ANY, min_items: 0, max_items: None });
+        let mut any = vec![object, array];
+        for node in [
+            Node::String { min_length: 0, max_length: None },
+            Node::Number { integer: false },
+            Node::Boolean,
+            Node::Null,
+        ] {
+            any.push(nodes.len());
+            nodes.push(node);
+        }
+        nodes[ANY] = Node::Union(any);
+        Compiler { root, nodes, refs: Vec::new() }
+    }
+
+    fn push(&mut self, node: Node) -> usize {
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+
+    fn literal(values: &[Value]) -> Node {
+        Node::Literal(values.iter().map(|v| v.to_string().chars().collect()).collect())
+    }
+
+    fn compile(&mut self, schema: &Value) -> LuaResult<usize> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok(ANY),
+            Value::Object(schema) => schema,
+            _ => return Err(LuaError::RuntimeError(format!("unsupported schema: {}", schema))),
+        };
+        if let Some(reference) = schema.get("$ref").and_then(|r| r.as_str()) {
+            return self.compile_ref(reference);
+        }
+        if let Some(values) = schema.get("enum").and_then(|e| e.as_array()) {
+            return Ok(self.push(Self::literal(values)));
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(self.push(Self::literal(std::slice::from_ref(value))));
+        }
+        for key in ["anyOf", "oneOf"] {
+            if let Some(alternatives) = schema.get(key).and_then(|a| a.as_array()) {
+                let alternatives = alternatives.iter()
+                    .map(|alternative| self.compile(alternative))
+                    .collect::<LuaResult<Vec<usize>>>()?;
+                return Ok(self.push(Node::Union(alternatives)));
+            }
+        }
+        let types: Vec<&str> = match schema.get("type") {
+            Some(Value::String(t)) => vec![t.as_str()],
+            Some(Value::Array(types)) => types.iter().filter_map(|t| t.as_str()).collect(),
+            _ if schema.contains_key("properties") => vec!["object"],
+            _ if schema.contains_key("items") => vec!["array"],
+            _ => return Ok(ANY),
+        };
+        let mut alternatives = Vec::with_capacity(types.len());
+        for t in types {
+            let node = match t {
+                "object" => self.compile_object(schema)?,
+                "array" => {
+                    let items = match schema.get("items") {
+                        Some(items) => self.compile(items)?,
+                        None => ANY,
+                    };
+                    Node::Array {
+                        items,
+                        min_items: schema.get("minItems").and_then(|n| n.as_u64()).unwrap_or(0) as usize,
+                        max_items: schema.get("maxItems").and_then(|n| n.as_u64()).map(|n| n as usize),
+                    }
+                }
+                "string" => Node::String {
+                    min_length: schema.get("minLength").and_then(|n| n.as_u64()).unwrap_or(0) as usize,
+                    max_length: schema.get("maxLength").and_then(|n| n.as_u64()).map(|n| n as usize),
+                },
+                "number" => Node::Number { integer: false },
+                "integer" => Node::Number { integer: true },
+                "boolean" => Node::Boolean,
+                "null" => Node::Null,
+                other => return Err(LuaError::RuntimeError(format!("unsupported schema type: {}", other))),
+            };
+            alternatives.push(self.push(node));
+        }
+        Ok(match alternatives.len() {
+            1 => alternatives[0],
+            _ => self.push(Node::Union(alternatives)),
+        })
+    }
+
+    /// Properties may come in any order; those not in `properties` are only allowed when
+    /// `additionalProperties` says so, which keeps generations to the declared shape.
+    fn compile_object(&mut self, schema: &serde_json::Map<String, Value>) -> LuaResult<Node> {
+        let mut properties = Vec::new();
+        if let Some(declared) = schema.get("properties").and_then(|p| p.as_object()) {
+            if declared.len() > 64 {
+                return Err(LuaError::RuntimeError("objects are limited to 64 properties".to_string()));
+            }
+            for (name, property) in declared {
+                properties.push((name.clone(), self.compile(property)?));
+            }
+        }
+        let mut required = 0u64;
+        for name in schema.get("required").and_then(|r| r.as_array()).into_iter().flatten() {
+            let index = properties.iter()
+                .position(|(property, _)| Some(property.as_str()) == name.as_str())
+                .ok_or_else(|| LuaError::RuntimeError(format!("required property {} is not declared", name)))?;
+            required |= 1 << index;
+        }
+        let additional = match schema.get("additionalProperties") {
+            None | Some(Value::Bool(false)) => None,
+            Some(additional) => Some(self.compile(additional)?),
+        };
+        Ok(Node::Object { properties, required, additional })
+    }
+
+    /// Local references only, `#/$defs/...` or `#/definitions/...`.
+    fn compile_ref(&mut self, reference: &str) -> LuaResult<usize> {
+        if let Some((_, node)) = self.refs.iter().find(|(r, _)| r == reference) {
+            return Ok(*node);
+        }
+        let target = reference.strip_prefix('#')
+            .and_then(|pointer| self.root.pointer(pointer))
+            .ok_or_else(|| LuaError::RuntimeError(format!("unresolved $ref: {}", reference)))?;
+        let node = self.push(Node::Pending);
+        self.refs.push((reference.to_string(), node));
+        let compiled = self.compile(target)?;
+        self.nodes[node] = Node::Union(vec![compiled]);
+        Ok(node)
+    }
+
+    /// Fails when a union reaches itself through unions only, which a `$ref` to an enclosing
+    /// schema with nothing read in between makes: stepping it would recurse forever.
+    fn check_cycles(&self) -> LuaResult<()> {
+        /// 0 when unvisited, 1 while on the current path, 2 once known to be acyclic.
+        fn acyclic(nodes: &[Node], node: usize, state: &mut [u8]) -> bool {
+            match state[node] {
+                1 => return false,
+                2 => return true,
+                _ => (),
+            }
+            state[node] = 1;
+            if let Node::Union(alternatives) = &nodes[node] {
+                if !alternatives.iter().all(|&alternative| acyclic(nodes, alternative, state)) {
+                    return false;
+                }
+            }
+            state[node] = 2;
+            true
+        }
+        let mut state = vec![0u8; self.nodes.len()];
+        if (0..self.nodes.len()).all(|node| acyclic(&self.nodes, node, &mut state)) {
+            Ok(())
+        } else {
+            Err(LuaError::RuntimeError("infinitely recursive $ref in the JSON schema".to_string()))
+        }
+    }
+}
+
+fn is_whitespace(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\n' | '\r')
+}
+
+/// Restricts generation to the texts matching a JSON schema, any JSON value, or one of a
+/// list of choices.
+///
+/// The parser is a set of pushdown stacks, one per way of reading the text so far, so
+/// `anyOf` and overlapping literals need no lookahead.  Each step, the most likely tokens
+/// are fed to a copy of it and the logits of those it rejects are masked.
+#[derive(Debug, Clone)]
+pub struct Constraint {
+    nodes: Arc<Vec<Node>>,
+    stacks: Vec<Vec<Frame>>,
+    json: bool,
+}
+
+impl Constraint {
+    pub fn json_schema(schema: &Value) -> LuaResult<Self> {
+        let mut compiler = Compiler::new(schema);
+        let root = compiler.compile(schema)?;
+        compiler.check_cycles()?;
+        Ok(Constraint { nodes: Arc::new(compiler.nodes), stacks: vec![vec![Frame::Value(root)]], json: true })
+    }
+
+    pub fn choices(choices: &[String]) -> LuaResult<Self> {
+        if choices.iter().any(|choice| choice.is_empty()) || choices.is_empty() {
+            return Err(LuaError::RuntimeError("choices must be non-empty strings".to_string()));
+        }
+        let nodes = vec![Node::Literal(choices.iter().map(|c| c.chars().collect()).collect())];
+        let stacks = (0..choices.len())
+            .map(|option| vec![Frame::Literal { node: 0, option, position: 0 }])
+            .collect();
+        Ok(Constraint { nodes: Arc::new(nodes), stacks, json: false })
+    }
+
+    /// Reads `json_schema` (a table or JSON text), `json = true` (any JSON value) or
+    /// `choices` (a list of strings) from generation options.
+    pub fn from_table(lua: &Lua, table: &LuaTable) -> LuaResult<Option<Self>> {
+        match table.get::<_, LuaValue>("json_schema")? {
+            LuaValue::Nil => (),
+            LuaValue::String(schema) => {
+                let schema: Value = serde_json::from_slice(schema.as_bytes()).map_err(LuaError::external)?;
+                return Self::json_schema(&schema).map(Some);
+            }
+            schema => return Self::json_schema(&lua.from_value(schema)?).map(Some),
+        }
+        if table.get::<_, Option<bool>>("json")?.unwrap_or(false) {
+            return Self::json_schema(&Value::Bool(true)).map(Some);
+        }
+        match table.get::<_, Option<Vec<String>>>("choices")? {
+            Some(choices) => Self::choices(&choices).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Feeds `c` to `stack`, pushing every resulting stack to `out`.
+    fn step(&self, mut stack: Vec<Frame>, c: char, out: &mut Vec<Vec<Frame>>) {
+        let frame = match stack.pop() {
+            Some(frame) => frame,
+            // The document is complete, only trailing whitespace may follow.
+            None => {
+                if is_whitespace(c) {
+                    out.push(stack);
+                }
+                return;
+            }
+        };
+        match frame {
+            Frame::Value(node) => {
+                if is_whitespace(c) {
+                    stack.push(frame);
+                    out.push(stack);
+                    return;
+                }
+                match &self.nodes[node] {
+                    Node::Union(alternatives) => {
+                        for alternative in alternatives {
+                            let mut branch = stack.clone();
+                            branch.push(Frame::Value(*alternative));
+                            self.step(branch, c, out);
+                        }
+                    }
+                    Node::Object { .. } if c == '{' => {
+                        stack.push(Frame::ObjectOpen { node, seen: 0, first: true });
+                        out.push(stack);
+                    }
+                    Node::Array { .. } if c == '[' => {
+                        stack.push(Frame::ArrayOpen { node });
+                        out.push(stack);
+                    }
+                    Node::String { .. } if c == '"' => {
+                        stack.push(Frame::String { node, length: 0, escape: 0 });
+                        out.push(stack);
+                    }
+                    Node::Number { integer } if c == '-' || c.is_ascii_digit() => {
+                        let phase = match c {
+                            '-' => NumberPhase::Minus,
+                            '0' => NumberPhase::Zero,
+                            _ => NumberPhase::Int,
+                        };
+                        stack.push(Frame::Number { integer: *integer, phase });
+                        out.push(stack);
+                    }
+                    Node::Boolean | Node::Null | Node::Literal(_) => {
+                        let texts: Vec<Vec<char>> = match &self.nodes[node] {
+                            Node::Boolean => vec!["true".chars().collect(), "false".chars().collect()],
+                            Node::Null => vec!["null".chars().collect()],
+                            Node::Literal(texts) => texts.clone(),
+                            _ => unreachable!(),
+                        };
+                        // Booleans and nulls are matched as literals of their own node.
+                        for (option, text) in texts.iter().enumerate() {
+                            if text.first() == Some(&c) {
+                                let mut branch = stack.clone();
+                                if text.len() > 1 {
+                                    branch.push(Frame::Literal { node, option, position: 1 });
+                                }
+                                out.push(branch);
+                            }
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            Frame::ObjectOpen { node, seen, first } => {
+                let Node::Object { properties, required, additional } = &self.nodes[node] else { return };
+                if is_whitespace(c) {
+                    stack.push(frame);
+                    out.push(stack);
+                } else if c == '"' {
+                    let remaining = (0..properties.len()).any(|i| seen & (1 << i) == 0);
+                    if remaining || additional.is_some() {
+                        stack.push(Frame::ObjectKey { node, seen, key: String::new() });
+                        out.push(stack);
+                    }
+                } else if c == '}' && first && seen & required == *required {
+                    out.push(stack);
+                }
+            }
+            Frame::ObjectKey { node, seen, mut key } => {
+                let Node::Object { properties, additional, .. } = &self.nodes[node] else { return };
+                if c == '"' {
+                    let property = properties.iter()
+                        .enumerate()
+                        .position(|(i, (name, _))| *name == key && seen & (1 << i) == 0);
+                    if property.is_some() || additional.is_some() {
+                        stack.push(Frame::ObjectColon { node, seen, property });
+                        out.push(stack);
+                    }
+                } else if c != '\\' && c >= ' ' {
+                    key.push(c);
+                    let declared = properties.iter()
+                        .enumerate()
+                        .any(|(i, (name, _))| seen & (1 << i) == 0 && name.starts_with(key.as_str()));
+                    if declared || additional.is_some() {
+                        stack.push(Frame::ObjectKey { node, seen, key });
+                        out.push(stack);
+                    }
+                }
+            }
+            Frame::ObjectColon { node, seen, property } => {
+                let Node::Object { properties, additional, .. } = &self.nodes[node] else { return };
+                if is_whitespace(c) {
+                    stack.push(frame);
+                    out.push(stack);
+                } else if c == ':' {
+                    let (seen, value) = match property {
+                        Some(i) => (seen | (1 << i), properties[i].1),
+                        None => (seen, additional.unwrap_or(ANY)),
+                    };
+                    stack.push(Frame::ObjectNext { node, seen });
+                    stack.push(Frame::Value(value));
+                    out.push(stack);
+                }
+            }
+            Frame::ObjectNext { node, seen } => {
+                let Node::Object { required, .. } = &self.nodes[node] else { return };
+                if is_whitespace(c) {
+                    stack.push(frame);
+                    out.push(stack);
+                } else if c == ',' {
+                    stack.push(Frame::ObjectOpen { node, seen, first: false });
+                    out.push(stack);
+                } else if c == '}' && seen & required == *required {
+                    out.push(stack);
+                }
+            }
+            Frame::ArrayOpen { node } => {
+                let Node::Array { items, min_items, max_items } = &self.nodes[node] else { return };
+                if is_whitespace(c) {
+                    stack.push(frame);
+                    out.push(stack);
+                } else if c == ']' {
+                    if *min_items == 0 {
+                        out.push(stack);
+                    }
+                } else if max_items.map_or(true, |max| max >= 1) {
+                    stack.push(Frame::ArrayNext { node, count: 1 });
+                    stack.push(Frame::Value(*items));
+                    self.step(stack, c, out);
+                }
+            }
+            Frame::ArrayNext { node, count } => {
+                let Node::Array { items, min_items, max_items } = &self.nodes[node] else { return };
+                if is_whitespace(c) {
+                    stack.push(frame);
+                    out.push(stack);
+                } else if c == ',' && max_items.map_or(true, |max| count < max) {
+                    stack.push(Frame::ArrayNext { node, count: count + 1 });
+                    stack.push(Frame::Value(*items));
+                    out.push(stack);
+                } else if c == ']' && count >= *min_items {
+                    out.push(stack);
+                }
+            }
+            Frame::String { node, length, escape } => {
+                let (min_length, max_length) = match &self.nodes[node] {
+                    Node::String { min_length, max_length } => (*min_length, *max_length),
+                    _ => (0, None),
+                };
+                let next = match escape {
+                    0 if c == '"' => {
+                        if length >= min_length {
+                            out.push(stack);
+                        }
+                        return;
+                    }
+                    0 if c == '\\' => Some((length, 1)),
+                    0 if c < ' ' => None,
+                    0 => Some((length + 1, 0)),
+                    1 if "\"\\/bfnrt".contains(c) => Some((length + 1, 0)),
+                    1 if c == 'u' => Some((length, 2)),
+                    2..=4 if c.is_ascii_hexdigit() => Some((length, escape + 1)),
+                    5 if c.is_ascii_hexdigit() => Some((length + 1, 0)),
+                    _ => None,
+                };
+                if let Some((length, escape)) = next {
+                    if max_length.map_or(true, |max| length <= max) {
+                        stack.push(Frame::String { node, length, escape });
+                        out.push(stack);
+                    }
+                }
+            }
+            Frame::Number { integer, phase } => {
+                use NumberPhase::*;
+                let next = match (phase, c) {
+                    (Minus, '0') => Some(Zero),
+                    (Minus, '1'..='9') => Some(Int),
+                    (Int, '0'..='9') => Some(Int),
+                    (Zero | Int, '.') if !integer => Some(Dot),
+                    (Zero | Int | Frac, 'e' | 'E') if !integer => Some(Exp),
+                    (Dot | Frac, '0'..='9') => Some(Frac),
+                    (Exp, '+' | '-') => Some(ExpSign),
+                    (Exp | ExpSign | ExpDigits, '0'..='9') => Some(ExpDigits),
+                    _ => None,
+                };
+                match next {
+                    Some(phase) => {
+                        stack.push(Frame::Number { integer, phase });
+                        out.push(stack);
+                    }
+                    // A number only ends with the character after it.
+                    None if phase.is_complete() => self.step(stack, c, out),
+                    None => (),
+                }
+            }
+            Frame::Literal { node, option, position } => {
+                let text = match &self.nodes[node] {
+                    Node::Literal(texts) => texts[option].clone(),
+                    Node::Boolean => ["true", "false"][option].chars().collect(),
+                    Node::Null => "null".chars().collect(),
+                    _ => return,
+                };
+                if text.get(position) == Some(&c) {
+                    if position + 1 < text.len() {
+                        stack.push(Frame::Literal { node, option, position: position + 1 });
+                    }
+                    out.push(stack);
+                }
+            }
+        }
+    }
+
+    /// The parser stacks after `text`, empty if `text` can't continue the output.
+    fn feed(&self, stacks: &[Vec<Frame>], text: &str) -> Vec<Vec<Frame>> {
+        let mut stacks = stacks.to_vec();
+        for c in text.chars() {
+            let mut next = Vec::new();
+            for stack in stacks {
+                self.step(stack, c, &mut next);
+            }
+            let mut unique = HashSet::new();
+            next.retain(|stack| unique.insert(stack.clone()));
+            if next.is_empty() {
+                return next;
+            }
+            stacks = next;
+        }
+        stacks
+    }
+
+    /// Whether the output so far is a whole document (a trailing number counts as ended).
+    fn is_complete(&self) -> bool {
+        self.stacks.iter().any(|stack| {
+            stack.iter().all(|frame| matches!(frame, Frame::Number { phase, .. } if phase.is_complete()))
+        })
+    }
+
+    /// Whether nothing but whitespace can follow, so generation may stop.
+    pub fn is_done(&self) -> bool {
+        self.stacks.iter().all(|stack| stack.is_empty())
+    }
+
+    /// The text `id` adds after `context`, from decoding it with a few preceding tokens so
+    /// leading spaces come out as they would in the whole output.  An incomplete UTF-8
+    /// sequence comes out as U+FFFD.
+    fn token_text(tokenizer: &Tokenizer, context: &[u32], base: &str, id: u32) -> LuaResult<String> {
+        let mut ids = context.to_vec();
+        ids.push(id);
+        let text = tokenizer.decode(&ids, true).map_err(LuaError::external)?;
+        Ok(text.strip_prefix(base).map(str::to_string).unwrap_or_else(|| '\u{FFFD}'.to_string()))
+    }
+
+    fn context_of(context: &[u32]) -> &[u32] {
+        &context[context.len().saturating_sub(4)..]
+    }
+
+    /// Masks the logits of every token which can't continue the output.  `context` holds
+    /// the tokens before the next one, `eos_token_ids` are only allowed on a complete output.
+    pub fn mask(
+        &self,
+        logits: &Tensor,
+        tokenizer: &Tokenizer,
+        context: &[u32],
+        eos_token_ids: &[u32],
+    ) -> LuaResult<Tensor> {
+        let values: Vec<f32> = logits.to_vec1().map_err(LuaError::external)?;
+        let context = Self::context_of(context);
+        let base = tokenizer.decode(context, true).map_err(LuaError::external)?;
+        let mut order: Vec<usize> = (0..values.len()).filter(|&i| values[i].is_finite()).collect();
+        order.sort_by(|&a, &b| values[b].total_cmp(&values[a]));
+        let complete = self.is_complete();
+        let mut masked = vec![f32::NEG_INFINITY; values.len()];
+        let mut allowed = 0;
+        for (checked, id) in order.into_iter().enumerate() {
+            if checked >= CANDIDATES && allowed > 0 {
+                break;
+            }
+            let valid = if eos_token_ids.contains(&(id as u32)) {
+                complete
+            } else {
+                let text = Self::token_text(tokenizer, context, &base, id as u32)?;
+                !text.is_empty() && !self.feed(&self.stacks, &text).is_empty()
+            };
+            if valid {
+                masked[id] = values[id];
+                allowed += 1;
+            }
+        }
+        if allowed == 0 {
+            return Err(LuaError::RuntimeError("no token can continue the constrained output".to_string()));
+        }
+        Tensor::new(masked, logits.device()).map_err(LuaError::external)
+    }
+
+    /// Moves the parser past token `id`, generated after `context`.
+    pub fn advance(&mut self, tokenizer: &Tokenizer, context: &[u32], id: u32) -> LuaResult<()> {
+        let context = Self::context_of(context);
+        let base = tokenizer.decode(context, true).map_err(LuaError::external)?;
+        let text = Self::token_text(tokenizer, context, &base, id)?;
+        let stacks = self.feed(&self.stacks, &text);
+        if stacks.is_empty() {
+            return Err(LuaError::RuntimeError(format!("token {:?} breaks the constrained output", text)));
+        }
+        self.stacks = stacks;
+        Ok(())
+    }
+
+    /// Decodes the output as JSON, `None` when it was cut off before being complete.
+    pub fn parse(&self, text: &str) -> Option<Value> {
+        if self.json {
+            serde_json::from_str(text.trim()).ok()
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepts(constraint: &Constraint, text: &str) -> bool {
+        !constraint.feed(&constraint.stacks, text).is_empty()
+    }
+
+    #[test]
+    fn test_json_schema_prefixes() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "age": { "type": "integer" },
+                "tags": { "type": "array", "items": { "enum": ["a", "b"] } }
+            },
+            "required": ["name"]
+        });
+        let constraint = Constraint::json_schema(&schema).unwrap();
+        assert!(accepts(&constraint, r#"{"name": "Ada", "age": 36"#));
+        assert!(accepts(&constraint, r#"{ "tags": ["a", "b"], "name": "x"}"#));
+        assert!(!accepts(&constraint, r#"{"age": 3.5"#));
+        assert!(!accepts(&constraint, r#"{"nickname""#));
+        assert!(!accepts(&constraint, r#"{"age": 1}"#));
+        assert!(!accepts(&constraint, r#"{"tags": ["c"#));
+    }
+
+    #[test]
+    fn test_recursive_ref() {
+        for schema in [
+            serde_json::json!({ "$ref": "#" }),
+            serde_json::json!({ "anyOf": [{ "$ref": "#" }, { "type": "null" }] }),
+            serde_json::json!({ "$defs": { "a": { "$ref": "#/$defs/b" }, "b": { "$ref": "#/$defs/a" } }, "$ref": "#/$defs/a" }),
+        ] {
+            assert!(Constraint::json_schema(&schema).is_err());
+        }
+
+        // Recursion through a value that reads something first is fine.
+        let tree = serde_json::json!({
+            "type": "object",
+            "properties": { "children": { "type": "array", "items": { "$ref": "#" } } }
+        });
+        let constraint = Constraint::json_schema(&tree).unwrap();
+        assert!(accepts(&constraint, r#"{"children": [{"children": []}"#));
+    }
+
+    #[test]
+    fn test_complete_and_done() {
+        let mut constraint = Constraint::json_schema(&serde_json::json!({ "type": "number" })).unwrap();
+        constraint.stacks = constraint.feed(&constraint.stacks, "-1.5");
+        assert!(constraint.is_complete());
+        assert!(!constraint.is_done());
+
+        let mut constraint = Constraint::choices(&["yes".to_string(), "yes please".to_string()]).unwrap();
+        constraint.stacks = constraint.feed(&constraint.stacks, "yes");
+        assert!(constraint.is_complete());
+        assert!(!constraint.is_done());
+        assert!(!accepts(&constraint, "!"));
+    }
+}
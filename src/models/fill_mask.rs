@@ -0,0 +1,233 @@
+use mlua::prelude::*;
+use candle_core::{Result as CandleResult, Tensor, D};
+use candle_nn::{LayerNorm, Linear, Module, VarBuilder};
+use candle_transformers::models::bert::{BertModel, Config};
+use tokenizers::Tokenizer;
+
+use crate::models::bert::{weight_prefix, Args};
+use crate::ao_log;
+
+
+/// The masked-LM prediction head found on `BertForMaskedLM` (`cls.predictions.*`) and
+/// `RobertaForMaskedLM` (`lm_head.*`) checkpoints.
+struct MaskedLMHead {
+    dense: Linear,
+    layer_norm: LayerNorm,
+    decoder: Tensor,
+    bias: Tensor,
+}
+
+/// The dense, layer norm, decoder and bias tensor names of the head of a `model_type`.
+fn head_names(model_type: &str) -> (&'static str, &'static str, &'static str, &'static str) {
+    match model_type {
+        "roberta" | "xlm-roberta" | "camembert" => ("lm_head.dense", "lm_head.layer_norm", "lm_head.decoder", "lm_head.bias"),
+        _ => (
+            "cls.predictions.transform.dense",
+            "cls.predictions.transform.LayerNorm",
+            "cls.predictions.decoder",
+            "cls.predictions.bias",
+        ),
+    }
+}
+
+impl MaskedLMHead {
+    fn load(vb: VarBuilder, config: &Config, model_type: &str) -> CandleResult<Self> {
+        let (dense, layer_norm, decoder, bias) = head_names(model_type);
+        let dense = candle_nn::linear(config.hidden_size, config.hidden_size, vb.pp(dense))?;
+        let layer_norm = candle_nn::layer_norm(config.hidden_size, config.layer_norm_eps, vb.pp(layer_norm))?;
+        // The decoder is usually tied to the word embeddings and left out of the safetensors.
+        let shape = (config.vocab_size, config.hidden_size);
+        let decoder = vb.get(shape, &format!("{decoder}.weight"))
+            .or_else(|_| vb.get(shape, &format!("{}.embeddings.word_embeddings.weight", weight_prefix(model_type))))
+            .or_else(|_| vb.get(shape, "embeddings.word_embeddings.weight"))?;
+        let bias = vb.get(config.vocab_size, bias)?;
+        Ok(Self { dense, layer_norm, decoder, bias })
+    }
+}
+
+impl Module for MaskedLMHead {
+    fn forward(&self, hidden_states: &Tensor) -> CandleResult<Tensor> {
+        let xs = self.dense.forward(hidden_states)?.gelu_erf()?;
+        let xs = self.layer_norm.forward(&xs)?;
+        xs.matmul(&self.decoder.t()?)?.broadcast_add(&self.bias)
+    }
+}
+
+#[derive(serde::Serialize, Debug)]
+struct Completion {
+    score: f32,
+    token: u32,
+    token_str: String,
+    sequence: String,
+}
+
+/// Positions of the tokenizer's mask token, `[MASK]` or `<mask>`, in `tokens`.  Errors if
+/// the tokenizer has no mask token or `tokens` has none.
+fn mask_positions(tokenizer: &Tokenizer, tokens: &[u32]) -> LuaResult<Vec<u32>> {
+    let mask_token = ["[MASK]", "<mask>"]
+        .iter()
+        .find_map(|token| tokenizer.token_to_id(token).map(|id| (*token, id)));
+    let (mask_token, mask_id) = mask_token
+        .ok_or_else(|| LuaError::RuntimeError("tokenizer has neither a [MASK] nor a <mask> token".to_string()))?;
+    let positions: Vec<u32> = tokens.iter()
+        .enumerate()
+        .filter(|(_, &id)| id == mask_id)
+        .map(|(position, _)| position as u32)
+        .collect();
+    if positions.is_empty() {
+        return Err(LuaError::RuntimeError(format!("no {} token found in the text", mask_token)));
+    }
+    Ok(positions)
+}
+
+fn predict(args: &Args, top_k: usize) -> LuaResult<Vec<Vec<Completion>>> {
+    let mut tokenizer = args.build_tokenizer()?;
+    tokenizer
+        .with_padding(None)
+        .with_truncation(None)
+        .map_err(LuaError::external)?;
+    let tokens = tokenizer.encode(&*args.prompt, true)
+        .map_err(LuaError::external)?
+        .get_ids()
+        .to_vec();
+    let mask_positions = mask_positions(&tokenizer, &tokens)?;
+    let max_positions = args.max_positions()?;
+    if tokens.len() > max_positions {
+        return Err(LuaError::RuntimeError(format!(
+            "the text is {} tokens but the model accepts at most {}",
+            tokens.len(),
+            max_positions
+        )));
+    }
+
+    let config = args.build_config()?;
+    let model_type = args.config_json()?
+        .get("model_type")
+        .and_then(|m| m.as_str())
+        .unwrap_or("bert")
+        .to_string();
+    let vb = args.build_var_builder()?;
+    let model = BertModel::load(vb.clone(), &config)
+        .map_err(|err| {
+            ao_log(&format!("!! Error on BertModel::load()\n{}", err));
+            LuaError::external(err)
+        })?;
+    let head = MaskedLMHead::load(vb, &config, &model_type)
+        .map_err(|err| {
+            ao_log(&format!("!! Error loading the masked-LM head\n{}", err));
+            LuaError::external(err)
+        })?;
+
+    let token_ids = Tensor::new(&tokens[..], &args.device)
+        .map_err(LuaError::external)?
+        .unsqueeze(0)
+        .map_err(LuaError::external)?;
+    let token_type_ids = token_ids.zeros_like().map_err(LuaError::external)?;
+    let hidden_states = model.forward(&token_ids, &token_type_ids)
+        .map_err(|err| {
+            ao_log(&format!("!! Error on model.forward\n {}", err));
+            LuaError::external(err)
+        })?
+        .squeeze(0)
+        .map_err(LuaError::external)?;
+    let positions = Tensor::new(&mask_positions[..], &args.device).map_err(LuaError::external)?;
+    let masked_states = hidden_states.index_select(&positions, 0).map_err(LuaError::external)?;
+    let logits = head.forward(&masked_states).map_err(LuaError::external)?;
+    let probs: Vec<Vec<f32>> = candle_nn::ops::softmax(&logits, D::Minus1)
+        .map_err(LuaError::external)?
+        .to_vec2()
+        .map_err(LuaError::external)?;
+
+    let mut results = Vec::with_capacity(mask_positions.len());
+    for (position, probs) in mask_positions.iter().zip(probs) {
+        let mut ranked: Vec<(u32, f32)> = probs.into_iter()
+            .enumerate()
+            .map(|(id, p)| (id as u32, p))
+            .collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranked.truncate(top_k);
+        let mut completions = Vec::with_capacity(ranked.len());
+        for (token, score) in ranked {
+            let mut filled = tokens.clone();
+            filled[*position as usize] = token;
+            let sequence = tokenizer.decode(&filled, true).map_err(LuaError::external)?;
+            let token_str = tokenizer.decode(&[token], false).map_err(LuaError::external)?;
+            completions.push(Completion { score, token, token_str: token_str.trim().to_string(), sequence });
+        }
+        results.push(completions);
+    }
+    Ok(results)
+}
+
+/// Lua entry point, `bert.fill_mask(opts)`.
+///
+/// `opts.text` (or `opts.prompt`) must contain the tokenizer's mask token, `[MASK]` for BERT
+/// or `<mask>` for RoBERTa, and `opts.top_k` (default 5) sets how many completions to return.
+/// Each completion is `{ score, token, token_str, sequence }`, and the result is one list
+/// of completions per mask, in text order, even when there is a single mask.
+pub fn fill_mask(lua: &Lua, table: LuaTable) -> LuaResult<LuaValue> {
+    let mut args = Args::from_table(&table)?;
+    if let Some(text) = table.get::<_, Option<String>>("text")? {
+        args.prompt = text;
+    }
+    let top_k: usize = table.get("top_k").unwrap_or(5);
+    let results = predict(&args, top_k)?;
+    lua.to_value(&results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use candle_core::{DType, Device};
+    use tokenizers::models::wordlevel::WordLevel;
+
+    fn tokenizer(mask_token: &str) -> Tokenizer {
+        let vocab = [("[UNK]", 0), ("hello", 1), (mask_token, 2)]
+            .iter()
+            .map(|(token, id)| (token.to_string(), *id))
+            .collect();
+        Tokenizer::new(WordLevel::builder().vocab(vocab).unk_token("[UNK]".to_string()).build().unwrap())
+    }
+
+    #[test]
+    fn test_mask_positions() {
+        assert_eq!(mask_positions(&tokenizer("[MASK]"), &[1, 2, 1, 2]).unwrap(), vec![1, 3]);
+        assert_eq!(mask_positions(&tokenizer("<mask>"), &[2, 1]).unwrap(), vec![0]);
+        assert!(mask_positions(&tokenizer("[MASK]"), &[1, 1]).is_err());
+        assert!(mask_positions(&tokenizer("<pad>"), &[2]).is_err());
+    }
+
+    #[test]
+    fn test_head_names() {
+        for model_type in ["roberta", "xlm-roberta", "camembert"] {
+            assert_eq!(head_names(model_type).2, "lm_head.decoder");
+        }
+        assert_eq!(head_names("bert").2, "cls.predictions.decoder");
+    }
+
+    /// A head without its decoder, which must come from the `prefix` word embeddings.
+    fn tied_head(model_type: &str, prefix: &str) -> MaskedLMHead {
+        let device = Device::Cpu;
+        let config = Config { vocab_size: 3, hidden_size: 2, ..Default::default() };
+        let (dense, layer_norm, _, bias) = head_names(model_type);
+        let embeddings = Tensor::new(&[[1f32, 2.], [3., 4.], [5., 6.]], &device).unwrap();
+        let tensors: HashMap<String, Tensor> = [
+            (format!("{dense}.weight"), Tensor::eye(2, DType::F32, &device).unwrap()),
+            (format!("{dense}.bias"), Tensor::zeros(2, DType::F32, &device).unwrap()),
+            (format!("{layer_norm}.weight"), Tensor::ones(2, DType::F32, &device).unwrap()),
+            (format!("{layer_norm}.bias"), Tensor::zeros(2, DType::F32, &device).unwrap()),
+            (bias.to_string(), Tensor::zeros(3, DType::F32, &device).unwrap()),
+            (format!("{prefix}embeddings.word_embeddings.weight"), embeddings),
+        ].into_iter().collect();
+        MaskedLMHead::load(VarBuilder::from_tensors(tensors, DType::F32, &device), &config, model_type).unwrap()
+    }
+
+    #[test]
+    fn test_tied_decoder() {
+        for (model_type, prefix) in [("bert", "bert."), ("xlm-roberta", "roberta."), ("camembert", "roberta."), ("bert", "")] {
+            let head = tied_head(model_type, prefix);
+            assert_eq!(head.decoder.to_vec2::<f32>().unwrap(), vec![vec![1., 2.], vec![3., 4.], vec![5., 6.]]);
+        }
+    }
+}
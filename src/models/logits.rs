@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+
+use candle_core::Tensor;
+use candle_transformers::generation::{LogitsProcessor, Sampling};
+use mlua::prelude::*;
+
+use crate::models::common::ban_repeated_ngrams;
+
+
+/// The logits-processor chain shared by the generation pipelines, read from the Lua options.
+///
+/// `penalize` applies what depends on the tokens so far (penalties, biases, bans) and is
+/// also used by beam search.  `truncate` applies the min-p and typical-p filters on the
+/// tempered distribution, and `logits_processor` samples with temperature, top-k and top-p,
+/// as `candle_transformers` implements them.
+#[derive(Debug, Clone)]
+pub struct LogitsChain {
+    /// The temperature used to generate samples, 0 for greedy decoding. default_value_t = 0.8
+    pub temperature: f64,
+    /// Only sample among the `top_k` most likely tokens.
+    pub top_k: Option<usize>,
+    /// Nucleus sampling probability cutoff.
+    pub top_p: Option<f64>,
+    /// Drop the tokens less likely than `min_p` times the most likely one.
+    pub min_p: Option<f64>,
+    /// Locally typical sampling mass, see Meister et al. (2022).
+    pub typical_p: Option<f64>,
+    /// Penalty to be applied for repeating tokens, 1. means no penalty. default_value_t = 1.1
+    pub repeat_penalty: f32,
+    /// The context size to consider for the repeat penalty. default_value_t = 64
+    pub repeat_last_n: usize,
+    /// Subtracted from a logit once per time the token was generated. default_value_t = 0
+    pub frequency_penalty: f32,
+    /// Subtracted from a logit if the token was generated at all. default_value_t = 0
+    pub presence_penalty: f32,
+    /// Never repeat an n-gram of this size, 0 disables. default_value_t = 0
+    pub no_repeat_ngram_size: usize,
+    /// EOS is suppressed until this many tokens have been generated. default_value_t = 0
+    pub min_new_tokens: usize,
+    /// Added to the logits of these token ids.
+    pub logit_bias: HashMap<u32, f32>,
+    /// Token ids which are never generated.
+    pub banned_token_ids: Vec<u32>,
+}
+
+impl LogitsChain {
+    /// Reads `temperature`, `top_k`, `top_p`, `min_p`, `typical_p`, `repeat_penalty`,
+    /// `repeat_last_n`, `frequency_penalty`, `presence_penalty`, `no_repeat_ngram_size`,
+    /// `min_new_tokens`, `logit_bias` (`{ [id] = bias }`, ids may be numeric strings) and
+    /// `banned_token_ids`.
+    pub fn from_table(table: &LuaTable) -> LuaResult<Self> {
+        let mut logit_bias = HashMap::new();
+        if let Some(biases) = table.get::<_, Option<LuaTable>>("logit_bias")? {
+            for pair in biases.pairs::<LuaValue, f32>() {
+                let (id, bias) = pair?;
+                let id = match id {
+                    LuaValue::Integer(id) => u32::try_from(id).ok(),
+                    LuaValue::Number(id) if id >= 0. && id.fract() == 0. && id <= u32::MAX as f64 => Some(id as u32),
+                    LuaValue::String(id) => id.to_str()?.parse().ok(),
+                    _ => None,
+                }.ok_or_else(|| LuaError::RuntimeError(
+                    "logit_bias keys must be non-negative integer token ids".to_string()
+                ))?;
+                logit_bias.insert(id, bias);
+            }
+        }
+        Ok(LogitsChain {
+            temperature: table.get("temperature").unwrap_or(0.8f64),
+            top_k: table.get("top_k")?,
+            top_p: table.get("top_p")?,
+            min_p: table.get("min_p")?,
+            typical_p: table.get("typical_p")?,
+            repeat_penalty: table.get("repeat_penalty").unwrap_or(1.1f32),
+            repeat_last_n: table.get("repeat_last_n").unwrap_or(64usize),
+            frequency_penalty: table.get("frequency_penalty").unwrap_or(0f32),
+            presence_penalty: table.get("presence_penalty").unwrap_or(0f32),
+            no_repeat_ngram_size: table.get("no_repeat_ngram_size").unwrap_or(0usize),
+            min_new_tokens: table.get("min_new_tokens").unwrap_or(0usize),
+            logit_bias,
+            banned_token_ids: table.get::<_, Option<Vec<u32>>>("banned_token_ids")?.unwrap_or_default(),
+        })
+    }
+
+    /// Samples with `temperature`, `top_k` and `top_p`, greedily at temperature 0.
+    pub fn logits_processor(&self, seed: u64) -> LogitsProcessor {
+        let temperature = if self.temperature <= 0. { None } else { Some(self.temperature) };
+        let sampling = match (temperature, self.top_k, self.top_p) {
+            (None, _, _) => Sampling::ArgMax,
+            (Some(temperature), None, None) => Sampling::All { temperature },
+            (Some(temperature), Some(k), None) => Sampling::TopK { k, temperature },
+            (Some(temperature), None, Some(p)) => Sampling::TopP { p, temperature },
+            (Some(temperature), Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
+        };
+        LogitsProcessor::from_sampling(seed, sampling)
+    }
+
+    /// Applies the penalties, biases and bans given `tokens`, the first `prefix_len` of which
+    /// are the prompt (only generated tokens count for the frequency, presence and n-gram
+    /// rules and `min_new_tokens`).
+    pub fn penalize(&self, logits: Tensor, tokens: &[u32], prefix_len: usize, eos_token_ids: &[u32]) -> LuaResult<Tensor> {
+        let logits = if self.repeat_penalty == 1. {
+            logits
+        } else {
+            let start_at = tokens.len().saturating_sub(self.repeat_last_n);
+            candle_transformers::utils::apply_repeat_penalty(&logits, self.repeat_penalty, &tokens[start_at..])
+                .map_err(LuaError::external)?
+        };
+        let generated = &tokens[prefix_len.min(tokens.len())..];
+        let suppress_eos = generated.len() < self.min_new_tokens;
+        let counts = self.frequency_penalty != 0. || self.presence_penalty != 0.;
+        if !suppress_eos && !counts && self.no_repeat_ngram_size == 0
+            && self.logit_bias.is_empty() && self.banned_token_ids.is_empty()
+        {
+            return Ok(logits);
+        }
+
+        let device = logits.device().clone();
+        let mut logits: Vec<f32> = logits.to_vec1().map_err(LuaError::external)?;
+        if counts {
+            let mut occurrences: HashMap<u32, usize> = HashMap::new();
+            for token in generated {
+                *occurrences.entry(*token).or_default() += 1;
+            }
+            for (token, count) in occurrences {
+                if let Some(logit) = logits.get_mut(token as usize) {
+                    *logit -= self.frequency_penalty * count as f32 + self.presence_penalty;
+                }
+            }
+        }
+        for (token, bias) in &self.logit_bias {
+            if let Some(logit) = logits.get_mut(*token as usize) {
+                *logit += bias;
+            }
+        }
+        let eos = if suppress_eos { eos_token_ids } else { &[] };
+        for token in self.banned_token_ids.iter().chain(eos) {
+            if let Some(logit) = logits.get_mut(*token as usize) {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+        ban_repeated_ngrams(&mut logits, generated, self.no_repeat_ngram_size);
+        Tensor::new(logits, &device).map_err(LuaError::external)
+    }
+
+    /// Masks the tokens dropped by `min_p` and `typical_p`.  A no-op for greedy decoding.
+    pub fn truncate(&self, logits: Tensor) -> LuaResult<Tensor> {
+        if self.temperature <= 0. || (self.min_p.is_none() && self.typical_p.is_none()) {
+            return Ok(logits);
+        }
+        let device = logits.device().clone();
+        let mut logits: Vec<f32> = logits.to_vec1().map_err(LuaError::external)?;
+        let probs = softmax(&logits, self.temperature);
+        if let Some(typical_p) = self.typical_p {
+            let entropy: f64 = probs.iter()
+                .filter(|p| **p > 0.)
+                .map(|p| -p * p.ln())
+                .sum();
+            // Tokens by how far their surprise is from the expected one, most typical first.
+            let mut order: Vec<usize> = (0..probs.len()).filter(|&i| probs[i] > 0.).collect();
+            let distance = |i: usize| (-probs[i].ln() - entropy).abs();
+            order.sort_by(|&a, &b| distance(a).total_cmp(&distance(b)));
+            let mut mass = 0.;
+            for (rank, &i) in order.iter().enumerate() {
+                if rank > 0 && mass >= typical_p {
+                    logits[i] = f32::NEG_INFINITY;
+                }
+                mass += probs[i];
+            }
+        }
+        if let Some(min_p) = self.min_p {
+            let max = probs.iter().cloned().fold(0., f64::max);
+            for (logit, p) in logits.iter_mut().zip(&probs) {
+                if *p < min_p * max {
+                    *logit = f32::NEG_INFINITY;
+                }
+            }
+        }
+        Tensor::new(logits, &device).map_err(LuaError::external)
+    }
+
+    /// `penalize` then `truncate`, for the sampling loops.
+    pub fn process(&self, logits: Tensor, tokens: &[u32], prefix_len: usize, eos_token_ids: &[u32]) -> LuaResult<Tensor> {
+        let logits = self.penalize(logits, tokens, prefix_len, eos_token_ids)?;
+        self.truncate(logits)
+    }
+}
+
+fn softmax(logits: &[f32], temperature: f64) -> Vec<f64> {
+    let max = logits.iter().cloned().fold(f32::NEG_INFINITY, f32::max) as f64;
+    let exps: Vec<f64> = logits.iter().map(|l| ((*l as f64 - max) / temperature).exp()).collect();
+    let sum: f64 = exps.iter().sum();
+    exps.into_iter().map(|e| e / sum).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    fn chain() -> LogitsChain {
+        LogitsChain {
+            temperature: 1.,
+            top_k: None,
+            top_p: None,
+            min_p: None,
+            typical_p: None,
+            repeat_penalty: 1.,
+            repeat_last_n: 64,
+            frequency_penalty: 0.,
+            presence_penalty: 0.,
+            no_repeat_ngram_size: 0,
+            min_new_tokens: 0,
+            logit_bias: HashMap::new(),
+            banned_token_ids: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_penalize() {
+        let mut chain = chain();
+        chain.frequency_penalty = 0.5;
+        chain.presence_penalty = 1.;
+        chain.logit_bias.insert(3, 2.);
+        chain.banned_token_ids.push(2);
+        chain.min_new_tokens = 5;
+        let logits = Tensor::new(&[1f32, 1., 1., 1., 1.], &Device::Cpu).unwrap();
+        // Token 1 is in the prompt, which doesn't count, and was generated twice.
+        let logits: Vec<f32> = chain.penalize(logits, &[1, 1, 1], 1, &[4]).unwrap().to_vec1().unwrap();
+        assert_eq!(logits[..4], [1., -1., f32::NEG_INFINITY, 3.]);
+        assert_eq!(logits[4], f32::NEG_INFINITY);
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut chain = chain();
+        chain.min_p = Some(0.1);
+        let logits = Tensor::new(&[0f32, -1., -5.], &Device::Cpu).unwrap();
+        let logits: Vec<f32> = chain.truncate(logits).unwrap().to_vec1().unwrap();
+        assert_eq!(logits, [0., -1., f32::NEG_INFINITY]);
+    }
+}
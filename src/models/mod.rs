@@ -1,2 +1,18 @@
 pub mod bert;
-pub mod common;
\ No newline at end of file
+pub mod chat_template;
+pub mod common;
+pub mod constraints;
+pub mod fill_mask;
+pub mod logits;
+pub mod sessions;
+pub mod stable_diffusion;
+pub mod stable_diffusion_clip;
+pub mod stable_diffusion_config;
+pub mod stable_diffusion_schedulers;
+pub mod stable_diffusion_unet;
+pub mod stable_diffusion_vae;
+pub mod t5;
+pub mod text_generation;
+pub mod token_classification;
+pub mod tokenizer;
+pub mod translation;
@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+use lazy_static::lazy_static;
+use mlua::prelude::*;
+use serde_json::Value;
+
+use crate::models::chat_template::ChatTemplate;
+use crate::models::text_generation::LoadedModel;
+use crate::utils::message_timestamp;
+
+
+/// A named conversation kept in the process between messages: its history and, optionally,
+/// the model's KV cache over that history, so a follow-up only processes the new tokens.
+pub(crate) struct Session {
+    /// Sessions with the same `model_id` share the model weights.
+    pub(crate) model_id: Option<String>,
+    /// The model with an empty KV cache, to start over from.
+    base: LoadedModel,
+    /// The model whose KV cache holds `cached_tokens`.
+    pub(crate) model: LoadedModel,
+    pub(crate) template: ChatTemplate,
+    pub(crate) messages: Vec<Value>,
+    pub(crate) cached_tokens: Vec<u32>,
+    /// Message timestamps (ms).
+    created: u64,
+    pub(crate) last_used: u64,
+}
+
+impl Session {
+    pub(crate) fn new(model_id: Option<String>, model: LoadedModel, template: ChatTemplate) -> Self {
+        let now = message_timestamp();
+        Session {
+            model_id,
+            base: model.clone(),
+            model,
+            template,
+            messages: Vec::new(),
+            cached_tokens: Vec::new(),
+            created: now,
+            last_used: now,
+        }
+    }
+
+    pub(crate) fn clear_cache(&mut self) {
+        self.model = self.base.clone();
+        self.cached_tokens.clear();
+    }
+
+    /// How many leading `tokens` are already in the KV cache.  The cache can't be rolled
+    /// back, so when it isn't a strict prefix of `tokens` it is cleared.
+    pub(crate) fn reusable_prefix(&mut self, tokens: &[u32]) -> usize {
+        match reusable_len(&self.cached_tokens, tokens) {
+            Some(len) => len,
+            None => {
+                self.clear_cache();
+                0
+            }
+        }
+    }
+}
+
+/// The length of `cached` if it is a strict prefix of `tokens`, leaving at least one new
+/// token to run the model on.
+fn reusable_len(cached: &[u32], tokens: &[u32]) -> Option<usize> {
+    (cached.len() < tokens.len() && tokens.starts_with(cached)).then_some(cached.len())
+}
+
+/// Whether a session last used at `last_used` has been idle for longer than `max_idle_ms`.
+fn is_expired(now: u64, last_used: u64, max_idle_ms: u64) -> bool {
+    now.saturating_sub(last_used) > max_idle_ms
+}
+
+#[derive(serde::Serialize, Debug)]
+struct SessionInfo {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model_id: Option<String>,
+    model: &'static str,
+    messages: usize,
+    cached_tokens: usize,
+    created: u64,
+    last_used: u64,
+}
+
+#[derive(serde::Serialize, Debug, Clone, Copy)]
+struct Limits {
+    /// Least recently used sessions are dropped beyond this many.
+    max_sessions: usize,
+    /// Total tokens kept in KV caches over all sessions; least recently used sessions lose
+    /// their cache (not their history) beyond it.
+    max_cached_tokens: usize,
+}
+
+struct SessionStore {
+    sessions: HashMap<String, Session>,
+    limits: Limits,
+}
+
+/// What the `limits` do to sessions given as `(name, last used, cached tokens)`: the names
+/// of the sessions to drop, then of those to clear the cache of.  The least recently used go
+/// first, `keep` (the session just used) last.
+fn plan_limits(sessions: &[(&str, u64, usize)], limits: Limits, keep: &str) -> (Vec<String>, Vec<String>) {
+    let mut candidates: Vec<&(&str, u64, usize)> = sessions.iter().filter(|(name, _, _)| *name != keep).collect();
+    candidates.sort_by_key(|(_, last_used, _)| *last_used);
+    candidates.extend(sessions.iter().filter(|(name, _, _)| *name == keep));
+
+    let excess = sessions.len().saturating_sub(limits.max_sessions.max(1));
+    let dropped: Vec<&(&str, u64, usize)> = candidates.iter()
+        .filter(|(name, _, _)| *name != keep)
+        .take(excess)
+        .copied()
+        .collect();
+    let mut cached: usize = sessions.iter().map(|(_, _, tokens)| tokens).sum::<usize>()
+        - dropped.iter().map(|(_, _, tokens)| tokens).sum::<usize>();
+    let mut cleared = Vec::new();
+    for (name, _, tokens) in candidates.into_iter().skip(dropped.len()) {
+        if cached <= limits.max_cached_tokens {
+            break;
+        }
+        cached -= tokens;
+        cleared.push(name.to_string());
+    }
+    (dropped.into_iter().map(|(name, _, _)| name.to_string()).collect(), cleared)
+}
+
+impl SessionStore {
+    /// Applies the limits, sparing `keep` (the session just used) for as long as possible.
+    fn enforce_limits(&mut self, keep: &str) {
+        let sessions: Vec<(&str, u64, usize)> = self.sessions.iter()
+            .map(|(name, session)| (name.as_str(), session.last_used, session.cached_tokens.len()))
+            .collect();
+        let (dropped, cleared) = plan_limits(&sessions, self.limits, keep);
+        for name in dropped {
+            self.sessions.remove(&name);
+        }
+        for name in cleared {
+            if let Some(session) = self.sessions.get_mut(&name) {
+                session.clear_cache();
+            }
+        }
+    }
+}
+
+lazy_static! {
+    static ref SESSIONS: Mutex<SessionStore> = Mutex::new(SessionStore {
+        sessions: HashMap::new(),
+        limits: Limits { max_sessions: 16, max_cached_tokens: 8192 },
+    });
+}
+
+fn store() -> MutexGuard<'static, SessionStore> {
+    match SESSIONS.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Removes a session from the store for the duration of a generation, so Lua callbacks
+/// run meanwhile can use the session APIs.  Hand it back with `put`.
+pub(crate) fn take(name: &str) -> Option<Session> {
+    store().sessions.remove(name)
+}
+
+pub(crate) fn put(name: String, session: Session) {
+    let mut store = store();
+    store.sessions.insert(name.clone(), session);
+    store.enforce_limits(&name);
+}
+
+/// A model with an empty KV cache, from any session loaded with `model_id`.
+pub(crate) fn find_model(model_id: &str) -> Option<LoadedModel> {
+    store().sessions.values()
+        .find(|session| session.model_id.as_deref() == Some(model_id))
+        .map(|session| session.base.clone())
+}
+
+/// `sessions.list()` -> `{ { name, model_id, model, messages, cached_tokens, created, last_used }, ... }`
+fn list(lua: &Lua, _: ()) -> LuaResult<LuaValue> {
+    let store = store();
+    let mut infos: Vec<SessionInfo> = store.sessions.iter()
+        .map(|(name, session)| SessionInfo {
+            name: name.clone(),
+            model_id: session.model_id.clone(),
+            model: session.model.model.name(),
+            messages: session.messages.len(),
+            cached_tokens: session.cached_tokens.len(),
+            created: session.created,
+            last_used: session.last_used,
+        })
+        .collect();
+    infos.sort_by(|a, b| a.name.cmp(&b.name));
+    lua.to_value(&infos)
+}
+
+/// `sessions.history(name)` -> the session's messages, or nil.
+fn history(lua: &Lua, name: String) -> LuaResult<LuaValue> {
+    match store().sessions.get(&name) {
+        Some(session) => lua.to_value(&session.messages),
+        None => Ok(LuaValue::Nil),
+    }
+}
+
+/// `sessions.reset(name)` forgets the history and KV cache but keeps the model loaded.
+fn reset(_: &Lua, name: String) -> LuaResult<bool> {
+    match store().sessions.get_mut(&name) {
+        Some(session) => {
+            session.messages.clear();
+            session.clear_cache();
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// `sessions.delete(name)` drops the session and, unless shared, its model.
+fn delete(_: &Lua, name: String) -> LuaResult<bool> {
+    Ok(store().sessions.remove(&name).is_some())
+}
+
+/// `sessions.expire(max_idle_ms)` drops sessions unused for longer than `max_idle_ms`,
+/// measured with message timestamps, and returns how many were dropped.
+fn expire(_: &Lua, max_idle_ms: u64) -> LuaResult<usize> {
+    let now = message_timestamp();
+    let mut store = store();
+    let before = store.sessions.len();
+    store.sessions.retain(|_, session| !is_expired(now, session.last_used, max_idle_ms));
+    Ok(before - store.sessions.len())
+}
+
+/// `sessions.configure({ max_sessions?, max_cached_tokens? })` -> the limits in force.
+fn configure(lua: &Lua, opts: Option<LuaTable>) -> LuaResult<LuaValue> {
+    let mut store = store();
+    if let Some(opts) = opts {
+        if let Some(max_sessions) = opts.get::<_, Option<usize>>("max_sessions")? {
+            store.limits.max_sessions = max_sessions;
+        }
+        if let Some(max_cached_tokens) = opts.get::<_, Option<usize>>("max_cached_tokens")? {
+            store.limits.max_cached_tokens = max_cached_tokens;
+        }
+        store.enforce_limits("");
+    }
+    lua.to_value(&store.limits)
+}
+
+/// The `sessions` table of the `text_generation` module.
+pub fn create_table(lua: &Lua) -> LuaResult<LuaTable> {
+    let sessions_table = lua.create_table()?;
+    sessions_table.set("list", lua.create_function(list)?)?;
+    sessions_table.set("history", lua.create_function(history)?)?;
+    sessions_table.set("reset", lua.create_function(reset)?)?;
+    sessions_table.set("delete", lua.create_function(delete)?)?;
+    sessions_table.set("expire", lua.create_function(expire)?)?;
+    sessions_table.set("configure", lua.create_function(configure)?)?;
+    Ok(sessions_table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: Limits = Limits { max_sessions: 2, max_cached_tokens: 100 };
+
+    #[test]
+    fn test_reusable_len() {
+        assert_eq!(reusable_len(&[], &[1, 2]), Some(0));
+        assert_eq!(reusable_len(&[1, 2], &[1, 2, 3]), Some(2));
+        // At least one token must be left to run the model on.
+        assert_eq!(reusable_len(&[1, 2], &[1, 2]), None);
+        // An edited history can't reuse the cache.
+        assert_eq!(reusable_len(&[1, 2], &[1, 3, 4]), None);
+        assert_eq!(reusable_len(&[1, 2, 3], &[1, 2]), None);
+    }
+
+    #[test]
+    fn test_plan_limits_drops_least_recently_used() {
+        let sessions = [("a", 3, 0), ("b", 1, 0), ("c", 2, 0), ("d", 0, 0)];
+        // `d` is the oldest but was just used.
+        let (dropped, cleared) = plan_limits(&sessions, LIMITS, "d");
+        assert_eq!(dropped, vec!["b", "c"]);
+        assert!(cleared.is_empty());
+        let (dropped, _) = plan_limits(&sessions[..2], LIMITS, "a");
+        assert!(dropped.is_empty());
+        let limits = Limits { max_sessions: 0, ..LIMITS };
+        let (dropped, _) = plan_limits(&sessions, limits, "a");
+        assert_eq!(dropped, vec!["d", "b", "c"]);
+    }
+
+    #[test]
+    fn test_plan_limits_clears_caches() {
+        let sessions = [("a", 3, 60), ("b", 1, 30), ("c", 2, 50)];
+        // Dropping `b` leaves 110 cached tokens, clearing `c` brings it under the limit.
+        let (dropped, cleared) = plan_limits(&sessions, LIMITS, "a");
+        assert_eq!(dropped, vec!["b"]);
+        assert_eq!(cleared, vec!["c"]);
+        // The session just used loses its cache last.
+        let limits = Limits { max_sessions: 4, max_cached_tokens: 10 };
+        let (dropped, cleared) = plan_limits(&sessions, limits, "b");
+        assert!(dropped.is_empty());
+        assert_eq!(cleared, vec!["c", "a", "b"]);
+        let limits = Limits { max_sessions: 4, max_cached_tokens: 40 };
+        let (_, cleared) = plan_limits(&sessions, limits, "b");
+        assert_eq!(cleared, vec!["c", "a"]);
+    }
+
+    #[test]
+    fn test_is_expired() {
+        assert!(!is_expired(1_000, 400, 600));
+        assert!(is_expired(1_001, 400, 600));
+        // A clock going back doesn't expire anything.
+        assert!(!is_expired(100, 400, 0));
+    }
+}
@@ -1,716 +1,948 @@
-#![allow(unused)]
-
-use mlua::prelude::*;
-use mlua::UserData;
-use wasm_bindgen::prelude::*;
-use std::error::Error;
 use std::io::Cursor;
-use std::sync::Arc;
-use std::thread;
-use candle_core::{DType, Device, IndexOp, Module, Tensor, D};
 
-use tokenizers::Tokenizer;
-use anyhow::{Error as AnyError, Result as AnyResult};
-use base64::engine::general_purpose::STANDARD;
-use candle_core::safetensors::BufferedSafetensors;
-use candle_nn::VarBuilder;
-// use candle_transformers::models::stable_diffusion;
-use candle_transformers::models::stable_diffusion::{
-    // StableDiffusionConfig,
-    clip,
-    unet_2d::{UNet2DConditionModel, UNet2DConditionModelConfig},
-    vae::{AutoEncoderKL, AutoEncoderKLConfig}
-};
-use base64::prelude::*;
+use base64::prelude::{BASE64_STANDARD, Engine};
+use candle_core::{DType, Device, IndexOp, Module, Tensor, D};
+use candle_transformers::models::stable_diffusion::{ddim, ddpm, euler_ancestral_discrete};
+use candle_transformers::models::stable_diffusion::schedulers::{BetaSchedule, PredictionType, TimestepSpacing};
 use image::codecs::png::PngEncoder;
-use image::{ColorType, ExtendedColorType, ImageEncoder};
-use crate::common::{image_preprocess, save_image_file};
-use crate::stable_diffusion_config::StableDiffusionConfig;
+use image::{ExtendedColorType, ImageEncoder};
+use mlua::prelude::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokenizers::Tokenizer;
+
+use crate::ao_log;
+use crate::models::common::{image_preprocess, load_bytes};
+use crate::models::stable_diffusion_config::StableDiffusionConfig;
+use crate::models::stable_diffusion_schedulers::SchedulerConfig;
+use crate::models::stable_diffusion_unet::TextTimeConditioning;
+use crate::utils::message_seed;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StableDiffusionVersion {
+    V1_5,
+    V2_1,
+    Xl,
+    Turbo,
+    Ssd1b,
+}
+
+impl StableDiffusionVersion {
+    /// Accepts `v1_5`, `v1.5`, `V2_1`...
+    fn from_str(version: &str) -> LuaResult<Self> {
+        match version.replace('.', "_").to_lowercase().as_str() {
+            "v1_5" => Ok(StableDiffusionVersion::V1_5),
+            "v2_1" => Ok(StableDiffusionVersion::V2_1),
+            "xl" => Ok(StableDiffusionVersion::Xl),
+            "turbo" => Ok(StableDiffusionVersion::Turbo),
+            "ssd1b" | "ssd_1b" => Ok(StableDiffusionVersion::Ssd1b),
+            _ => Err(LuaError::RuntimeError(format!("invalid stable diffusion version: {}", version))),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            StableDiffusionVersion::V1_5 => "v1_5",
+            StableDiffusionVersion::V2_1 => "v2_1",
+            StableDiffusionVersion::Xl => "xl",
+            StableDiffusionVersion::Turbo => "turbo",
+            StableDiffusionVersion::Ssd1b => "ssd1b",
+        }
+    }
+
+    fn config(
+        &self,
+        sliced_attention_size: Option<usize>,
+        height: Option<usize>,
+        width: Option<usize>,
+    ) -> StableDiffusionConfig {
+        match self {
+            StableDiffusionVersion::V1_5 => StableDiffusionConfig::v1_5(sliced_attention_size, height, width),
+            StableDiffusionVersion::V2_1 => StableDiffusionConfig::v2_1(sliced_attention_size, height, width),
+            StableDiffusionVersion::Xl => StableDiffusionConfig::sdxl(sliced_attention_size, height, width),
+            StableDiffusionVersion::Turbo => StableDiffusionConfig::sdxl_turbo(sliced_attention_size, height, width),
+            StableDiffusionVersion::Ssd1b => StableDiffusionConfig::ssd1b(sliced_attention_size, height, width),
+        }
+    }
+
+    fn default_steps(&self) -> usize {
+        match self {
+            StableDiffusionVersion::Turbo => 1,
+            _ => 30,
+        }
+    }
+
+    fn default_guidance_scale(&self) -> f64 {
+        match self {
+            StableDiffusionVersion::Turbo => 0.,
+            _ => 7.5,
+        }
+    }
+
+    /// The scheduler of the version's `StableDiffusionConfig`.
+    fn default_scheduler(&self) -> SchedulerKind {
+        match self {
+            StableDiffusionVersion::Turbo => SchedulerKind::EulerAncestral,
+            _ => SchedulerKind::Ddim,
+        }
+    }
+
+    fn default_prediction_type(&self) -> PredictionType {
+        match self {
+            StableDiffusionVersion::V2_1 => PredictionType::VPrediction,
+            _ => PredictionType::Epsilon,
+        }
+    }
+
+    fn default_timestep_spacing(&self) -> TimestepSpacing {
+        match self {
+            StableDiffusionVersion::Turbo => TimestepSpacing::Trailing,
+            _ => TimestepSpacing::Leading,
+        }
+    }
+
+    /// The `scaling_factor` of the VAE config, the SDXL VAE (also used by SSD-1B) having its own.
+    fn vae_scale(&self) -> f64 {
+        match self {
+            StableDiffusionVersion::V1_5 | StableDiffusionVersion::V2_1 => 0.18215,
+            StableDiffusionVersion::Xl | StableDiffusionVersion::Turbo | StableDiffusionVersion::Ssd1b => 0.13025,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SchedulerKind {
+    Ddim,
+    EulerAncestral,
+    Ddpm,
+}
+
+impl SchedulerKind {
+    fn from_str(scheduler: &str) -> LuaResult<Self> {
+        match scheduler.to_lowercase().as_str() {
+            "ddim" => Ok(SchedulerKind::Ddim),
+            "euler_ancestral" | "euler_a" => Ok(SchedulerKind::EulerAncestral),
+            "ddpm" => Ok(SchedulerKind::Ddpm),
+            _ => Err(LuaError::RuntimeError(format!("invalid scheduler: {}", scheduler))),
+        }
+    }
 
+    fn name(&self) -> &'static str {
+        match self {
+            SchedulerKind::Ddim => "ddim",
+            SchedulerKind::EulerAncestral => "euler_ancestral",
+            SchedulerKind::Ddpm => "ddpm",
+        }
+    }
+}
+
+/// Reads `scheduler` and its parameters, `None` when none is given so that the version's
+/// own scheduler is kept.  Unset parameters take the version's defaults.
+fn scheduler_config(
+    table: &LuaTable,
+    sd_version: StableDiffusionVersion,
+) -> LuaResult<(SchedulerKind, Option<SchedulerConfig>)> {
+    let kind = match table.get::<_, Option<String>>("scheduler")? {
+        Some(scheduler) => Some(SchedulerKind::from_str(&scheduler)?),
+        None => None,
+    };
+    let eta: Option<f64> = table.get("eta")?;
+    let prediction_type = match table.get::<_, Option<String>>("prediction_type")?.as_deref() {
+        None => None,
+        Some("epsilon") => Some(PredictionType::Epsilon),
+        Some("v_prediction") => Some(PredictionType::VPrediction),
+        Some("sample") => Some(PredictionType::Sample),
+        Some(other) => return Err(LuaError::RuntimeError(format!("invalid prediction_type: {}", other))),
+    };
+    let timestep_spacing = match table.get::<_, Option<String>>("timestep_spacing")?.as_deref() {
+        None => None,
+        Some("leading") => Some(TimestepSpacing::Leading),
+        Some("linspace") => Some(TimestepSpacing::Linspace),
+        Some("trailing") => Some(TimestepSpacing::Trailing),
+        Some(other) => return Err(LuaError::RuntimeError(format!("invalid timestep_spacing: {}", other))),
+    };
+    let beta_schedule = match table.get::<_, Option<String>>("beta_schedule")?.as_deref() {
+        None => None,
+        Some("linear") => Some(BetaSchedule::Linear),
+        Some("scaled_linear") => Some(BetaSchedule::ScaledLinear),
+        Some("squaredcos_cap_v2") => Some(BetaSchedule::SquaredcosCapV2),
+        Some(other) => return Err(LuaError::RuntimeError(format!("invalid beta_schedule: {}", other))),
+    };
+
+    let customized = kind.is_some() || eta.is_some() || prediction_type.is_some()
+        || timestep_spacing.is_some() || beta_schedule.is_some();
+    let kind = kind.unwrap_or_else(|| sd_version.default_scheduler());
+    if !customized {
+        return Ok((kind, None));
+    }
+    if eta.is_some() && kind != SchedulerKind::Ddim {
+        return Err(LuaError::RuntimeError(format!("eta is only used by ddim, not {}", kind.name())));
+    }
+    if timestep_spacing.is_some() && kind == SchedulerKind::Ddpm {
+        return Err(LuaError::RuntimeError("timestep_spacing is not supported by ddpm".to_string()));
+    }
+    let prediction_type = prediction_type.unwrap_or_else(|| sd_version.default_prediction_type());
+    let timestep_spacing = timestep_spacing.unwrap_or_else(|| sd_version.default_timestep_spacing());
+    let config = match kind {
+        SchedulerKind::Ddim => {
+            let default = ddim::DDIMSchedulerConfig::default();
+            SchedulerConfig::Ddim(ddim::DDIMSchedulerConfig {
+                eta: eta.unwrap_or(default.eta),
+                prediction_type,
+                timestep_spacing,
+                beta_schedule: beta_schedule.unwrap_or(default.beta_schedule),
+                ..default
+            })
+        }
+        SchedulerKind::EulerAncestral => {
+            let default = euler_ancestral_discrete::EulerAncestralDiscreteSchedulerConfig::default();
+            SchedulerConfig::EulerAncestral(euler_ancestral_discrete::EulerAncestralDiscreteSchedulerConfig {
+                prediction_type,
+                timestep_spacing,
+                beta_schedule: beta_schedule.unwrap_or(default.beta_schedule),
+                ..default
+            })
+        }
+        SchedulerKind::Ddpm => {
+            let default = ddpm::DDPMSchedulerConfig::default();
+            SchedulerConfig::Ddpm(ddpm::DDPMSchedulerConfig {
+                prediction_type,
+                beta_schedule: beta_schedule.unwrap_or(default.beta_schedule),
+                ..default
+            })
+        }
+    };
+    Ok((kind, Some(config)))
+}
 
 struct Args {
     /// The prompt to be used for image generation.
     prompt: String,
-    uncond_prompt: String,  // default_value = ""
-    /// The height in pixels of the generated image.
-    height: usize,
-    /// The width in pixels of the generated image.
-    width: usize,
-    /// The CLIP weight file, in .safetensors format passed in as string.
+    /// The negative prompt, used with classifier-free guidance. default_value = ""
+    uncond_prompt: String,
+    /// The CLIP weight file, in .safetensors format.
     clip_weights: Vec<u8>,
     /// The VAE weight file, in .safetensors format.
     vae_weights: Vec<u8>,
     /// The UNet weight file, in .safetensors format.
     unet_weights: Vec<u8>,
-    /// The file specifying the tokenizer to use for tokenization.
     tokenizer: Tokenizer,
-    /// The size of the sliced attention or 0 for automatic slicing (disabled by default)
+    /// The second CLIP weight file of SDXL-like versions, in .safetensors format.
+    clip2_weights: Option<Vec<u8>>,
+    /// The tokenizer of the second text encoder, which pads with `!` rather than `<|endoftext|>`.
+    tokenizer2: Option<Tokenizer>,
+    /// The size of the sliced attention or 0 for automatic slicing. default_value = 0
     sliced_attention_size: Option<usize>,
-    /// The number of steps to run the diffusion for.
+    /// The number of steps to run the diffusion for, defaults per version.
     n_steps: usize,
-    /// The number of samples to generate iteratively.
+    /// The number of samples to generate iteratively. default_value_t = 1
     num_samples: usize,
-    /// The numbers of samples to generate simultaneously.
-    bsize: Option<usize>,
-    /// The name of the final image to generate.
-    final_image: String,
+    /// The numbers of samples to generate simultaneously. default_value_t = 1
+    bsize: usize,
     sd_version: StableDiffusionVersion,
-    /// Generate intermediary images at each step.
-    intermediary_images: bool,
+    /// The denoising scheduler, defaults per version.
+    scheduler: SchedulerKind,
     use_flash_attn: bool,
-    use_f16: bool,
+    /// Classifier-free guidance scale, defaults per version; 1 or less disables guidance.
     guidance_scale: f64,
-    img2img: Option<String>,
+    /// The initial image, see `common::load_bytes` for the accepted inputs.
+    img2img: Option<Vec<u8>>,
     /// The strength, indicates how much to transform the initial image. The
     /// value must be between 0 and 1, a value of 1 discards the initial image
     /// information.
     img2img_strength: f64,
-    /// The seed to use when generating random samples.
+    /// The seed of the noise, defaults to one derived from the message being handled.
     seed: u64,
-    first: bool,
     dtype: DType,
     device: Device,
     sd_config: StableDiffusionConfig,
 }
 
-impl UserData for Args { }
-
-impl<'lua> FromLua<'lua> for Args {
-    fn from_lua(value: LuaValue<'lua>, _: &'lua Lua) -> LuaResult<Self> {
-        match value {
-            LuaValue::Table(table) => {
-                let prompt: String = table.get("prompt")?;
-                let uncond_prompt: String = table.get("uncond_prompt").unwrap_or(String::from(""));
-                let height: usize = table.get("height").unwrap_or(1024);
-                let width: usize = table.get("width").unwrap_or(768);
-
-                let clip_weights: String = table.get("clip_model")?;
-                let clip_weights = BASE64_STANDARD.decode(clip_weights)
-                    .map_err(|err| LuaError::RuntimeError(err.to_string()))?;
-                // let clip_weights: Vec<u8> = clip_weights.as_bytes().to_vec();
-
-                let vae_weights: String = table.get("vae_model")?;
-                let vae_weights: Vec<u8> = BASE64_STANDARD.decode(vae_weights)
-                    .map_err(|err| LuaError::RuntimeError(err.to_string()))?;
-                // let vae_weights: Vec<u8> = vae_weights.as_bytes().to_vec();
-
-                let unet_weights: String = table.get("unet_model")?;
-                let unet_weights: Vec<u8> = BASE64_STANDARD.decode(unet_weights)
-                    .map_err(|err| LuaError::RuntimeError(err.to_string()))?;
-                // let unet_weights: Vec<u8> = unet_weights.as_bytes().to_vec();
-
-                let tokenizer: String = table.get("tokenizer")?;
-                // let tokenizer: Vec<u8> = tokenizer.as_bytes().to_vec();
-                let tokenizer: Vec<u8> = BASE64_STANDARD.decode(tokenizer)
-                    .map_err(|err| LuaError::RuntimeError(err.to_string()))?;
-                let mut tokenizer = Tokenizer::from_bytes(tokenizer)
-                    .map_err(|err| LuaError::RuntimeError(err.to_string()))?;
-
-                let sd_version: String = table.get("sd_version")
-                    .unwrap_or(String::from("v1_5"))
-                    .replace(".", "_")
-                    .to_lowercase();
-                let sd_version: StableDiffusionVersion = match sd_version.as_str() {
-                    "v1_5" => Ok(StableDiffusionVersion::V1_5),
-                    "v2_1" => Ok(StableDiffusionVersion::V2_1),
-                    "xl" => Ok(StableDiffusionVersion::Xl),
-                    "turbo" => Ok(StableDiffusionVersion::Turbo),
-                    _ => Err(mlua::Error::FromLuaConversionError {
-                        from: "string",
-                        to: "StableDiffusionVersion",
-                        message: Some(format!("invalid stable diffusion version: {}", sd_version)),
-                    }),
-                }?;
-                let n_steps: Option<usize> = table.get("n_steps")?;
-                let n_steps = match n_steps {
-                    Some(n_steps) => n_steps,
-                    None => match sd_version {
-                        StableDiffusionVersion::V1_5
-                        | StableDiffusionVersion::V2_1
-                        | StableDiffusionVersion::Xl => 30,
-                        StableDiffusionVersion::Turbo => 1,
-                    },
-                };
-                let num_samples: usize = table.get("num_samples").unwrap_or(1);
-                let bsize: Option<usize> = table.get("bsize")?;
-                let sliced_attention_size: Option<usize> = table.get("sliced_attention_size").unwrap_or(Some(0));
-                let final_image: String = table.get("final_image").unwrap_or(String::from("sd_final.png"));
-
-                let intermediary_images: bool = table.get("intermediary_images")?;
-                let use_flash_attn: bool = table.get("use_flash_attn")?;
-                let guidance_scale: Option<f64> = table.get("guidance_scale")?;
-                let guidance_scale: f64 = match guidance_scale {
-                    Some(guidance_scale) => guidance_scale,
-                    None => match sd_version {
-                        StableDiffusionVersion::V1_5
-                        | StableDiffusionVersion::V2_1
-                        | StableDiffusionVersion::Xl => 7.5,
-                        StableDiffusionVersion::Turbo => 0.,
-                    },
-                };
-                let img2img: Option<String> = table.get("img2img")?;
-                let img2img_strength: f64 = table.get("img2img_strength").unwrap_or(0.8);
-                if !(0. ..=1.).contains(&img2img_strength) {
-                    return Err(LuaError::RuntimeError(
-                        format!(
-                            "Stable-Diffusion arg 'img2img_strength' should be between 0 and 1, got {}",
-                            img2img_strength
-                        )
-                    ))
-                }
-                let first: bool = table.get("first")?;
-                let device: Device = Device::Cpu;
-                let use_f16: bool = table.get("use_f16").unwrap_or(false);
-                let dtype: DType = if use_f16 { DType::F16 } else { DType::F32 };
-                let seed: u64 = table.get("seed").unwrap_or(42u64);
-                device.set_seed(seed)
-                    .map_err(|e| LuaError::ExternalError);
-
-                let sd_config = match sd_version {
-                    StableDiffusionVersion::V1_5 => {
-                        StableDiffusionConfig::v1_5(sliced_attention_size, Some(height), Some(width))
-                    }
-                    StableDiffusionVersion::V2_1 => {
-                        StableDiffusionConfig::v2_1(sliced_attention_size, Some(height), Some(width))
-                    }
-                    StableDiffusionVersion::Xl => {
-                        StableDiffusionConfig::sdxl(sliced_attention_size, Some(height), Some(width))
-                    }
-                    StableDiffusionVersion::Turbo => {
-                        StableDiffusionConfig::sdxl_turbo(sliced_attention_size, Some(height), Some(width))
-                    }
-                };
-                Ok(Args {
-                    prompt, uncond_prompt, width, height, clip_weights, unet_weights, vae_weights,
-                    tokenizer, sliced_attention_size, n_steps, num_samples, bsize, final_image,
-                    sd_version, intermediary_images, use_flash_attn, use_f16, guidance_scale,
-                    img2img, img2img_strength, seed, first, dtype, device, sd_config
-                })
-            },
-            _ => {
-                Err(mlua::Error::FromLuaConversionError {
-                    from: value.type_name(),
-                    to: "Args",
-                    message: None,
-                })
+impl Args {
+    fn from_table(table: &LuaTable) -> LuaResult<Self> {
+        let sd_version = StableDiffusionVersion::from_str(
+            &table.get::<_, Option<String>>("sd_version")?.unwrap_or_else(|| "v1_5".to_string())
+        )?;
+        let height: Option<usize> = table.get("height")?;
+        let width: Option<usize> = table.get("width")?;
+        for (name, size) in [("height", height), ("width", width)] {
+            if size.map_or(false, |size| size == 0 || size % 8 != 0) {
+                return Err(LuaError::RuntimeError(format!("{} has to be a multiple of 8", name)));
             }
         }
+        let img2img_strength: f64 = table.get("img2img_strength").unwrap_or(0.8);
+        if !(0. ..=1.).contains(&img2img_strength) {
+            return Err(LuaError::RuntimeError(format!(
+                "img2img_strength should be between 0 and 1, got {}",
+                img2img_strength
+            )));
+        }
+        let sliced_attention_size: Option<usize> = table.get("sliced_attention_size").unwrap_or(Some(0));
+        let use_f16: bool = table.get("use_f16").unwrap_or(false);
+        let (scheduler, scheduler_config) = scheduler_config(table, sd_version)?;
+        let mut sd_config = sd_version.config(sliced_attention_size, height, width);
+        if let Some(scheduler_config) = scheduler_config {
+            sd_config.scheduler = scheduler_config;
+        }
+        let clip2_model: LuaValue = table.get("clip2_model")?;
+        let tokenizer2: LuaValue = table.get("tokenizer2")?;
+        let (clip2_weights, tokenizer2) = match (sd_config.clip2.is_some(), clip2_model, tokenizer2) {
+            (false, LuaValue::Nil, LuaValue::Nil) => (None, None),
+            (false, _, _) => {
+                return Err(LuaError::RuntimeError(format!(
+                    "clip2_model and tokenizer2 are only used by SDXL versions, not {}",
+                    sd_version.name()
+                )));
+            }
+            (true, LuaValue::Nil, _) | (true, _, LuaValue::Nil) => {
+                return Err(LuaError::RuntimeError(format!(
+                    "{} needs both text encoders, clip2_model and tokenizer2 are required",
+                    sd_version.name()
+                )));
+            }
+            (true, clip2_model, tokenizer2) => (
+                Some(load_bytes(clip2_model, "clip2_model")?),
+                Some(Tokenizer::from_bytes(load_bytes(tokenizer2, "tokenizer2")?)
+                    .map_err(LuaError::external)?),
+            ),
+        };
+        Ok(Args {
+            prompt: table.get("prompt")?,
+            uncond_prompt: table.get::<_, Option<String>>("uncond_prompt")?.unwrap_or_default(),
+            clip_weights: load_bytes(table.get("clip_model")?, "clip_model")?,
+            vae_weights: load_bytes(table.get("vae_model")?, "vae_model")?,
+            unet_weights: load_bytes(table.get("unet_model")?, "unet_model")?,
+            tokenizer: Tokenizer::from_bytes(load_bytes(table.get("tokenizer")?, "tokenizer")?)
+                .map_err(LuaError::external)?,
+            clip2_weights,
+            tokenizer2,
+            sliced_attention_size,
+            n_steps: table.get::<_, Option<usize>>("n_steps")?.unwrap_or_else(|| sd_version.default_steps()),
+            num_samples: table.get("num_samples").unwrap_or(1usize),
+            bsize: table.get::<_, Option<usize>>("bsize")?.unwrap_or(1).max(1),
+            sd_version,
+            scheduler,
+            use_flash_attn: table.get("use_flash_attn").unwrap_or(false),
+            guidance_scale: table.get::<_, Option<f64>>("guidance_scale")?
+                .unwrap_or_else(|| sd_version.default_guidance_scale()),
+            img2img: match table.get::<_, LuaValue>("img2img")? {
+                LuaValue::Nil => None,
+                image => Some(load_bytes(image, "img2img")?),
+            },
+            img2img_strength,
+            seed: table.get::<_, Option<u64>>("seed")?.unwrap_or_else(message_seed),
+            dtype: if use_f16 { DType::F16 } else { DType::F32 },
+            device: Device::Cpu,
+            sd_config,
+        })
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum StableDiffusionVersion {
-    V1_5,
-    V2_1,
-    Xl,
-    Turbo,
+/// One image of the output.
+#[derive(serde::Serialize, Debug)]
+struct GeneratedImage {
+    /// PNG data URL.
+    image: String,
+    /// 1-based position among all the images.
+    index: usize,
+    /// The `num_samples` iteration the image comes from, 1-based.
+    sample: usize,
+    /// The seed of all the noise of this image, which reproduces it given the same options.
+    seed: u64,
+    /// Denoising steps run, fewer than `n_steps` for img2img.
+    steps: usize,
+}
+
+/// What `text_to_image.generate` hands back to Lua.
+#[derive(serde::Serialize, Debug)]
+struct TextToImageOutput {
+    /// Every image of every batch, in order.
+    images: Vec<GeneratedImage>,
+    seed: u64,
+    n_steps: usize,
+    guidance_scale: f64,
+    width: usize,
+    height: usize,
+    sd_version: &'static str,
+    scheduler: &'static str,
+    elapsed: f64,
 }
 
+/// Standard normal noise from seeded generators: the CPU device can't be seeded, so
+/// `Tensor::randn` would make every run different.  Each image of the batch draws all its
+/// noise from its own generator, seeded with `seed + i`, so it comes out the same whatever
+/// the batch size.
+struct Noise {
+    rngs: Vec<StdRng>,
+}
 
+impl Noise {
+    fn new(seed: u64, bsize: usize) -> Self {
+        Noise { rngs: (0..bsize).map(|i| StdRng::seed_from_u64(seed.wrapping_add(i as u64))).collect() }
+    }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum ModelFile {
-    Tokenizer,
-    Tokenizer2,
-    Clip,
-    Clip2,
-    Unet,
-    Vae,
+    /// `(bsize, channels, height, width)` noise, the next draw of each image's generator.
+    fn randn(&mut self, (channels, height, width): (usize, usize, usize), device: &Device) -> LuaResult<Tensor> {
+        let per_image = channels * height * width;
+        let noise: Vec<f32> = self.rngs.iter_mut().flat_map(|rng| randn_vec(per_image, rng)).collect();
+        Tensor::from_vec(noise, (self.rngs.len(), channels, height, width), device).map_err(LuaError::external)
+    }
 }
 
-fn output_filename(
-    basename: &str,
-    sample_idx: usize,
-    num_samples: usize,
-    timestep_idx: Option<usize>,
-) -> String {
-    let filename = if num_samples > 1 {
-        match basename.rsplit_once('.') {
-            None => format!("{basename}.{sample_idx}.png"),
-            Some((filename_no_extension, extension)) => {
-                format!("{filename_no_extension}.{sample_idx}.{extension}")
-            }
-        }
-    } else {
-        basename.to_string()
-    };
-    match timestep_idx {
-        None => filename,
-        Some(timestep_idx) => match filename.rsplit_once('.') {
-            None => format!("{filename}-{timestep_idx}.png"),
-            Some((filename_no_extension, extension)) => {
-                format!("{filename_no_extension}-{timestep_idx}.{extension}")
-            }
-        },
+fn randn_vec(count: usize, rng: &mut StdRng) -> Vec<f32> {
+    // Box-Muller, two samples per pair of uniforms.
+    let mut noise = Vec::with_capacity(count + 1);
+    while noise.len() < count {
+        let u1: f32 = rng.gen_range(f32::EPSILON..1.);
+        let u2: f32 = rng.gen();
+        let radius = (-2. * u1.ln()).sqrt();
+        let angle = 2. * std::f32::consts::PI * u2;
+        noise.push(radius * angle.cos());
+        noise.push(radius * angle.sin());
     }
+    noise.truncate(count);
+    noise
 }
 
-// #[allow(clippy::too_many_arguments)]
-// fn save_image(
-//     vae: &AutoEncoderKL,
-//     latents: &Tensor,
-//     vae_scale: f64,
-//     bsize: usize,
-//     idx: usize,
-//     final_image: &str,
-//     num_samples: usize,
-//     timestep_ids: Option<usize>,
-// ) -> Result<(), candle_core::Error> {
-//     let images = vae.decode(&(latents / vae_scale)?)?;
-//     let images = ((images / 2.)? + 0.5)?.to_device(&Device::Cpu)?;
-//     let images = (images.clamp(0f32, 1.)? * 255.)?.to_dtype(DType::U8)?;
-//     for batch in 0..bsize {
-//         let image = images.i(batch)?;
-//         let image_filename = output_filename(
-//             final_image,
-//             (bsize * idx) + batch + 1,
-//             batch + num_samples,
-//             timestep_ids,
-//         );
-//         save_image_file(&image, image_filename)?;
-//     }
-//     println!("Finished batch");
-//     Ok(())
-// }
-fn generate_image(
-    vae: &AutoEncoderKL,
-    latents: &Tensor,
-    vae_scale: f64,
-    bsize: usize,
-    idx: usize,
-    num_samples: usize,
-    timestep_ids: Option<usize>,
-) -> Result<String, candle_core::Error> {
-    let images = vae.decode(&(latents / vae_scale)?)?;
-    let images = ((images / 2.)? + 0.5)?.to_device(&Device::Cpu)?;
-    let images = (images.clamp(0f32, 1.)? * 255.)?.to_dtype(DType::U8)?;
-    // let mut image_buffers = Vec::new();
-    // for batch in 0..bsize {
-    //     let image = images.i(batch)?;
-    //     let image = image.permute((1, 2, 0))?.flatten_all()?;
-    //     let pixels = image.to_vec1::<u8>()?;
-    //     let (height, width) = image.dims2()?;
-    //     let image: image::ImageBuffer<image::Rgb<u8>, Vec<u8>> =
-    //         match image::ImageBuffer::from_raw(width as u32, height as u32, pixels) {
-    //             Some(image) => image,
-    //             None => candle_core::bail!("Error while creating image buffer"),
-    //         };
-    //
-    //     let mut buffer = Cursor::new(Vec::new());
-    //     let encoder = PngEncoder::new(&mut buffer);
-    //     match encoder.write_image(&image, image.width(), image.height(), ExtendedColorType::from(ColorType::Rgb8)) {
-    //        Ok(()) => {},
-    //        Err(e) => candle_core::bail!("Error while encoding image buffer to PNG: {}", e),
-    //     };
-    //
-    //     image_buffers.push(buffer.into_inner());
-    // }
-    // let images = vae.decode(&(latents / vae_scale)?)?;
-    // let images = ((images / 2.)? + 0.5)?.to_device(&Device::Cpu)?;
-    // let images = (images.clamp(0f32, 1.)? * 255.)?.to_dtype(DType::U8)?;
-    let image = images.i(0)?;
-    let (channel, height, width) = image.dims3()?;
+/// Encodes a `(3, height, width)` u8 tensor as a PNG data URL.
+fn encode_png(image: &Tensor) -> LuaResult<String> {
+    let (channel, height, width) = image.dims3().map_err(LuaError::external)?;
     if channel != 3 {
-        candle_core::bail!("save_image expects an input of shape (3, height, width)")
+        return Err(LuaError::RuntimeError("images must have the shape (3, height, width)".to_string()));
     }
-    let image = image.permute((1, 2, 0))?.flatten_all()?;
-    let pixels = image.to_vec1::<u8>()?;
-    // let (height, width) = image.dims2()?;
-
-    let image: image::ImageBuffer<image::Rgb<u8>, Vec<u8>> =
-        image::ImageBuffer::from_raw(width as u32, height as u32, pixels)
-            .ok_or_else(|| {
-                println!("Error while creating image buffer");
-                candle_core::Error::Msg("Error while creating image buffer".parse().unwrap())
-            })?;
-
+    let pixels = image.permute((1, 2, 0))
+        .and_then(|image| image.flatten_all())
+        .and_then(|image| image.to_vec1::<u8>())
+        .map_err(LuaError::external)?;
     let mut buffer = Cursor::new(Vec::new());
-    let encoder = PngEncoder::new(&mut buffer);
-    encoder.write_image(&image, image.width(), image.height(), ExtendedColorType::Rgb8)
-        .map_err(|e| AnyError::msg(e));
+    PngEncoder::new(&mut buffer)
+        .write_image(&pixels, width as u32, height as u32, ExtendedColorType::Rgb8)
+        .map_err(LuaError::external)?;
+    Ok(format!("data:image/png;base64,{}", BASE64_STANDARD.encode(buffer.into_inner())))
+}
 
-    // Ok(buffer.into_inner())
+/// Repeats each prompt's rows `bsize` times, `[uncond, cond]` becoming `[uncond, uncond, cond,
+/// cond]` to line up with the `[latents, latents]` model input.
+fn repeat_prompts(embeddings: &Tensor, bsize: usize) -> candle_core::Result<Tensor> {
+    let prompts = embeddings.chunk(embeddings.dim(0)?, 0)?;
+    let rows: Vec<&Tensor> = prompts.iter()
+        .flat_map(|prompt| std::iter::repeat(prompt).take(bsize))
+        .collect();
+    Tensor::cat(&rows, 0)
+}
 
-    let image_b64 = BASE64_STANDARD.encode(buffer.into_inner());
-    Ok(format!("data:image/png;base64,{}", image_b64))
+/// Decodes latents into the batch of images, as `(bsize, 3, height, width)` u8.
+fn decode_latents(
+    vae: &candle_transformers::models::stable_diffusion::vae::AutoEncoderKL,
+    latents: &Tensor,
+    vae_scale: f64,
+) -> candle_core::Result<Tensor> {
+    let images = vae.decode(&(latents / vae_scale)?)?;
+    let images = ((images / 2.)? + 0.5)?.to_device(&Device::Cpu)?;
+    (images.clamp(0f32, 1.)? * 255.)?.to_dtype(DType::U8)
 }
 
+/// Runs CLIP on the prompt, and on the negative prompt ahead of it when `use_guide_scale`.
+/// `first` picks the first or, for SDXL, the second text encoder, each padding the tokens
+/// as its own config says.  The second one also returns the pooled embeddings, its projected
+/// hidden state at the end-of-text token.
 #[allow(clippy::too_many_arguments)]
 fn text_embeddings(
-    lua: &Lua,
     prompt: &str,
     uncond_prompt: &str,
-    tokenizer: Tokenizer,
+    tokenizer: &Tokenizer,
     clip_weights: Vec<u8>,
-    sd_version: StableDiffusionVersion,
     sd_config: &StableDiffusionConfig,
-    use_f16: bool,
     device: &Device,
     dtype: DType,
     use_guide_scale: bool,
     first: bool,
-) -> AnyResult<Tensor> {
-    let globals = lua.globals();
-    let _print: LuaFunction = globals.get("print")?;
-    let print = |s: &str| { _print.call::<_, ()>(String::from(s)); };
-
-    print("Starting text_embeddings first line of function");
-    // let clip_config = if first {
-    //     &sd_config.clip
-    // } else {
-    //     sd_config.clip2.as_ref().unwrap()
-    // };
-    // print("Set clip_config");
-    //
-    // let clip_vb = VarBuilder::from_buffered_safetensors(clip_weights, dtype, &device)
-    //         .map_err(|err| LuaError::external(err))?;
-    //
-    // print("Set clip VarBuilder");
-    // let text_model = clip::ClipTextTransformer::new(clip_vb, clip_config)
-    //     .map_err(|err| {
-    //         println!("{}", err);
-    //         LuaError::external(err)
-    //     })?;
-    let text_model = sd_config.build_clip(clip_weights, device, dtype, first)
-        .map_err(|err| {
-            println!("{}", err);
-            LuaError::external(err)
-        })?;
-    print("Created textmodel");
-    let pad_id = match &sd_config.clip.pad_with {
-        Some(padding) => *tokenizer.get_vocab(true).get(padding.as_str()).unwrap(),
-        None => *tokenizer.get_vocab(true).get("<|endoftext|>").unwrap(),
+) -> LuaResult<(Tensor, Option<Tensor>)> {
+    let (text_model, projection) = if first {
+        sd_config.build_clip(clip_weights, device, dtype, true).map(|model| (model, None))
+    } else {
+        sd_config.build_clip2_with_projection(clip_weights, device, dtype)
+            .map(|(model, projection)| (model, Some(projection)))
+    }.map_err(|err| {
+        ao_log(&format!("!! Error loading the CLIP text model\n{}", err));
+        LuaError::external(err)
+    })?;
+    let clip_config = match (first, &sd_config.clip2) {
+        (false, Some(clip2)) => clip2,
+        _ => &sd_config.clip,
     };
-    println!("Running with prompt \"{prompt}\".");
-    let mut tokens = tokenizer
-        .encode(prompt, true)
-        .map_err(AnyError::msg)?
-        .get_ids()
-        .to_vec();
-    if tokens.len() > sd_config.clip.max_position_embeddings {
-        anyhow::bail!(
-            "the prompt is too long, {} > max-tokens ({})",
-            tokens.len(),
-            sd_config.clip.max_position_embeddings
-        )
-    }
-    while tokens.len() < sd_config.clip.max_position_embeddings {
-        tokens.push(pad_id)
-    }
-    let tokens = Tensor::new(tokens.as_slice(), &device)?.unsqueeze(0)?;
-
-    println!("Building the Clip transformer.");
-
-    let text_embeddings = text_model.forward(&tokens)
-            .map_err(|err| LuaError::external(err))?;
-
-    let text_embeddings = if use_guide_scale {
-        let mut uncond_tokens = tokenizer
-            .encode(uncond_prompt, true)
-            .map_err(|err| LuaError::external(err))?
+    let pad_token = clip_config.pad_with.as_deref().unwrap_or("<|endoftext|>");
+    let pad_id = tokenizer.token_to_id(pad_token)
+        .ok_or_else(|| LuaError::RuntimeError(format!("the tokenizer has no {} token", pad_token)))?;
+    let max_tokens = clip_config.max_position_embeddings;
+    let eos_id = tokenizer.token_to_id("<|endoftext|>");
+    let encode = |text: &str, name: &str| -> LuaResult<(Tensor, Option<Tensor>)> {
+        let mut tokens = tokenizer.encode(text, true)
+            .map_err(LuaError::external)?
             .get_ids()
-            .to_vec()
-            ;
-        if uncond_tokens.len() > sd_config.clip.max_position_embeddings {
-            anyhow::bail!(
-                "the negative prompt is too long, {} > max-tokens ({})",
-                uncond_tokens.len(),
-                sd_config.clip.max_position_embeddings
-            )
-        }
-        while uncond_tokens.len() < sd_config.clip.max_position_embeddings {
-            uncond_tokens.push(pad_id)
+            .to_vec();
+        if tokens.len() > max_tokens {
+            return Err(LuaError::RuntimeError(format!(
+                "the {} is too long, {} > max-tokens ({})", name, tokens.len(), max_tokens
+            )));
         }
+        let eos_position = eos_id
+            .and_then(|eos_id| tokens.iter().position(|token| *token == eos_id))
+            .unwrap_or(tokens.len().saturating_sub(1));
+        tokens.resize(max_tokens, pad_id);
+        let tokens = Tensor::new(tokens.as_slice(), device)
+            .and_then(|tokens| tokens.unsqueeze(0))
+            .map_err(LuaError::external)?;
+        let hidden = text_model.forward(&tokens).map_err(LuaError::external)?;
+        let pooled = match &projection {
+            Some(projection) => Some(
+                hidden.i((.., eos_position, ..))
+                    .and_then(|eos| projection.forward(&eos))
+                    .map_err(LuaError::external)?
+            ),
+            None => None,
+        };
+        Ok((hidden, pooled))
+    };
 
-        let uncond_tokens = Tensor::new(uncond_tokens.as_slice(), &device)
-            .map_err(|err| LuaError::external(err))?
-            .unsqueeze(0)
-            .map_err(|err| LuaError::external(err))?
-            ;
-        let uncond_embeddings = text_model.forward(&uncond_tokens)
-            .map_err(|err| LuaError::external(err))?;
-
-        Tensor::cat(&[uncond_embeddings, text_embeddings], 0)
-            .map_err(|err| LuaError::external(err))?
-            .to_dtype(dtype)
-            .map_err(|err| LuaError::external(err))?
+    let (text_embeddings, pooled) = encode(prompt, "prompt")?;
+    let (text_embeddings, pooled) = if use_guide_scale {
+        let (uncond_embeddings, uncond_pooled) = encode(uncond_prompt, "negative prompt")?;
+        let pooled = match (uncond_pooled, pooled) {
+            (Some(uncond_pooled), Some(pooled)) => {
+                Some(Tensor::cat(&[uncond_pooled, pooled], 0).map_err(LuaError::external)?)
+            }
+            _ => None,
+        };
+        (Tensor::cat(&[uncond_embeddings, text_embeddings], 0).map_err(LuaError::external)?, pooled)
     } else {
-        text_embeddings.to_dtype(dtype).map_err(|err| LuaError::external(err))?
+        (text_embeddings, pooled)
     };
-    Ok(text_embeddings)
+    let pooled = pooled.map(|pooled| pooled.to_dtype(dtype)).transpose().map_err(LuaError::external)?;
+    Ok((text_embeddings.to_dtype(dtype).map_err(LuaError::external)?, pooled))
 }
 
+/// Zeroes the negative prompt's half of guided embeddings.
+fn zero_uncond(embeddings: &Tensor) -> candle_core::Result<Tensor> {
+    let cond = embeddings.narrow(0, 1, 1)?;
+    Tensor::cat(&[&cond.zeros_like()?, &cond], 0)
+}
 
-// fn run(lua: &Lua, args: Args) -> LuaResult<()> {
-fn run(lua: &Lua, args: Args) -> LuaResult<mlua::Variadic<String>> {
-    let globals = lua.globals();
-    let _print: LuaFunction = globals.get("print")?;
-    let print = |s: &str| { _print.call::<_, ()>(String::from(s)); };
-    // let prompt = args.prompt.unwrap_or("".parse().unwrap());
-    // let uncond_prompt = args.uncond_prompt.clone().unwrap_or(String::from(""));
-
-    print("Starting to run, validating args...");
+fn run(mut args: Args) -> LuaResult<TextToImageOutput> {
+    let start = std::time::Instant::now();
+    let use_guide_scale = args.guidance_scale > 1.0;
+    args.sd_config.check_text_time().map_err(LuaError::external)?;
 
-    let height = args.height;
-    let width = args.width;
-    let sliced_attention_size = args.sliced_attention_size;
-    let sd_version = args.sd_version;
-    let bsize = args.bsize.unwrap_or(1);
-    let use_f16 = args.use_f16;
+    // SDXL concatenates the hidden states of its two text encoders, and conditions its UNet on
+    // the pooled embeddings of the second one.
+    let mut encoders = vec![(&args.tokenizer, std::mem::take(&mut args.clip_weights), true)];
+    if let (Some(tokenizer2), Some(clip2_weights)) = (&args.tokenizer2, args.clip2_weights.take()) {
+        encoders.push((tokenizer2, clip2_weights, false));
+    }
+    let text_embeddings = encoders.into_iter()
+        .map(|(tokenizer, clip_weights, first)| text_embeddings(
+            &args.prompt,
+            &args.uncond_prompt,
+            tokenizer,
+            clip_weights,
+            &args.sd_config,
+            &args.device,
+            args.dtype,
+            use_guide_scale,
+            first,
+        ))
+        .collect::<LuaResult<Vec<_>>>()?;
+    let pooled = text_embeddings.iter().find_map(|(_, pooled)| pooled.clone());
+    let hidden: Vec<Tensor> = text_embeddings.into_iter().map(|(hidden, _)| hidden).collect();
+    let text_embeddings = Tensor::cat(&hidden, D::Minus1).map_err(LuaError::external)?;
+    // Like diffusers' `force_zeros_for_empty_prompt`, SDXL is guided away from zeros rather
+    // than from an empty prompt.
+    let zero_uncond_prompt = use_guide_scale && args.uncond_prompt.is_empty() && args.sd_config.text_time.is_some();
+    let (text_embeddings, pooled) = if zero_uncond_prompt {
+        (
+            zero_uncond(&text_embeddings).map_err(LuaError::external)?,
+            pooled.map(|pooled| zero_uncond(&pooled)).transpose().map_err(LuaError::external)?,
+        )
+    } else {
+        (text_embeddings, pooled)
+    };
+    diffuse(args, &text_embeddings, pooled.as_ref(), start)
+}
 
+/// Denoises `bsize` latents at a time conditioned on `text_embeddings`, the negative then
+/// the positive prompt's with guidance, and decodes them.  `pooled` are the pooled embeddings
+/// in the same order, required by SDXL.  The text encoders are not used.
+fn diffuse(
+    args: Args,
+    text_embeddings: &Tensor,
+    pooled: Option<&Tensor>,
+    start: std::time::Instant,
+) -> LuaResult<TextToImageOutput> {
+    let sd_config = &args.sd_config;
+    let bsize = args.bsize;
     let use_guide_scale = args.guidance_scale > 1.0;
-
-    // print("About to create models...");
-    // let (
-    //     // text_model,
-    //     vae_model,
-    //     unet_model,
-    //     tokenizer
-    // ) = args
-    //     .load_model(args.sd_config.clone())
-    //     .map_err(|err| LuaError::external(err))?;
-    // print("Created models...");
-
-    let scheduler = args.sd_config
-        .build_scheduler(args.n_steps)
-        .map_err(|e| LuaError::RuntimeError(e.to_string()))?;
-
-    let which = match sd_version {
-        StableDiffusionVersion::Xl | StableDiffusionVersion::Turbo => vec![true, false],
-        _ => vec![true],
+    let scheduler = sd_config.build_scheduler(args.n_steps).map_err(LuaError::external)?;
+    let text_embeddings = repeat_prompts(text_embeddings, bsize).map_err(LuaError::external)?;
+    // The original and target sizes are the output size, without cropping.
+    let text_time = match (&sd_config.text_time, pooled) {
+        (None, _) => None,
+        (Some(_), Some(pooled)) => {
+            let (height, width) = (sd_config.height as f32, sd_config.width as f32);
+            let text_embeds = repeat_prompts(pooled, bsize).map_err(LuaError::external)?;
+            let time_ids = Tensor::new(&[height, width, 0., 0., height, width], &args.device)
+                .and_then(|ids| ids.unsqueeze(0))
+                .and_then(|ids| ids.repeat((text_embeds.dim(0)?, 1)))
+                .and_then(|ids| ids.to_dtype(args.dtype))
+                .map_err(LuaError::external)?;
+            Some(TextTimeConditioning { text_embeds, time_ids })
+        }
+        (Some(_), None) => {
+            return Err(LuaError::RuntimeError(format!(
+                "{} needs the pooled embeddings of the second text encoder",
+                args.sd_version.name()
+            )));
+        }
     };
 
-    // print("prepare tokenizer");
-    // let mut tokenizer = Tokenizer::from_bytes(args.tokenizer.clone().as_bytes())
-    //         .map_err(|err| LuaError::external(err))?;
-
-    // let clip_model_bytes: Vec<u8> = args.clip_weights.as_bytes().to_vec();
-    print("About to start text_embeddings");
-    let text_embeddings = which
-        .iter()
-        .map(|first| {
-            text_embeddings(
-                &lua,
-                &args.prompt,
-                &args.uncond_prompt,
-                args.tokenizer.clone(),
-                args.clip_weights.clone(),
-                sd_version,
-                &args.sd_config,
-                args.use_f16,
-                &args.device,
-                args.dtype,
-                use_guide_scale,
-                *first,
-            )
-        })
-        .collect::<Result<Vec<_>, AnyError>>() // ?;
-        // .map_err(|e| LuaError::RuntimeError(e.to_string()))?;
-        .map_err(|e| {
-            println!("Error: {:?}", e);
-            LuaError::external(e)
+    let with_encoder = args.img2img.is_some();
+    let (vae_model, vae_encoder) = sd_config.build_vae(args.vae_weights, &args.device, args.dtype, with_encoder)
+        .map_err(|err| {
+            ao_log(&format!("!! Error loading the VAE\n{}", err));
+            LuaError::external(err)
+        })?;
+    let unet_model = sd_config.build_unet(args.unet_weights, &args.device, 4, args.use_flash_attn, args.dtype)
+        .map_err(|err| {
+            ao_log(&format!("!! Error loading the UNet\n{}", err));
+            LuaError::external(err)
         })?;
 
-    print("Line 477");
-
-    let text_embeddings = Tensor::cat(&text_embeddings, D::Minus1)
-        .map_err(|e| LuaError::RuntimeError(e.to_string()))?;
-
-    let text_embeddings = text_embeddings
-        .repeat((bsize, 1, 1))
-        // .map_err(|e| LuaError::ExternalError);
-        .map_err(|e| LuaError::RuntimeError(e.to_string()))?;
-    print("finished text embeddings");
-
-    // Create VAE Model
-    // https://huggingface.co/runwayml/stable-diffusion-v1-5/blob/main/vae/config.json
-    let vae_model: AutoEncoderKL = args.sd_config.build_vae(
-        args.vae_weights,
-        &args.device,
-        args.dtype,
-    ).map_err(|e| LuaError::RuntimeError(e.to_string()))?;
-
-    print("Finished vae_model");
-    // Create UNET Model
-    let unet_model = args.sd_config.build_unet(
-        args.unet_weights.clone(),
-        &args.device,
-        4,
-        args.use_flash_attn,
-        args.dtype
-    ).map_err(|e| {
-        print(&*e.to_string());
-        println!("{}", e.to_string());
-        LuaError::RuntimeError(e.to_string())
-    })?;
-
-    let init_latent_dist = match &args.img2img {
-        None => None,
-        Some(image) => {
-            let image = image_preprocess(image)
-                .map_err(|e| LuaError::RuntimeError(e.to_string()))?
-                .to_device(&args.device)
-                .map_err(|e| LuaError::RuntimeError(e.to_string()))?;
-            Some(vae_model
-                .encode(&image)
-                .map_err(|e| LuaError::RuntimeError(e.to_string()))?
-            )
+    // The mean and standard deviation of the initial image's latents.
+    let posterior = match (&args.img2img, &vae_encoder) {
+        (Some(image), Some(vae_encoder)) => {
+            let image = image_preprocess(image, sd_config.height, sd_config.width)
+                .and_then(|image| image.to_device(&args.device))
+                .and_then(|image| image.to_dtype(args.dtype))
+                .map_err(LuaError::external)?;
+            Some(vae_encoder.posterior(&image).map_err(LuaError::external)?)
         }
+        _ => None,
     };
-
     let t_start = if args.img2img.is_some() {
         args.n_steps - (args.n_steps as f64 * args.img2img_strength) as usize
     } else {
         0
     };
+    let vae_scale = args.sd_version.vae_scale();
 
-    let vae_scale = match sd_version {
-        StableDiffusionVersion::V1_5
-        | StableDiffusionVersion::V2_1
-        | StableDiffusionVersion::Xl => 0.18215,
-        StableDiffusionVersion::Turbo => 0.13025,
-    };
-
-    let mut images_array = Vec::new();
-
+    let mut images = Vec::with_capacity(args.num_samples * bsize);
     for idx in 0..args.num_samples {
-        println!("Starting batch {}", idx);
+        let seed = args.seed.wrapping_add((idx * bsize) as u64);
         let timesteps = scheduler.timesteps();
-        let latents = match &init_latent_dist {
-            Some(init_latent_dist) => {
-                let latents = init_latent_dist.sample()
-                    .map_err(|e| LuaError::RuntimeError(e.to_string()))?;
-                let latents = (latents * vae_scale)
-                    .map_err(|e| LuaError::RuntimeError(e.to_string()))?
-                    .to_device(&args.device)
-                    .map_err(|e| LuaError::RuntimeError(e.to_string()))?;
+        let mut noise = Noise::new(seed, bsize);
+        let latent_dims = (4, sd_config.height / 8, sd_config.width / 8);
+        let latents = match &posterior {
+            Some((mean, std)) => {
+                // Sampled from the posterior with the image's own noise, `mean + std * noise`.
+                let latents = noise.randn(latent_dims, &args.device)?
+                    .to_dtype(mean.dtype())
+                    .and_then(|sample| sample.broadcast_mul(std))
+                    .and_then(|sample| sample.broadcast_add(mean))
+                    .and_then(|latents| latents * vae_scale)
+                    .map_err(LuaError::external)?;
                 if t_start < timesteps.len() {
-                    let noise = latents
-                        .randn_like(0f64, 1f64)
-                        .map_err(|e| LuaError::RuntimeError(e.to_string()))?;
-                    scheduler
-                        .add_noise(&latents, noise, timesteps[t_start])
-                        .map_err(|e| LuaError::RuntimeError(e.to_string()))?
+                    let noise = noise.randn(latent_dims, &args.device)?
+                        .to_dtype(latents.dtype())
+                        .map_err(LuaError::external)?;
+                    scheduler.add_noise(&latents, noise, timesteps[t_start]).map_err(LuaError::external)?
                 } else {
                     latents
                 }
             }
             None => {
-                let latents = Tensor::randn(
-                    0f32,
-                    1f32,
-                    (bsize, 4, args.sd_config.height / 8, args.sd_config.width / 8),
-                    &args.device,
-                ).map_err(|e| LuaError::RuntimeError(e.to_string()))?;
-                // scale the initial noise by the standard deviation required by the scheduler
-                (latents * scheduler.init_noise_sigma())
-                    .map_err(|e| LuaError::RuntimeError(e.to_string()))?
+                // Scale the initial noise by the standard deviation required by the scheduler.
+                (noise.randn(latent_dims, &args.device)? * scheduler.init_noise_sigma())
+                    .map_err(LuaError::external)?
             }
         };
-        let mut latents = latents
-            .to_dtype(args.dtype)
-            .map_err(|e| LuaError::RuntimeError(e.to_string()))?;
+        let mut latents = latents.to_dtype(args.dtype).map_err(LuaError::external)?;
 
-        println!("starting sampling");
         for (timestep_index, &timestep) in timesteps.iter().enumerate() {
             if timestep_index < t_start {
                 continue;
             }
-            let start_time = std::time::Instant::now();
+            let step_start = std::time::Instant::now();
             let latent_model_input = if use_guide_scale {
-                Tensor::cat(&[&latents, &latents], 0)
-                    .map_err(|e| LuaError::RuntimeError(e.to_string()))?
+                Tensor::cat(&[&latents, &latents], 0).map_err(LuaError::external)?
             } else {
                 latents.clone()
             };
-
-            let latent_model_input = scheduler
-                .scale_model_input(latent_model_input, timestep)
-                .map_err(|e| LuaError::RuntimeError(e.to_string()))?;
-            let noise_pred = unet_model
-                .forward(&latent_model_input, timestep as f64, &text_embeddings)
-                .map_err(|e| LuaError::RuntimeError(e.to_string()))?;
-
+            let latent_model_input = scheduler.scale_model_input(latent_model_input, timestep)
+                .map_err(LuaError::external)?;
+            let noise_pred = unet_model.forward(&latent_model_input, timestep as f64, &text_embeddings, text_time.as_ref())
+                .map_err(LuaError::external)?;
             let noise_pred = if use_guide_scale {
-                let noise_pred = noise_pred
-                    .chunk(2, 0)
-                    .map_err(|e| LuaError::RuntimeError(e.to_string()))?;
-
+                let noise_pred = noise_pred.chunk(2, 0).map_err(LuaError::external)?;
                 let (noise_pred_uncond, noise_pred_text) = (&noise_pred[0], &noise_pred[1]);
-                (
-                    noise_pred_uncond + (
-                        (noise_pred_text - noise_pred_uncond).map_err(|e| LuaError::RuntimeError(e.to_string()))?
-                            * args.guidance_scale
-                    ).map_err(|e| LuaError::RuntimeError(e.to_string()))?
-                ).map_err(|e| LuaError::RuntimeError(e.to_string()))?
+                ((noise_pred_text - noise_pred_uncond)
+                    .and_then(|guidance| guidance * args.guidance_scale)
+                    .and_then(|guidance| noise_pred_uncond + guidance))
+                    .map_err(LuaError::external)?
             } else {
                 noise_pred
             };
+            let step_noise = if scheduler.adds_noise() {
+                Some(noise.randn(latent_dims, &args.device)?.to_dtype(latents.dtype()).map_err(LuaError::external)?)
+            } else {
+                None
+            };
+            latents = scheduler.step(&noise_pred, timestep, &latents, step_noise.as_ref())
+                .map_err(LuaError::external)?;
+            ao_log(&format!(
+                "Step {}/{} done, {:.2}s",
+                timestep_index + 1,
+                args.n_steps,
+                step_start.elapsed().as_secs_f32(),
+            ));
+        }
+
+        let decoded = decode_latents(&vae_model, &latents, vae_scale).map_err(LuaError::external)?;
+        for batch in 0..bsize {
+            images.push(GeneratedImage {
+                image: encode_png(&decoded.i(batch).map_err(LuaError::external)?)?,
+                index: idx * bsize + batch + 1,
+                sample: idx + 1,
+                seed: seed.wrapping_add(batch as u64),
+                steps: timesteps.len().saturating_sub(t_start),
+            });
+        }
+    }
+
+    Ok(TextToImageOutput {
+        images,
+        seed: args.seed,
+        n_steps: args.n_steps,
+        guidance_scale: args.guidance_scale,
+        width: sd_config.width,
+        height: sd_config.height,
+        sd_version: args.sd_version.name(),
+        scheduler: args.scheduler.name(),
+        elapsed: start.elapsed().as_secs_f64(),
+    })
+}
 
-            latents = scheduler.step(&noise_pred, timestep, &latents)
-                .map_err(|e| LuaError::RuntimeError(e.to_string()))?;
-            let dt = start_time.elapsed().as_secs_f32();
-            println!("Step {}/{} done, {:.2}s", timestep_index + 1, args.n_steps, dt);
-
-            // if args.intermediary_images {
-            //     save_image(
-            //         &vae_model,
-            //         &latents,
-            //         vae_scale,
-            //         bsize,
-            //         idx,
-            //         // &args.final_image,
-            //         args.num_samples,
-            //         Some(timestep_index + 1),
-            //     ).map_err(|e| LuaError::RuntimeError(e.to_string()));
-            // }
+/// Lua entry point, `text_to_image.generate(opts)`.
+///
+/// Generates images for `opts.prompt` with Stable Diffusion.  `clip_model`, `vae_model` and
+/// `unet_model` are safetensors weights and `tokenizer` the CLIP `tokenizer.json`, see
+/// `common::load_bytes` for the accepted inputs.  `sd_version` is `v1_5` (default), `v2_1`,
+/// `xl`, `turbo` or `ssd1b` and sets the defaults of `n_steps`, `guidance_scale`, `height`
+/// and `width` (multiples of 8).  The last three also need the second text encoder,
+/// `clip2_model` and `tokenizer2` (`text_encoder_2` and `tokenizer_2` in the diffusers
+/// layout), whose pooled embeddings condition the UNet along with the image size, and with
+/// an empty `uncond_prompt` guide away from zero embeddings as diffusers does.  Other options:
+/// `uncond_prompt`, `num_samples`, `bsize`, `sliced_attention_size`, `use_f16`,
+/// `use_flash_attn` and `seed`, which seeds all the noise of the `i`-th image (0-based) with
+/// `seed + i` and defaults to a hash of the message id: the initial noise, the sampling of
+/// the img2img latents and the noise the schedulers add at each step.  `num_samples` batches
+/// of `bsize` images are generated.
+///
+/// `img2img` starts from an image instead of noise: a PNG, JPEG or WebP given as a base64
+/// data URL, raw bytes or a WeaveDrive reference, resized and cropped to `width` x `height`.
+/// `img2img_strength` (default 0.8) is how much of the diffusion is run over it, 1 discarding
+/// the image entirely.
+///
+/// `scheduler` is `ddim`, `euler_ancestral` or `ddpm`, by default the version's own (DDIM,
+/// Euler ancestral for turbo).  It is tuned with `eta` (DDIM only, 0 is deterministic),
+/// `prediction_type` (`epsilon`, `v_prediction` or `sample`), `timestep_spacing` (`leading`,
+/// `linspace` or `trailing`, not DDPM) and `beta_schedule` (`linear`, `scaled_linear` or
+/// `squaredcos_cap_v2`), the version's values being kept for those not given.  DDPM needs
+/// far more steps than the other two.
+///
+/// Returns `{ images, seed, n_steps, guidance_scale, width, height, sd_version, scheduler,
+/// elapsed }`, `images` listing all `num_samples * bsize` images in order as
+/// `{ image = "data:image/png;base64,...", index, sample, seed, steps }`.
+pub fn generate(lua: &Lua, table: LuaTable) -> LuaResult<LuaValue> {
+    let args = Args::from_table(&table)?;
+    let output = run(args)?;
+    lua.to_value(&output)
+}
+
+pub fn preload(lua: &Lua) -> LuaResult<()> {
+    let package: LuaTable = lua.globals().get("package")?;
+    let loaded: LuaTable = package.get("loaded")?;
+    let text_to_image_module_table = lua.create_table()?;
+    text_to_image_module_table.set("generate", lua.create_function(generate)?)?;
+    loaded.set("text_to_image", text_to_image_module_table)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_nn::{VarBuilder, VarMap};
+    use candle_transformers::models::stable_diffusion::{unet_2d, vae};
+    use crate::models::stable_diffusion_clip::{ClipConfig, ClipTextTransformer};
+    use crate::models::stable_diffusion_unet::{TextTimeConfig, UNet2DConditionModel};
+    use tokenizers::models::wordlevel::WordLevel;
+    use tokenizers::pre_tokenizers::whitespace::Whitespace;
+
+    /// Safetensors of the model `build` makes, candle's random initialization being replaced
+    /// with small weights drawn from a fixed seed.
+    fn tiny_weights(build: impl FnOnce(VarBuilder) -> candle_core::Result<()>) -> Vec<u8> {
+        let varmap = VarMap::new();
+        build(VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu)).unwrap();
+        let vars = varmap.data().lock().unwrap();
+        let mut names: Vec<&String> = vars.keys().collect();
+        names.sort();
+        let mut rng = StdRng::seed_from_u64(0);
+        for name in names.iter() {
+            let var = &vars[*name];
+            let values: Vec<f32> = randn_vec(var.elem_count(), &mut rng).into_iter().map(|x| x * 0.1).collect();
+            var.set(&Tensor::from_vec(values, var.dims(), &Device::Cpu).unwrap()).unwrap();
         }
+        safetensors::serialize(names.iter().map(|name| (name.as_str(), vars[*name].as_tensor())), &None).unwrap()
+    }
 
-        println!(
-            "Generating the final image for sample {}/{}.",
-            idx + 1,
-            args.num_samples
-        );
-        let image64 = generate_image(
-            &vae_model,
-            &latents,
-            vae_scale,
-            bsize,
-            idx,
-            args.num_samples,
-            None,
-        ).map_err(|e| {
-            println!("{}", e);
-            LuaError::RuntimeError(e.to_string())
-        })?;
+    fn tokenizer() -> Tokenizer {
+        let vocab = [("<|endoftext|>", 0), ("!", 1), ("a", 2), ("cat", 3), ("[UNK]", 4)]
+            .iter()
+            .map(|(token, id)| (token.to_string(), *id))
+            .collect();
+        let mut tokenizer = Tokenizer::new(WordLevel::builder().vocab(vocab).unk_token("[UNK]".to_string()).build().unwrap());
+        tokenizer.with_pre_tokenizer(Whitespace::default());
+        tokenizer
+    }
 
-        images_array.push(image64);
+    fn tiny_clip(config: &ClipConfig, projection_dim: usize) -> ClipConfig {
+        ClipConfig {
+            vocab_size: 5,
+            embed_dim: 16,
+            intermediate_size: 32,
+            max_position_embeddings: 8,
+            num_hidden_layers: 1,
+            num_attention_heads: 2,
+            projection_dim,
+            ..config.clone()
+        }
     }
 
-    // let images_table = lua.create_sequence_from(images_array.iter())?;
-    let images_table = mlua::Variadic::from_iter(images_array.iter().cloned());
-    
+    /// The options of `sd_version` for two 64x64 images in two steps, its text encoders, UNet
+    /// and VAE shrunk to a few channels.
+    fn tiny_args(sd_version: StableDiffusionVersion, img2img: Option<Vec<u8>>) -> Args {
+        let mut sd_config = sd_version.config(None, Some(64), Some(64));
+        sd_config.clip = tiny_clip(&sd_config.clip, 16);
+        // A pooled width of its own, to tell it from the hidden size.
+        sd_config.clip2 = sd_config.clip2.as_ref().map(|clip2| tiny_clip(clip2, 24));
+        if sd_config.text_time.is_some() {
+            sd_config.text_time = Some(TextTimeConfig { time_embed_dim: 8, input_dim: 24 + 6 * 8 });
+        }
+        sd_config.unet.blocks = vec![
+            unet_2d::BlockConfig { out_channels: 32, use_cross_attn: Some(1), attention_head_dim: 4 },
+            unet_2d::BlockConfig { out_channels: 64, use_cross_attn: Some(1), attention_head_dim: 4 },
+        ];
+        sd_config.unet.layers_per_block = 1;
+        sd_config.unet.cross_attention_dim = sd_config.clip.embed_dim
+            + sd_config.clip2.as_ref().map_or(0, |clip2| clip2.embed_dim);
+        sd_config.autoencoder.block_out_channels = vec![32; 4];
+        sd_config.autoencoder.layers_per_block = 1;
+
+        let clip_weights = tiny_weights(|vb| ClipTextTransformer::new(vb, &sd_config.clip).map(|_| ()));
+        let clip2_weights = sd_config.clip2.as_ref().map(|clip2| tiny_weights(|vb| {
+            ClipTextTransformer::new(vb.clone(), clip2)?;
+            candle_nn::linear_no_bias(clip2.embed_dim, clip2.projection_dim, vb.pp("text_projection")).map(|_| ())
+        }));
+        let unet_weights = tiny_weights(|vb| {
+            UNet2DConditionModel::new(vb, 4, 4, false, sd_config.unet.clone(), sd_config.text_time).map(|_| ())
+        });
+        let vae_weights = tiny_weights(|vb| {
+            vae::AutoEncoderKL::new(vb, 3, 3, sd_config.autoencoder.clone()).map(|_| ())
+        });
+        Args {
+            prompt: "a cat".to_string(),
+            uncond_prompt: String::new(),
+            clip_weights,
+            vae_weights,
+            unet_weights,
+            tokenizer: tokenizer(),
+            tokenizer2: clip2_weights.as_ref().map(|_| tokenizer()),
+            clip2_weights,
+            sliced_attention_size: None,
+            n_steps: 2,
+            num_samples: 1,
+            bsize: 2,
+            sd_version,
+            scheduler: sd_version.default_scheduler(),
+            use_flash_attn: false,
+            guidance_scale: sd_version.default_guidance_scale(),
+            img2img,
+            img2img_strength: 0.5,
+            seed: 42,
+            dtype: DType::F32,
+            device: Device::Cpu,
+            sd_config,
+        }
+    }
 
-    Ok(images_table)
-}
+    /// Each image's noise follows from its seed alone, draw after draw.
+    #[test]
+    fn test_noise() {
+        let draws = |seed, bsize| -> Vec<Vec<Vec<f32>>> {
+            let mut noise = Noise::new(seed, bsize);
+            // The initial noise, then the noise of a step.
+            (0..2)
+                .map(|_| {
+                    let draw = noise.randn((4, 2, 3), &Device::Cpu).unwrap();
+                    assert_eq!(draw.dims4().unwrap(), (bsize, 4, 2, 3));
+                    (0..bsize).map(|i| draw.i(i).unwrap().flatten_all().unwrap().to_vec1().unwrap()).collect()
+                })
+                .collect()
+        };
+        let batch = draws(7, 2);
+        assert_eq!(batch, draws(7, 2));
+        assert_eq!(batch[0][1], draws(8, 1)[0][0]);
+        assert_eq!(batch[1][1], draws(8, 1)[1][0]);
+        assert_ne!(batch[0][0], batch[0][1]);
+        assert_ne!(batch[0][0], batch[1][0]);
+    }
 
+    #[test]
+    fn test_tiny_pipelines() {
+        let versions = [
+            StableDiffusionVersion::V1_5,
+            StableDiffusionVersion::V2_1,
+            StableDiffusionVersion::Xl,
+            StableDiffusionVersion::Turbo,
+            StableDiffusionVersion::Ssd1b,
+        ];
+        for sd_version in versions {
+            assert_eq!(StableDiffusionVersion::from_str(sd_version.name()).unwrap(), sd_version);
+            let output = run(tiny_args(sd_version, None)).unwrap();
+            assert_eq!(output.sd_version, sd_version.name());
+            assert_eq!(output.images.len(), 2);
+            for (i, image) in output.images.iter().enumerate() {
+                assert!(image.image.starts_with("data:image/png;base64,"));
+                assert_eq!((image.index, image.sample, image.seed, image.steps), (i + 1, 1, 42 + i as u64, 2));
+            }
+            // Every draw is seeded, the ancestral steps of turbo included.
+            let again = run(tiny_args(sd_version, None)).unwrap();
+            for (image, again) in output.images.iter().zip(again.images.iter()) {
+                assert_eq!(image.image, again.image);
+            }
+        }
+    }
 
-// pub fn main(lua: &Lua, table_value: LuaValue) -> LuaResult<()> {
-pub fn main(lua: &Lua, table_value: LuaValue) -> LuaResult<mlua::Variadic<String>> {
-    let globals = lua.globals();
-    let _print: LuaFunction = globals.get("print")?;
-    let print = |s: &str| { _print.call::<_, ()>(String::from(s)); };
-    print("Starting stable diffusion");
-    let args = Args::from_lua(table_value, &lua)?;
-    // args.prompt = String::from("A very realistic photo of a rusty robot walking on a sandy beach");
-
-    let images = run(&lua, args)?;
-    println!("{:?}", images);
-    println!("----------------------------------------");
-    println!("----------------------------------------");
-    println!("----------------------------------------");
-    println!("----------------------------------------");
-    println!("----------------------------------------");
-    Ok(images)
+    #[test]
+    fn test_tiny_img2img() {
+        let pixels: Vec<u8> = (0..64 * 64 * 3).map(|i| (i % 251) as u8).collect();
+        let mut png = Cursor::new(Vec::new());
+        PngEncoder::new(&mut png).write_image(&pixels, 64, 64, ExtendedColorType::Rgb8).unwrap();
+        let png = png.into_inner();
+        for sd_version in [StableDiffusionVersion::V1_5, StableDiffusionVersion::Xl] {
+            let output = run(tiny_args(sd_version, Some(png.clone()))).unwrap();
+            // Half of the two steps are run over the image.
+            assert!(output.images.iter().all(|image| image.steps == 1));
+            let again = run(tiny_args(sd_version, Some(png.clone()))).unwrap();
+            assert_eq!(output.images[1].image, again.images[1].image);
+        }
+    }
 }
-    // format!("data:image/png;base64,{}", base64_string)
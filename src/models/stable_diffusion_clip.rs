@@ -0,0 +1,251 @@
+//! candle's CLIP text model, with a config whose sizes are public: candle's `clip::Config`
+//! keeps them private, and SDXL sizes its pooled embeddings by the `projection_dim` of the
+//! second text encoder.  The weights are laid out as in transformers' `CLIPTextModel`.
+use candle_core::{DType, Device, Result, Tensor, D};
+use candle_nn as nn;
+use candle_nn::Module;
+
+pub use candle_transformers::models::stable_diffusion::clip::Activation;
+
+
+#[derive(Debug, Clone)]
+pub struct ClipConfig {
+    pub vocab_size: usize,
+    /// `hidden_size` in transformers.
+    pub embed_dim: usize,
+    /// `hidden_act` in transformers.
+    pub activation: Activation,
+    pub intermediate_size: usize,
+    pub max_position_embeddings: usize,
+    /// The token to pad with, `<|endoftext|>` when not set.
+    pub pad_with: Option<String>,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    /// The width of `text_projection`, the pooled embeddings of `CLIPTextModelWithProjection`.
+    pub projection_dim: usize,
+}
+
+impl ClipConfig {
+    // The config details can be found in the "text_config" section of this json file:
+    // https://huggingface.co/openai/clip-vit-large-patch14/blob/main/config.json
+    pub fn v1_5() -> Self {
+        ClipConfig {
+            vocab_size: 49408,
+            embed_dim: 768,
+            activation: Activation::QuickGelu,
+            intermediate_size: 3072,
+            max_position_embeddings: 77,
+            pad_with: None,
+            num_hidden_layers: 12,
+            num_attention_heads: 12,
+            projection_dim: 768,
+        }
+    }
+
+    // https://huggingface.co/stabilityai/stable-diffusion-2-1/blob/main/text_encoder/config.json
+    pub fn v2_1() -> Self {
+        ClipConfig {
+            vocab_size: 49408,
+            embed_dim: 1024,
+            activation: Activation::Gelu,
+            intermediate_size: 4096,
+            max_position_embeddings: 77,
+            pad_with: Some("!".to_string()),
+            num_hidden_layers: 23,
+            num_attention_heads: 16,
+            projection_dim: 512,
+        }
+    }
+
+    // https://huggingface.co/stabilityai/stable-diffusion-xl-base-1.0/blob/main/text_encoder/config.json
+    pub fn sdxl() -> Self {
+        ClipConfig {
+            vocab_size: 49408,
+            embed_dim: 768,
+            activation: Activation::QuickGelu,
+            intermediate_size: 3072,
+            max_position_embeddings: 77,
+            pad_with: Some("!".to_string()),
+            num_hidden_layers: 12,
+            num_attention_heads: 12,
+            projection_dim: 768,
+        }
+    }
+
+    // https://huggingface.co/stabilityai/stable-diffusion-xl-base-1.0/blob/main/text_encoder_2/config.json
+    pub fn sdxl2() -> Self {
+        ClipConfig {
+            vocab_size: 49408,
+            embed_dim: 1280,
+            activation: Activation::Gelu,
+            intermediate_size: 5120,
+            max_position_embeddings: 77,
+            pad_with: Some("!".to_string()),
+            num_hidden_layers: 32,
+            num_attention_heads: 20,
+            projection_dim: 1280,
+        }
+    }
+
+    pub fn ssd1b() -> Self {
+        Self::sdxl()
+    }
+
+    pub fn ssd1b2() -> Self {
+        Self::sdxl2()
+    }
+}
+
+#[derive(Debug)]
+struct ClipTextEmbeddings {
+    token_embedding: nn::Embedding,
+    position_embedding: nn::Embedding,
+    position_ids: Tensor,
+}
+
+impl ClipTextEmbeddings {
+    fn new(vs: nn::VarBuilder, c: &ClipConfig) -> Result<Self> {
+        let token_embedding = nn::embedding(c.vocab_size, c.embed_dim, vs.pp("token_embedding"))?;
+        let position_embedding = nn::embedding(c.max_position_embeddings, c.embed_dim, vs.pp("position_embedding"))?;
+        let position_ids = Tensor::arange(0u32, c.max_position_embeddings as u32, vs.device())?.unsqueeze(0)?;
+        Ok(ClipTextEmbeddings { token_embedding, position_embedding, position_ids })
+    }
+}
+
+impl Module for ClipTextEmbeddings {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let token_embedding = self.token_embedding.forward(xs)?;
+        let position_embedding = self.position_embedding.forward(&self.position_ids)?;
+        token_embedding.broadcast_add(&position_embedding)
+    }
+}
+
+#[derive(Debug)]
+struct ClipAttention {
+    k_proj: nn::Linear,
+    v_proj: nn::Linear,
+    q_proj: nn::Linear,
+    out_proj: nn::Linear,
+    head_dim: usize,
+    scale: f64,
+    num_attention_heads: usize,
+}
+
+impl ClipAttention {
+    fn new(vs: nn::VarBuilder, c: &ClipConfig) -> Result<Self> {
+        let embed_dim = c.embed_dim;
+        let head_dim = embed_dim / c.num_attention_heads;
+        Ok(ClipAttention {
+            k_proj: nn::linear(embed_dim, embed_dim, vs.pp("k_proj"))?,
+            v_proj: nn::linear(embed_dim, embed_dim, vs.pp("v_proj"))?,
+            q_proj: nn::linear(embed_dim, embed_dim, vs.pp("q_proj"))?,
+            out_proj: nn::linear(embed_dim, embed_dim, vs.pp("out_proj"))?,
+            head_dim,
+            scale: (head_dim as f64).powf(-0.5),
+            num_attention_heads: c.num_attention_heads,
+        })
+    }
+
+    /// `(bsz * heads, seq_len, head_dim)` in f32, which the attention is computed in.
+    fn heads(&self, xs: &Tensor, seq_len: usize, bsz: usize) -> Result<Tensor> {
+        xs.reshape((bsz, seq_len, self.num_attention_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?
+            .reshape((bsz * self.num_attention_heads, seq_len, self.head_dim))?
+            .to_dtype(DType::F32)
+    }
+
+    fn forward(&self, xs: &Tensor, causal_attention_mask: &Tensor) -> Result<Tensor> {
+        let in_dtype = xs.dtype();
+        let (bsz, seq_len, embed_dim) = xs.dims3()?;
+        let query_states = self.heads(&(self.q_proj.forward(xs)? * self.scale)?, seq_len, bsz)?;
+        let key_states = self.heads(&self.k_proj.forward(xs)?, seq_len, bsz)?;
+        let value_states = self.heads(&self.v_proj.forward(xs)?, seq_len, bsz)?;
+        let attn_weights = query_states.matmul(&key_states.transpose(1, 2)?)?
+            .reshape((bsz, self.num_attention_heads, seq_len, seq_len))?
+            .broadcast_add(causal_attention_mask)?
+            .reshape((bsz * self.num_attention_heads, seq_len, seq_len))?;
+        let attn_weights = nn::ops::softmax(&attn_weights, D::Minus1)?;
+        let attn_output = attn_weights.matmul(&value_states)?
+            .to_dtype(in_dtype)?
+            .reshape((bsz, self.num_attention_heads, seq_len, self.head_dim))?
+            .transpose(1, 2)?
+            .reshape((bsz, seq_len, embed_dim))?;
+        self.out_proj.forward(&attn_output)
+    }
+}
+
+#[derive(Debug)]
+struct ClipEncoderLayer {
+    self_attn: ClipAttention,
+    layer_norm1: nn::LayerNorm,
+    fc1: nn::Linear,
+    fc2: nn::Linear,
+    activation: Activation,
+    layer_norm2: nn::LayerNorm,
+}
+
+impl ClipEncoderLayer {
+    fn new(vs: nn::VarBuilder, c: &ClipConfig) -> Result<Self> {
+        Ok(ClipEncoderLayer {
+            self_attn: ClipAttention::new(vs.pp("self_attn"), c)?,
+            layer_norm1: nn::layer_norm(c.embed_dim, 1e-5, vs.pp("layer_norm1"))?,
+            fc1: nn::linear(c.embed_dim, c.intermediate_size, vs.pp("mlp").pp("fc1"))?,
+            fc2: nn::linear(c.intermediate_size, c.embed_dim, vs.pp("mlp").pp("fc2"))?,
+            activation: c.activation,
+            layer_norm2: nn::layer_norm(c.embed_dim, 1e-5, vs.pp("layer_norm2"))?,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, causal_attention_mask: &Tensor) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self.layer_norm1.forward(xs)?;
+        let xs = (self.self_attn.forward(&xs, causal_attention_mask)? + residual)?;
+        let residual = &xs;
+        let hidden = self.fc1.forward(&self.layer_norm2.forward(&xs)?)?;
+        self.fc2.forward(&self.activation.forward(&hidden)?)? + residual
+    }
+}
+
+/// The text model of CLIP, up to its final layer norm.
+#[derive(Debug)]
+pub struct ClipTextTransformer {
+    embeddings: ClipTextEmbeddings,
+    layers: Vec<ClipEncoderLayer>,
+    final_layer_norm: nn::LayerNorm,
+}
+
+impl ClipTextTransformer {
+    pub fn new(vs: nn::VarBuilder, c: &ClipConfig) -> Result<Self> {
+        let vs = vs.pp("text_model");
+        let vs_layers = vs.pp("encoder").pp("layers");
+        let layers = (0..c.num_hidden_layers)
+            .map(|i| ClipEncoderLayer::new(vs_layers.pp(i.to_string()), c))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(ClipTextTransformer {
+            embeddings: ClipTextEmbeddings::new(vs.pp("embeddings"), c)?,
+            layers,
+            final_layer_norm: nn::layer_norm(c.embed_dim, 1e-5, vs.pp("final_layer_norm"))?,
+        })
+    }
+
+    fn causal_attention_mask(bsz: usize, seq_len: usize, device: &Device) -> Result<Tensor> {
+        let mask: Vec<f32> = (0..seq_len)
+            .flat_map(|i| (0..seq_len).map(move |j| if j > i { f32::MIN } else { 0. }))
+            .collect();
+        Tensor::from_slice(&mask, (seq_len, seq_len), device)?.broadcast_as((bsz, 1, seq_len, seq_len))
+    }
+}
+
+impl Module for ClipTextTransformer {
+    /// The hidden states of the `(batch, max_position_embeddings)` token ids.
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (bsz, seq_len) = xs.dims2()?;
+        let mut xs = self.embeddings.forward(xs)?;
+        let causal_attention_mask = Self::causal_attention_mask(bsz, seq_len, xs.device())?;
+        for layer in self.layers.iter() {
+            xs = layer.forward(&xs, &causal_attention_mask)?;
+        }
+        self.final_layer_norm.forward(&xs)
+    }
+}
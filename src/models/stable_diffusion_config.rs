@@ -1,6 +1,4 @@
 #![allow(dead_code)]
-use std::sync::Arc;
-
 use candle_core::{DType, Device, Result};
 use candle_nn as nn;
 
@@ -8,22 +6,34 @@ use candle_transformers::models::stable_diffusion::{
     ddim,
     euler_ancestral_discrete,
     schedulers,
-    clip,
     unet_2d,
     vae,
 };
-use schedulers::{Scheduler, SchedulerConfig};
 
+use crate::models::stable_diffusion_clip::{ClipConfig, ClipTextTransformer};
+use crate::models::stable_diffusion_schedulers::{SchedulerConfig, SeededScheduler};
+use crate::models::stable_diffusion_unet::{TextTimeConfig, UNet2DConditionModel};
+use crate::models::stable_diffusion_vae::VaeEncoder;
+
+
+// https://huggingface.co/stabilityai/stable-diffusion-xl-base-1.0/blob/main/unet/config.json
+// "addition_time_embed_dim": 256, "projection_class_embeddings_input_dim": 2816
+const SDXL_TEXT_TIME: TextTimeConfig = TextTimeConfig {
+    time_embed_dim: 256,
+    input_dim: 2816,
+};
 
 #[derive(Clone, Debug)]
 pub struct StableDiffusionConfig {
     pub width: usize,
     pub height: usize,
-    pub clip: clip::Config,
-    pub clip2: Option<clip::Config>,
+    pub clip: ClipConfig,
+    pub clip2: Option<ClipConfig>,
     pub autoencoder: vae::AutoEncoderKLConfig,
     pub unet: unet_2d::UNet2DConditionModelConfig,
-    pub scheduler: Arc<dyn SchedulerConfig>,
+    /// The added conditioning of the SDXL UNets.
+    pub text_time: Option<TextTimeConfig>,
+    pub scheduler: SchedulerConfig,
 }
 
 impl StableDiffusionConfig {
@@ -77,7 +87,7 @@ impl StableDiffusionConfig {
             512
         };
 
-        let scheduler = Arc::new(ddim::DDIMSchedulerConfig {
+        let scheduler = SchedulerConfig::Ddim(ddim::DDIMSchedulerConfig {
             prediction_type: schedulers::PredictionType::Epsilon,
             ..Default::default()
         });
@@ -85,11 +95,12 @@ impl StableDiffusionConfig {
         StableDiffusionConfig {
             width,
             height,
-            clip: clip::Config::v1_5(),
+            clip: ClipConfig::v1_5(),
             clip2: None,
             autoencoder,
             scheduler,
             unet,
+            text_time: None,
         }
     }
 
@@ -131,7 +142,7 @@ impl StableDiffusionConfig {
             latent_channels: 4,
             norm_num_groups: 32,
         };
-        let scheduler = Arc::new(ddim::DDIMSchedulerConfig {
+        let scheduler = SchedulerConfig::Ddim(ddim::DDIMSchedulerConfig {
             prediction_type,
             ..Default::default()
         });
@@ -153,11 +164,12 @@ impl StableDiffusionConfig {
         StableDiffusionConfig {
             width,
             height,
-            clip: clip::Config::v2_1(),
+            clip: ClipConfig::v2_1(),
             clip2: None,
             autoencoder,
             scheduler,
             unet,
+            text_time: None,
         }
     }
 
@@ -212,7 +224,7 @@ impl StableDiffusionConfig {
             latent_channels: 4,
             norm_num_groups: 32,
         };
-        let scheduler = Arc::new(ddim::DDIMSchedulerConfig {
+        let scheduler = SchedulerConfig::Ddim(ddim::DDIMSchedulerConfig {
             prediction_type,
             ..Default::default()
         });
@@ -234,11 +246,12 @@ impl StableDiffusionConfig {
         StableDiffusionConfig {
             width,
             height,
-            clip: clip::Config::sdxl(),
-            clip2: Some(clip::Config::sdxl2()),
+            clip: ClipConfig::sdxl(),
+            clip2: Some(ClipConfig::sdxl2()),
             autoencoder,
             scheduler,
             unet,
+            text_time: Some(SDXL_TEXT_TIME),
         }
     }
 
@@ -279,7 +292,7 @@ impl StableDiffusionConfig {
             latent_channels: 4,
             norm_num_groups: 32,
         };
-        let scheduler = Arc::new(
+        let scheduler = SchedulerConfig::EulerAncestral(
             euler_ancestral_discrete::EulerAncestralDiscreteSchedulerConfig {
                 prediction_type,
                 timestep_spacing: schedulers::TimestepSpacing::Trailing,
@@ -304,11 +317,12 @@ impl StableDiffusionConfig {
         Self {
             width,
             height,
-            clip: clip::Config::sdxl(),
-            clip2: Some(clip::Config::sdxl2()),
+            clip: ClipConfig::sdxl(),
+            clip2: Some(ClipConfig::sdxl2()),
             autoencoder,
             scheduler,
             unet,
+            text_time: Some(SDXL_TEXT_TIME),
         }
     }
 
@@ -376,7 +390,7 @@ impl StableDiffusionConfig {
             latent_channels: 4,
             norm_num_groups: 32,
         };
-        let scheduler = Arc::new(ddim::DDIMSchedulerConfig {
+        let scheduler = SchedulerConfig::Ddim(ddim::DDIMSchedulerConfig {
             ..Default::default()
         });
 
@@ -397,24 +411,32 @@ impl StableDiffusionConfig {
         Self {
             width,
             height,
-            clip: clip::Config::ssd1b(),
-            clip2: Some(clip::Config::ssd1b2()),
+            clip: ClipConfig::ssd1b(),
+            clip2: Some(ClipConfig::ssd1b2()),
             autoencoder,
             scheduler,
             unet,
+            text_time: Some(SDXL_TEXT_TIME),
         }
     }
 
+    /// The VAE and, `with_encoder`, the encoder that exposes its latent distribution.
     pub fn build_vae( //<P: AsRef<Vec<u8>>>
         &self,
         vae_weights: Vec<u8>,
         device: &Device,
         dtype: DType,
-    ) -> Result<vae::AutoEncoderKL> {
+        with_encoder: bool,
+    ) -> Result<(vae::AutoEncoderKL, Option<VaeEncoder>)> {
         let vs_ae = nn::VarBuilder::from_buffered_safetensors(vae_weights, dtype, &device)?;
+        let encoder = if with_encoder {
+            Some(VaeEncoder::new(vs_ae.clone(), 3, &self.autoencoder)?)
+        } else {
+            None
+        };
         // https://huggingface.co/runwayml/stable-diffusion-v1-5/blob/main/vae/config.json
         let autoencoder = vae::AutoEncoderKL::new(vs_ae, 3, 3, self.autoencoder.clone())?;
-        Ok(autoencoder)
+        Ok((autoencoder, encoder))
     }
 
     pub fn build_unet( // <P: AsRef<Vec<u8>>>
@@ -424,19 +446,20 @@ impl StableDiffusionConfig {
         in_channels: usize,
         use_flash_attn: bool,
         dtype: DType,
-    ) -> Result<unet_2d::UNet2DConditionModel> {
+    ) -> Result<UNet2DConditionModel> {
         let vs_unet = nn::VarBuilder::from_buffered_safetensors(unet_weights, dtype, &device)?;
-        let unet = unet_2d::UNet2DConditionModel::new(
+        let unet = UNet2DConditionModel::new(
             vs_unet,
             in_channels,
             4,
             use_flash_attn,
             self.unet.clone(),
+            self.text_time,
         )?;
         Ok(unet)
     }
 
-    pub fn build_scheduler(&self, n_steps: usize) -> Result<Box<dyn Scheduler>> {
+    pub fn build_scheduler(&self, n_steps: usize) -> Result<SeededScheduler> {
         self.scheduler.build(n_steps)
     }
 
@@ -446,16 +469,53 @@ impl StableDiffusionConfig {
         device: &Device,
         dtype: DType,
         first: bool,
-    ) -> Result<clip::ClipTextTransformer> {
+    ) -> Result<ClipTextTransformer> {
         let clip_config = if first {
             &self.clip
         } else {
             self.clip2.as_ref().unwrap()
         };
         let vs = nn::VarBuilder::from_buffered_safetensors(clip_weights, dtype, &device)?;
-        let text_model = clip::ClipTextTransformer::new(vs, clip_config)?;
+        let text_model = ClipTextTransformer::new(vs, clip_config)?;
         Ok(text_model)
     }
+
+    /// The second text encoder of SDXL with its `text_projection`, which turns the hidden
+    /// state of the end-of-text token into the pooled embeddings the UNet is conditioned on.
+    pub fn build_clip2_with_projection(
+        &self,
+        clip_weights: Vec<u8>,
+        device: &Device,
+        dtype: DType,
+    ) -> Result<(ClipTextTransformer, nn::Linear)> {
+        let clip_config = match &self.clip2 {
+            Some(clip_config) => clip_config,
+            None => candle_core::bail!("this version has no second text encoder"),
+        };
+        let vs = nn::VarBuilder::from_buffered_safetensors(clip_weights, dtype, &device)?;
+        let text_model = ClipTextTransformer::new(vs.clone(), clip_config)?;
+        let projection = nn::linear_no_bias(clip_config.embed_dim, clip_config.projection_dim, vs.pp("text_projection"))?;
+        Ok((text_model, projection))
+    }
+
+    /// Checks that the pooled embeddings of the second text encoder and the time ids make the
+    /// input of the UNet's added embedding.
+    pub fn check_text_time(&self) -> Result<()> {
+        let (clip2, text_time) = match (&self.clip2, &self.text_time) {
+            (_, None) => return Ok(()),
+            (Some(clip2), Some(text_time)) => (clip2, text_time),
+            (None, Some(_)) => candle_core::bail!("the UNet needs the pooled embeddings of a second text encoder"),
+        };
+        if text_time.pooled_dim() != Some(clip2.projection_dim) {
+            candle_core::bail!(
+                "the second text encoder's projection_dim ({}) plus 6 time ids of {} don't make the {} inputs of the UNet's add_embedding",
+                clip2.projection_dim,
+                text_time.time_embed_dim,
+                text_time.input_dim,
+            );
+        }
+        Ok(())
+    }
 }
 
 // pub fn build_clip_transformer( // <P: AsRef<Vec<u8>>>
@@ -468,3 +528,27 @@ impl StableDiffusionConfig {
 //     let text_model = clip::ClipTextTransformer::new(vs, clip)?;
 //     Ok(text_model)
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_text_time() {
+        for config in [
+            StableDiffusionConfig::v1_5(None, None, None),
+            StableDiffusionConfig::sdxl(None, None, None),
+            StableDiffusionConfig::sdxl_turbo(None, None, None),
+            StableDiffusionConfig::ssd1b(None, None, None),
+        ] {
+            config.check_text_time().unwrap();
+        }
+
+        let mut config = StableDiffusionConfig::sdxl(None, None, None);
+        config.clip2.as_mut().unwrap().projection_dim = 1024;
+        let err = config.check_text_time().unwrap_err().to_string();
+        assert!(err.contains("projection_dim (1024)") && err.contains("2816"), "{}", err);
+        config.clip2 = None;
+        assert!(config.check_text_time().is_err());
+    }
+}
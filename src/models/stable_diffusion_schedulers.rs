@@ -0,0 +1,288 @@
+//! candle's denoising schedulers, with the noise their steps add drawn by the caller.  candle's
+//! Euler ancestral, DDPM and `eta > 0` DDIM steps add `Tensor::randn` noise, which can't be
+//! seeded on the CPU, so those steps are redone here after candle's and take the noise as an
+//! argument.  The timesteps, the input scaling and `add_noise` are candle's, and so are the
+//! noise schedules, read through `add_noise` since candle keeps them private.
+use candle_core::{bail, DType, Device, Result, Tensor};
+
+use candle_transformers::models::stable_diffusion::{ddim, ddpm, euler_ancestral_discrete};
+use candle_transformers::models::stable_diffusion::ddpm::DDPMVarianceType;
+use candle_transformers::models::stable_diffusion::schedulers::{PredictionType, Scheduler, SchedulerConfig as _};
+
+
+/// The scheduler and its parameters.
+#[derive(Clone, Debug)]
+pub enum SchedulerConfig {
+    Ddim(ddim::DDIMSchedulerConfig),
+    EulerAncestral(euler_ancestral_discrete::EulerAncestralDiscreteSchedulerConfig),
+    Ddpm(ddpm::DDPMSchedulerConfig),
+}
+
+impl SchedulerConfig {
+    pub fn build(&self, inference_steps: usize) -> Result<SeededScheduler> {
+        let (scheduler, step_ratio): (Box<dyn Scheduler>, usize) = match self {
+            SchedulerConfig::Ddim(config) => {
+                (config.build(inference_steps)?, config.train_timesteps / inference_steps)
+            }
+            SchedulerConfig::EulerAncestral(config) => (config.build(inference_steps)?, 0),
+            SchedulerConfig::Ddpm(config) => (
+                Box::new(DDPM(ddpm::DDPMScheduler::new(inference_steps, config.clone())?)),
+                config.train_timesteps / inference_steps.min(config.train_timesteps),
+            ),
+        };
+        Ok(SeededScheduler { scheduler, config: self.clone(), step_ratio })
+    }
+}
+
+/// `ddpm::DDPMScheduler` doesn't implement `Scheduler`, this does.
+struct DDPM(ddpm::DDPMScheduler);
+
+impl Scheduler for DDPM {
+    fn timesteps(&self) -> &[usize] {
+        self.0.timesteps()
+    }
+
+    fn add_noise(&self, original: &Tensor, noise: Tensor, timestep: usize) -> Result<Tensor> {
+        self.0.add_noise(original, noise, timestep)
+    }
+
+    fn init_noise_sigma(&self) -> f64 {
+        self.0.init_noise_sigma()
+    }
+
+    fn scale_model_input(&self, sample: Tensor, timestep: usize) -> Result<Tensor> {
+        Ok(self.0.scale_model_input(sample, timestep))
+    }
+
+    fn step(&self, model_output: &Tensor, timestep: usize, sample: &Tensor) -> Result<Tensor> {
+        self.0.step(model_output, timestep, sample)
+    }
+}
+
+pub struct SeededScheduler {
+    scheduler: Box<dyn Scheduler>,
+    config: SchedulerConfig,
+    /// Training timesteps between two inference steps of DDIM and DDPM.
+    step_ratio: usize,
+}
+
+impl SeededScheduler {
+    pub fn timesteps(&self) -> &[usize] {
+        self.scheduler.timesteps()
+    }
+
+    pub fn add_noise(&self, original: &Tensor, noise: Tensor, timestep: usize) -> Result<Tensor> {
+        self.scheduler.add_noise(original, noise, timestep)
+    }
+
+    pub fn init_noise_sigma(&self) -> f64 {
+        self.scheduler.init_noise_sigma()
+    }
+
+    pub fn scale_model_input(&self, sample: Tensor, timestep: usize) -> Result<Tensor> {
+        self.scheduler.scale_model_input(sample, timestep)
+    }
+
+    /// Whether `step` needs noise.
+    pub fn adds_noise(&self) -> bool {
+        !matches!(&self.config, SchedulerConfig::Ddim(config) if config.eta <= 0.)
+    }
+
+    /// Denoises `sample` by one step, `noise` being standard normal noise shaped like it when
+    /// `adds_noise`.
+    pub fn step(&self, model_output: &Tensor, timestep: usize, sample: &Tensor, noise: Option<&Tensor>) -> Result<Tensor> {
+        let noise = match (self.adds_noise(), noise) {
+            (false, _) => return self.scheduler.step(model_output, timestep, sample),
+            (true, Some(noise)) => noise,
+            (true, None) => bail!("this scheduler's steps need noise"),
+        };
+        match &self.config {
+            SchedulerConfig::Ddim(config) => self.ddim_step(config, model_output, timestep, sample, noise),
+            SchedulerConfig::EulerAncestral(config) => {
+                self.euler_ancestral_step(config, model_output, timestep, sample, noise)
+            }
+            SchedulerConfig::Ddpm(config) => self.ddpm_step(config, model_output, timestep, sample, noise),
+        }
+    }
+
+    /// `alphas_cumprod[timestep]` of DDIM and DDPM: `add_noise` scales the original by its
+    /// square root.
+    fn alpha_prod(&self, timestep: usize) -> Result<f64> {
+        let one = Tensor::ones(1, DType::F64, &Device::Cpu)?;
+        let scaled = self.scheduler.add_noise(&one, one.zeros_like()?, timestep)?;
+        Ok(scaled.to_vec1::<f64>()?[0].powi(2))
+    }
+
+    /// The sigma of the Euler scheduler at `timestep`, by which `add_noise` scales the noise.
+    fn sigma(&self, timestep: usize) -> Result<f64> {
+        let one = Tensor::ones(1, DType::F64, &Device::Cpu)?;
+        let scaled = self.scheduler.add_noise(&one.zeros_like()?, one, timestep)?;
+        Ok(scaled.to_vec1::<f64>()?[0])
+    }
+
+    fn ddim_step(
+        &self,
+        config: &ddim::DDIMSchedulerConfig,
+        model_output: &Tensor,
+        timestep: usize,
+        sample: &Tensor,
+        noise: &Tensor,
+    ) -> Result<Tensor> {
+        let timestep = timestep.min(config.train_timesteps - 1);
+        let alpha_prod_t = self.alpha_prod(timestep)?;
+        let alpha_prod_t_prev = self.alpha_prod(timestep.saturating_sub(self.step_ratio))?;
+        let beta_prod_t = 1. - alpha_prod_t;
+        let beta_prod_t_prev = 1. - alpha_prod_t_prev;
+
+        let (pred_original_sample, pred_epsilon) = match config.prediction_type {
+            PredictionType::Epsilon => {
+                let pred_original_sample = ((sample - (model_output * beta_prod_t.sqrt())?)?
+                    * (1. / alpha_prod_t.sqrt()))?;
+                (pred_original_sample, model_output.clone())
+            }
+            PredictionType::VPrediction => {
+                let pred_original_sample = ((sample * alpha_prod_t.sqrt())? - (model_output * beta_prod_t.sqrt())?)?;
+                let pred_epsilon = ((model_output * alpha_prod_t.sqrt())? + (sample * beta_prod_t.sqrt())?)?;
+                (pred_original_sample, pred_epsilon)
+            }
+            PredictionType::Sample => {
+                let pred_original_sample = model_output.clone();
+                let pred_epsilon = ((sample - (&pred_original_sample * alpha_prod_t.sqrt())?)?
+                    * (1. / beta_prod_t.sqrt()))?;
+                (pred_original_sample, pred_epsilon)
+            }
+        };
+
+        let variance = (beta_prod_t_prev / beta_prod_t) * (1. - alpha_prod_t / alpha_prod_t_prev);
+        let std_dev_t = config.eta * variance.sqrt();
+        let pred_sample_direction = (pred_epsilon * (1. - alpha_prod_t_prev - std_dev_t * std_dev_t).sqrt())?;
+        let prev_sample = ((pred_original_sample * alpha_prod_t_prev.sqrt())? + pred_sample_direction)?;
+        prev_sample + (noise * std_dev_t)?
+    }
+
+    fn euler_ancestral_step(
+        &self,
+        config: &euler_ancestral_discrete::EulerAncestralDiscreteSchedulerConfig,
+        model_output: &Tensor,
+        timestep: usize,
+        sample: &Tensor,
+        noise: &Tensor,
+    ) -> Result<Tensor> {
+        let timesteps = self.scheduler.timesteps();
+        let step_index = match timesteps.iter().position(|&t| t == timestep) {
+            Some(step_index) => step_index,
+            None => bail!("timestep out of this scheduler's bounds: {timestep}"),
+        };
+        let sigma_from = self.sigma(timestep)?;
+        // The last step goes down to sigma 0.
+        let sigma_to = match timesteps.get(step_index + 1) {
+            Some(&next) => self.sigma(next)?,
+            None => 0.,
+        };
+
+        let pred_original_sample = match config.prediction_type {
+            PredictionType::Epsilon => (sample - (model_output * sigma_from)?)?,
+            PredictionType::VPrediction => {
+                ((model_output * (-sigma_from / (sigma_from.powi(2) + 1.).sqrt()))?
+                    + (sample / (sigma_from.powi(2) + 1.))?)?
+            }
+            PredictionType::Sample => bail!("prediction_type not implemented yet: sample"),
+        };
+
+        let sigma_up = (sigma_to.powi(2) * (sigma_from.powi(2) - sigma_to.powi(2)) / sigma_from.powi(2)).sqrt();
+        let sigma_down = (sigma_to.powi(2) - sigma_up.powi(2)).sqrt();
+        let derivative = ((sample - pred_original_sample)? / sigma_from)?;
+        let prev_sample = (sample + (derivative * (sigma_down - sigma_from))?)?;
+        prev_sample + (noise * sigma_up)?
+    }
+
+    fn ddpm_step(
+        &self,
+        config: &ddpm::DDPMSchedulerConfig,
+        model_output: &Tensor,
+        timestep: usize,
+        sample: &Tensor,
+        noise: &Tensor,
+    ) -> Result<Tensor> {
+        let alpha_prod_t = self.alpha_prod(timestep)?;
+        let alpha_prod_t_prev = match timestep.checked_sub(self.step_ratio) {
+            Some(prev_timestep) => self.alpha_prod(prev_timestep)?,
+            None => 1.,
+        };
+        let beta_prod_t = 1. - alpha_prod_t;
+        let beta_prod_t_prev = 1. - alpha_prod_t_prev;
+        let current_alpha_t = alpha_prod_t / alpha_prod_t_prev;
+        let current_beta_t = 1. - current_alpha_t;
+
+        let pred_original_sample = match config.prediction_type {
+            PredictionType::Epsilon => ((sample - (model_output * beta_prod_t.sqrt())?)? / alpha_prod_t.sqrt())?,
+            PredictionType::Sample => model_output.clone(),
+            PredictionType::VPrediction => ((sample * alpha_prod_t.sqrt())? - (model_output * beta_prod_t.sqrt())?)?,
+        };
+        let pred_original_sample = if config.clip_sample {
+            pred_original_sample.clamp(-1f32, 1f32)?
+        } else {
+            pred_original_sample
+        };
+
+        let pred_original_sample_coeff = (alpha_prod_t_prev.sqrt() * current_beta_t) / beta_prod_t;
+        let current_sample_coeff = current_alpha_t.sqrt() * beta_prod_t_prev / beta_prod_t;
+        let pred_prev_sample = ((&pred_original_sample * pred_original_sample_coeff)? + (sample * current_sample_coeff)?)?;
+        if timestep == 0 {
+            return Ok(pred_prev_sample);
+        }
+
+        let variance = (1. - alpha_prod_t_prev) / (1. - alpha_prod_t) * current_beta_t;
+        // The standard deviation of the added noise, as candle computes it.
+        let noise_scale = match config.variance_type {
+            DDPMVarianceType::FixedSmall => variance.max(1e-20).sqrt(),
+            DDPMVarianceType::FixedSmallLog => (variance.max(1e-20).ln() * 0.5).exp(),
+            DDPMVarianceType::FixedLarge => current_beta_t.sqrt(),
+            DDPMVarianceType::FixedLargeLog => current_beta_t.ln().sqrt(),
+            DDPMVarianceType::Learned => variance.sqrt(),
+        };
+        pred_prev_sample + (noise * noise_scale)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Where candle's steps add no noise, the seeded ones must give the same result.
+    #[test]
+    fn test_steps_match_candle() {
+        let device = Device::Cpu;
+        let sample = Tensor::arange(0f32, 8., &device).unwrap().reshape((1, 2, 2, 2)).unwrap();
+        let model_output = (sample.cos().unwrap() * 0.5).unwrap();
+        let noise = sample.zeros_like().unwrap();
+        let assert_close = |a: &Tensor, b: &Tensor| {
+            let diff = (a - b).unwrap().abs().unwrap().max_all().unwrap().to_scalar::<f32>().unwrap();
+            assert!(diff < 1e-4, "{}", diff);
+        };
+
+        // DDIM with `eta = 0` redone with zero noise.
+        let config = ddim::DDIMSchedulerConfig::default();
+        let scheduler = SchedulerConfig::Ddim(config).build(10).unwrap();
+        assert!(!scheduler.adds_noise());
+        for &timestep in scheduler.timesteps() {
+            let seeded = scheduler.ddim_step(&config, &model_output, timestep, &sample, &noise).unwrap();
+            let candle = scheduler.scheduler.step(&model_output, timestep, &sample).unwrap();
+            assert_close(&seeded, &candle);
+        }
+
+        // The last Euler ancestral step and DDPM step add no noise.
+        for config in [
+            SchedulerConfig::EulerAncestral(Default::default()),
+            SchedulerConfig::Ddpm(Default::default()),
+        ] {
+            let scheduler = config.build(10).unwrap();
+            assert!(scheduler.adds_noise());
+            assert!(scheduler.step(&model_output, scheduler.timesteps()[0], &sample, None).is_err());
+            let last = *scheduler.timesteps().last().unwrap();
+            let seeded = scheduler.step(&model_output, last, &sample, Some(&noise)).unwrap();
+            let candle = scheduler.scheduler.step(&model_output, last, &sample).unwrap();
+            assert_close(&seeded, &candle);
+        }
+    }
+}
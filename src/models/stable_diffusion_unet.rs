@@ -0,0 +1,310 @@
+//! candle's `unet_2d::UNet2DConditionModel`, with the additional embedding SDXL conditions
+//! on.  candle builds the blocks, this only assembles them, so the weights are laid out as
+//! in diffusers and `add_embedding.*` is read when the config has a `TextTimeConfig`.
+use candle_core::{Result, Tensor};
+use candle_nn as nn;
+use candle_nn::Module;
+
+use candle_transformers::models::stable_diffusion::embeddings::{TimestepEmbedding, Timesteps};
+use candle_transformers::models::stable_diffusion::unet_2d::{BlockConfig, UNet2DConditionModelConfig};
+use candle_transformers::models::stable_diffusion::unet_2d_blocks::*;
+
+
+/// SDXL's `addition_embed_type = "text_time"`: the pooled embeddings of the second text
+/// encoder and the six size and crop ids, each projected like a timestep, go through
+/// `add_embedding` and are added to the timestep embedding.
+#[derive(Debug, Clone, Copy)]
+pub struct TextTimeConfig {
+    /// `addition_time_embed_dim`, the sinusoidal width of each time id.
+    pub time_embed_dim: usize,
+    /// `projection_class_embeddings_input_dim`, the width of the pooled text embeddings and
+    /// the projected time ids together.
+    pub input_dim: usize,
+}
+
+impl TextTimeConfig {
+    /// The width of the pooled text embeddings, `None` when the time ids alone are too wide.
+    pub fn pooled_dim(&self) -> Option<usize> {
+        self.input_dim.checked_sub(6 * self.time_embed_dim)
+    }
+}
+
+/// The inputs of the `text_time` embedding, one row per row of the model input.
+#[derive(Debug)]
+pub struct TextTimeConditioning {
+    /// `(batch, pooled_dim)`.
+    pub text_embeds: Tensor,
+    /// `(batch, 6)`: original height and width, crop top and left, target height and width.
+    pub time_ids: Tensor,
+}
+
+#[derive(Debug)]
+struct TextTimeEmbedding {
+    config: TextTimeConfig,
+    time_proj: Timesteps,
+    embedding: TimestepEmbedding,
+}
+
+#[derive(Debug)]
+enum UNetDownBlock {
+    Basic(DownBlock2D),
+    CrossAttn(CrossAttnDownBlock2D),
+}
+
+#[derive(Debug)]
+enum UNetUpBlock {
+    Basic(UpBlock2D),
+    CrossAttn(CrossAttnUpBlock2D),
+}
+
+#[derive(Debug)]
+pub struct UNet2DConditionModel {
+    conv_in: nn::Conv2d,
+    time_proj: Timesteps,
+    time_embedding: TimestepEmbedding,
+    add_embedding: Option<TextTimeEmbedding>,
+    down_blocks: Vec<UNetDownBlock>,
+    mid_block: UNetMidBlock2DCrossAttn,
+    up_blocks: Vec<UNetUpBlock>,
+    conv_norm_out: nn::GroupNorm,
+    conv_out: nn::Conv2d,
+    config: UNet2DConditionModelConfig,
+}
+
+impl UNet2DConditionModel {
+    pub fn new(
+        vs: nn::VarBuilder,
+        in_channels: usize,
+        out_channels: usize,
+        use_flash_attn: bool,
+        config: UNet2DConditionModelConfig,
+        text_time: Option<TextTimeConfig>,
+    ) -> Result<Self> {
+        let n_blocks = config.blocks.len();
+        let b_channels = config.blocks[0].out_channels;
+        let bl_channels = config.blocks[n_blocks - 1].out_channels;
+        let bl_attention_head_dim = config.blocks[n_blocks - 1].attention_head_dim;
+        let time_embed_dim = b_channels * 4;
+        let conv_cfg = nn::Conv2dConfig {
+            padding: 1,
+            ..Default::default()
+        };
+        let conv_in = nn::conv2d(in_channels, b_channels, 3, conv_cfg, vs.pp("conv_in"))?;
+
+        let time_proj = Timesteps::new(b_channels, config.flip_sin_to_cos, config.freq_shift);
+        let time_embedding = TimestepEmbedding::new(vs.pp("time_embedding"), b_channels, time_embed_dim)?;
+        let add_embedding = match text_time {
+            Some(text_time) => Some(TextTimeEmbedding {
+                config: text_time,
+                time_proj: Timesteps::new(text_time.time_embed_dim, config.flip_sin_to_cos, config.freq_shift),
+                embedding: TimestepEmbedding::new(vs.pp("add_embedding"), text_time.input_dim, time_embed_dim)?,
+            }),
+            None => None,
+        };
+
+        // Enable automatic attention slicing if the config sliced_attention_size is set to 0.
+        let sliced_attention_size = |attention_head_dim: usize| match config.sliced_attention_size {
+            Some(0) => Some(attention_head_dim / 2),
+            size => size,
+        };
+
+        let vs_db = vs.pp("down_blocks");
+        let down_blocks = (0..n_blocks)
+            .map(|i| {
+                let BlockConfig { out_channels, use_cross_attn, attention_head_dim } = config.blocks[i];
+                let in_channels = if i > 0 { config.blocks[i - 1].out_channels } else { b_channels };
+                let db_cfg = DownBlock2DConfig {
+                    num_layers: config.layers_per_block,
+                    resnet_eps: config.norm_eps,
+                    resnet_groups: config.norm_num_groups,
+                    add_downsample: i < n_blocks - 1,
+                    downsample_padding: config.downsample_padding,
+                    ..Default::default()
+                };
+                match use_cross_attn {
+                    Some(transformer_layers_per_block) => {
+                        let cfg = CrossAttnDownBlock2DConfig {
+                            downblock: db_cfg,
+                            attn_num_head_channels: attention_head_dim,
+                            cross_attention_dim: config.cross_attention_dim,
+                            sliced_attention_size: sliced_attention_size(attention_head_dim),
+                            use_linear_projection: config.use_linear_projection,
+                            transformer_layers_per_block,
+                        };
+                        CrossAttnDownBlock2D::new(
+                            vs_db.pp(i.to_string()),
+                            in_channels,
+                            out_channels,
+                            Some(time_embed_dim),
+                            use_flash_attn,
+                            cfg,
+                        ).map(UNetDownBlock::CrossAttn)
+                    }
+                    None => DownBlock2D::new(
+                        vs_db.pp(i.to_string()),
+                        in_channels,
+                        out_channels,
+                        Some(time_embed_dim),
+                        db_cfg,
+                    ).map(UNetDownBlock::Basic),
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // https://github.com/huggingface/diffusers/blob/a76f2ad538e73b34d5fe7be08c8eb8ab38c7e90c/src/diffusers/models/unet_2d_condition.py#L462
+        let mid_cfg = UNetMidBlock2DCrossAttnConfig {
+            resnet_eps: config.norm_eps,
+            output_scale_factor: config.mid_block_scale_factor,
+            cross_attn_dim: config.cross_attention_dim,
+            attn_num_head_channels: bl_attention_head_dim,
+            resnet_groups: Some(config.norm_num_groups),
+            use_linear_projection: config.use_linear_projection,
+            transformer_layers_per_block: config.blocks[n_blocks - 1].use_cross_attn.unwrap_or(1),
+            ..Default::default()
+        };
+        let mid_block = UNetMidBlock2DCrossAttn::new(
+            vs.pp("mid_block"),
+            bl_channels,
+            Some(time_embed_dim),
+            use_flash_attn,
+            mid_cfg,
+        )?;
+
+        let vs_ub = vs.pp("up_blocks");
+        let up_blocks = (0..n_blocks)
+            .map(|i| {
+                let BlockConfig { out_channels, use_cross_attn, attention_head_dim } = config.blocks[n_blocks - 1 - i];
+                let prev_out_channels = if i > 0 { config.blocks[n_blocks - i].out_channels } else { bl_channels };
+                let in_channels = {
+                    let index = if i == n_blocks - 1 { 0 } else { n_blocks - i - 2 };
+                    config.blocks[index].out_channels
+                };
+                let ub_cfg = UpBlock2DConfig {
+                    num_layers: config.layers_per_block + 1,
+                    resnet_eps: config.norm_eps,
+                    resnet_groups: config.norm_num_groups,
+                    add_upsample: i < n_blocks - 1,
+                    ..Default::default()
+                };
+                match use_cross_attn {
+                    Some(transformer_layers_per_block) => {
+                        let cfg = CrossAttnUpBlock2DConfig {
+                            upblock: ub_cfg,
+                            attn_num_head_channels: attention_head_dim,
+                            cross_attention_dim: config.cross_attention_dim,
+                            sliced_attention_size: sliced_attention_size(attention_head_dim),
+                            use_linear_projection: config.use_linear_projection,
+                            transformer_layers_per_block,
+                        };
+                        CrossAttnUpBlock2D::new(
+                            vs_ub.pp(i.to_string()),
+                            in_channels,
+                            prev_out_channels,
+                            out_channels,
+                            Some(time_embed_dim),
+                            use_flash_attn,
+                            cfg,
+                        ).map(UNetUpBlock::CrossAttn)
+                    }
+                    None => UpBlock2D::new(
+                        vs_ub.pp(i.to_string()),
+                        in_channels,
+                        prev_out_channels,
+                        out_channels,
+                        Some(time_embed_dim),
+                        ub_cfg,
+                    ).map(UNetUpBlock::Basic),
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let conv_norm_out = nn::group_norm(config.norm_num_groups, b_channels, config.norm_eps, vs.pp("conv_norm_out"))?;
+        let conv_out = nn::conv2d(b_channels, out_channels, 3, conv_cfg, vs.pp("conv_out"))?;
+        Ok(Self {
+            conv_in,
+            time_proj,
+            time_embedding,
+            add_embedding,
+            down_blocks,
+            mid_block,
+            up_blocks,
+            conv_norm_out,
+            conv_out,
+            config,
+        })
+    }
+
+    /// Predicts the noise of `xs`.  `text_time` is required by, and only used by, models built
+    /// with a `TextTimeConfig`.
+    pub fn forward(
+        &self,
+        xs: &Tensor,
+        timestep: f64,
+        encoder_hidden_states: &Tensor,
+        text_time: Option<&TextTimeConditioning>,
+    ) -> Result<Tensor> {
+        let (bsize, _channels, height, width) = xs.dims4()?;
+        let device = xs.device();
+        let n_blocks = self.config.blocks.len();
+        let default_overall_up_factor = 2usize.pow(n_blocks as u32 - 1);
+        let forward_upsample_size = height % default_overall_up_factor != 0 || width % default_overall_up_factor != 0;
+        // 0. center input if necessary
+        let xs = if self.config.center_input_sample {
+            ((xs * 2.0)? - 1.0)?
+        } else {
+            xs.clone()
+        };
+        // 1. time, plus the added embedding
+        let emb = (Tensor::ones(bsize, xs.dtype(), device)? * timestep)?;
+        let emb = self.time_proj.forward(&emb)?;
+        let emb = self.time_embedding.forward(&emb)?;
+        let emb = match (&self.add_embedding, text_time) {
+            (None, _) => emb,
+            (Some(add_embedding), Some(text_time)) => {
+                let time_embeds = add_embedding.time_proj
+                    .forward(&text_time.time_ids.flatten_all()?)?
+                    .reshape((bsize, 6 * add_embedding.config.time_embed_dim))?;
+                let add_embeds = Tensor::cat(&[&text_time.text_embeds, &time_embeds], 1)?.to_dtype(emb.dtype())?;
+                (emb + add_embedding.embedding.forward(&add_embeds)?)?
+            }
+            (Some(_), None) => candle_core::bail!("this UNet needs the pooled text embeddings and time ids"),
+        };
+        // 2. pre-process
+        let xs = self.conv_in.forward(&xs)?;
+        // 3. down
+        let mut down_block_res_xs = vec![xs.clone()];
+        let mut xs = xs;
+        for down_block in self.down_blocks.iter() {
+            let (block_xs, res_xs) = match down_block {
+                UNetDownBlock::Basic(b) => b.forward(&xs, Some(&emb))?,
+                UNetDownBlock::CrossAttn(b) => b.forward(&xs, Some(&emb), Some(encoder_hidden_states))?,
+            };
+            down_block_res_xs.extend(res_xs);
+            xs = block_xs;
+        }
+        // 4. mid
+        let mut xs = self.mid_block.forward(&xs, Some(&emb), Some(encoder_hidden_states))?;
+        // 5. up, every up block has `layers_per_block + 1` resnets
+        let n_resnets = self.config.layers_per_block + 1;
+        let mut upsample_size = None;
+        for (i, up_block) in self.up_blocks.iter().enumerate() {
+            let res_xs = down_block_res_xs.split_off(down_block_res_xs.len() - n_resnets);
+            if i < n_blocks - 1 && forward_upsample_size {
+                if let Some(res) = down_block_res_xs.last() {
+                    let (_, _, h, w) = res.dims4()?;
+                    upsample_size = Some((h, w))
+                }
+            }
+            xs = match up_block {
+                UNetUpBlock::Basic(b) => b.forward(&xs, &res_xs, Some(&emb), upsample_size)?,
+                UNetUpBlock::CrossAttn(b) => {
+                    b.forward(&xs, &res_xs, Some(&emb), upsample_size, Some(encoder_hidden_states))?
+                }
+            };
+        }
+        // 6. post-process
+        let xs = self.conv_norm_out.forward(&xs)?;
+        let xs = nn::ops::silu(&xs)?;
+        self.conv_out.forward(&xs)
+    }
+}
@@ -0,0 +1,80 @@
+//! The encoder half of candle's `vae::AutoEncoderKL`.  candle samples the latent distribution
+//! with `Tensor::randn`, which can't be seeded on the CPU, and keeps its mean and standard
+//! deviation private; this one returns them so img2img can draw the sample from the seed.
+//! It reads the same `encoder.*` and `quant_conv.*` weights.
+use candle_core::{Result, Tensor};
+use candle_nn as nn;
+use candle_nn::Module;
+
+use candle_transformers::models::stable_diffusion::unet_2d_blocks::{
+    DownEncoderBlock2D, DownEncoderBlock2DConfig, UNetMidBlock2D, UNetMidBlock2DConfig,
+};
+use candle_transformers::models::stable_diffusion::vae::AutoEncoderKLConfig;
+
+
+#[derive(Debug)]
+pub struct VaeEncoder {
+    conv_in: nn::Conv2d,
+    down_blocks: Vec<DownEncoderBlock2D>,
+    mid_block: UNetMidBlock2D,
+    conv_norm_out: nn::GroupNorm,
+    conv_out: nn::Conv2d,
+    quant_conv: nn::Conv2d,
+}
+
+impl VaeEncoder {
+    /// `vs` is the VAE's root, as given to `AutoEncoderKL::new`.
+    pub fn new(vs: nn::VarBuilder, in_channels: usize, config: &AutoEncoderKLConfig) -> Result<Self> {
+        let conv_cfg = nn::Conv2dConfig {
+            padding: 1,
+            ..Default::default()
+        };
+        let channels = &config.block_out_channels;
+        let last_channels = channels[channels.len() - 1];
+        let vs_encoder = vs.pp("encoder");
+        let conv_in = nn::conv2d(in_channels, channels[0], 3, conv_cfg, vs_encoder.pp("conv_in"))?;
+        let vs_db = vs_encoder.pp("down_blocks");
+        let down_blocks = (0..channels.len())
+            .map(|i| {
+                let in_channels = if i > 0 { channels[i - 1] } else { channels[0] };
+                let cfg = DownEncoderBlock2DConfig {
+                    num_layers: config.layers_per_block,
+                    resnet_eps: 1e-6,
+                    resnet_groups: config.norm_num_groups,
+                    add_downsample: i + 1 < channels.len(),
+                    downsample_padding: 0,
+                    ..Default::default()
+                };
+                DownEncoderBlock2D::new(vs_db.pp(i.to_string()), in_channels, channels[i], cfg)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mid_cfg = UNetMidBlock2DConfig {
+            resnet_eps: 1e-6,
+            output_scale_factor: 1.,
+            attn_num_head_channels: None,
+            resnet_groups: Some(config.norm_num_groups),
+            ..Default::default()
+        };
+        let mid_block = UNetMidBlock2D::new(vs_encoder.pp("mid_block"), last_channels, None, mid_cfg)?;
+        let conv_norm_out = nn::group_norm(config.norm_num_groups, last_channels, 1e-6, vs_encoder.pp("conv_norm_out"))?;
+        // The mean and the log variance of each latent channel.
+        let moments = 2 * config.latent_channels;
+        let conv_out = nn::conv2d(last_channels, moments, 3, conv_cfg, vs_encoder.pp("conv_out"))?;
+        let quant_conv = nn::conv2d(moments, moments, 1, Default::default(), vs.pp("quant_conv"))?;
+        Ok(VaeEncoder { conv_in, down_blocks, mid_block, conv_norm_out, conv_out, quant_conv })
+    }
+
+    /// The mean and standard deviation of the latent distribution of the images `xs`.
+    pub fn posterior(&self, xs: &Tensor) -> Result<(Tensor, Tensor)> {
+        let mut xs = self.conv_in.forward(xs)?;
+        for down_block in self.down_blocks.iter() {
+            xs = down_block.forward(&xs)?;
+        }
+        let xs = self.mid_block.forward(&xs, None)?;
+        let xs = nn::ops::silu(&self.conv_norm_out.forward(&xs)?)?;
+        let moments = self.quant_conv.forward(&self.conv_out.forward(&xs)?)?;
+        let moments = moments.chunk(2, 1)?;
+        let std = (&moments[1] * 0.5)?.exp()?;
+        Ok((moments[0].clone(), std))
+    }
+}
@@ -1,18 +1,22 @@
 #![allow(unused)]
-use std::io::Write;
-use std::path::PathBuf;
+use std::collections::HashMap;
 
 use mlua::prelude::*;
-use crate::common::{normalize_l2};
+use crate::models::common::{
+    find_stop, is_gguf, load_bytes, normalize_l2, score_pairs, ScoreOutput, TokenLogprob, TokenStream,
+};
+use crate::models::constraints::Constraint;
+use crate::models::logits::LogitsChain;
 
-use candle_transformers::models::t5;
+use candle_transformers::models::{quantized_t5, t5};
+use candle_transformers::quantized_var_builder;
 
-use anyhow::{Error as AnyError, Result as AnyResult};
 use candle_core::{DType, Device, Tensor};
 use candle_nn::VarBuilder;
-use candle_transformers::generation::LogitsProcessor;
 use mlua::{Table, UserData};
 use tokenizers::Tokenizer;
+use crate::ao_log;
+use crate::utils::message_seed;
 
 
 // Which::T5Base => ("t5-base", "main"),
@@ -46,60 +50,321 @@ use tokenizers::Tokenizer;
 
 const DTYPE: DType = DType::F32;
 
-#[derive(Clone, Debug, Copy)]
-enum Which {
-    T5Base,
-    T5Small,
-    T5Large,
-    T5_3B,
-    Mt5Base,
-    Mt5Small,
-    Mt5Large,
-}
-
 #[derive(Debug, Clone)]
-struct Args {
-    device: Device,
-    model: String,
-    config: String,
-    tokenizer: String,
+pub(crate) struct Args {
+    pub(crate) device: Device,
+    /// The safetensors or GGUF weights, see `common::load_bytes` for the accepted inputs.
+    pub(crate) model: Vec<u8>,
+    pub(crate) config: Vec<u8>,
+    pub(crate) tokenizer: Vec<u8>,
     /// Enable decoding.
-    decode: bool,
+    pub(crate) decode: bool,
     /// Use this prompt, otherwise compute sentence similarities.
-    prompt: String,
+    pub(crate) prompt: String,
     /// If set along with --decode, will use this prompt to initialize the decoder.
-    decoder_prompt: Option<String>,
+    pub(crate) decoder_prompt: Option<String>,
+    /// Token forced right after the decoder start token, e.g. a target language tag.
+    pub(crate) forced_bos_token_id: Option<u32>,
     /// L2 normalization for embeddings. default_value = "true"
-    normalize_embeddings: bool,
-    /// The temperature used to generate samples. default_value_t = 0.8
-    temperature: f64,
-    /// Nucleus sampling probability cutoff.
-    top_p: Option<f64>,
-    /// Penalty to be applied for repeating tokens, 1. means no penalty. default_value_t = 1.1
-    repeat_penalty: f32,
-    /// The context size to consider for the repeat penalty. default_value_t = 64
-    repeat_last_n: usize,
+    pub(crate) normalize_embeddings: bool,
+    /// Sampling and penalties, see `LogitsChain::from_table`.
+    pub(crate) logits: LogitsChain,
+    /// Sampling seed, defaults to one derived from the message being handled.
+    pub(crate) seed: u64,
+    /// Maximum number of tokens to generate. default_value_t = 512
+    pub(crate) max_new_tokens: usize,
+    /// Generation ends as soon as the text contains one of these, which is cut off.
+    pub(crate) stop: Vec<String>,
+    /// Beam search width, 1 means sampling/greedy decoding. default_value_t = 1
+    pub(crate) num_beams: usize,
+    /// Exponent of the length normalization of beam scores. default_value_t = 1.0
+    pub(crate) length_penalty: f64,
+    /// Stop beam search as soon as `num_beams` hypotheses are finished. default_value_t = false
+    pub(crate) early_stopping: bool,
+    /// How many of the best beams to return. default_value_t = 1
+    pub(crate) num_return_sequences: usize,
+    /// Restricts the output to a JSON schema or a list of choices.
+    pub(crate) constraint: Option<Constraint>,
+    /// Return the log-probability of each generated token. default_value_t = false
+    pub(crate) logprobs: bool,
+    /// Also return this many of the most likely tokens at each position. default_value_t = 0
+    pub(crate) top_logprobs: usize,
 }
 
 impl UserData for Args { }
 
+/// What `t5.generate` hands back to Lua.
+#[derive(serde::Serialize, Debug)]
+pub(crate) struct T5Output {
+    /// The decoded continuation (without `decoder_prompt`), when decoding.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) text: Option<String>,
+    /// Mean-pooled encoder output, when `decode = false`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) embedding: Option<Vec<f32>>,
+    pub(crate) prompt_tokens: usize,
+    pub(crate) generated_tokens: usize,
+    /// Why decoding ended, `eos`, `length`, `stop` or `callback`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) finish_reason: Option<String>,
+    /// The decoded output, with `json_schema` or `json`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) json: Option<serde_json::Value>,
+    /// The generated tokens with their log-probabilities, with `logprobs` or `top_logprobs`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) logprobs: Option<Vec<TokenLogprob>>,
+    /// The sampling seed used, to reproduce the output.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) seed: Option<u64>,
+    /// Wall time of the encoder forward pass or decoding loop, in seconds.
+    pub(crate) elapsed: f64,
+    pub(crate) tokens_per_second: f64,
+    /// The `num_return_sequences` best beams, best first, when `num_beams > 1`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) sequences: Option<Vec<BeamSequence>>,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub(crate) struct BeamSequence {
+    pub(crate) text: String,
+    /// Sum of the token logprobs divided by `tokens ^ length_penalty`.
+    pub(crate) score: f64,
+    pub(crate) tokens: usize,
+    pub(crate) finish_reason: String,
+}
+
+/// A live hypothesis during beam search.  Each beam owns a clone of the model, so its KV
+/// cache follows the tokens it has decoded; clones share the weight tensors.
+struct Beam<M> {
+    token_ids: Vec<u32>,
+    logprob: f64,
+    model: M,
+}
+
+/// A finished hypothesis: token ids, normalized score and finish reason.
+type Hypothesis = (Vec<u32>, f64, &'static str);
+
+/// Runs the decoder on the next input and returns the logits for the last position.
+///
+/// With `use_cache`, only the newest token is fed after the first step, the rest being in
+/// the model's KV cache.
+fn decode_step(
+    model: &mut T5Generator,
+    builder: &T5ModelBuilder,
+    output_token_ids: &[u32],
+    index: usize,
+    encoder_output: &Tensor,
+) -> LuaResult<Tensor> {
+    let device = &builder.device;
+    let decoder_token_ids = if index == 0 || !builder.config.use_cache {
+        Tensor::new(output_token_ids, device)
+            .map_err(LuaError::external)?
+            .unsqueeze(0)
+            .map_err(LuaError::external)?
+    } else {
+        let last_token = *output_token_ids.last().unwrap();
+        Tensor::new(&[last_token], device)
+            .map_err(LuaError::external)?
+            .unsqueeze(0)
+            .map_err(LuaError::external)?
+    };
+    model
+        .decode(&decoder_token_ids, encoder_output)
+        .map_err(LuaError::external)?
+        .squeeze(0)
+        .map_err(LuaError::external)
+}
+
+/// Beam search over the decoder, returning the best `num_return_sequences` hypotheses.
+fn beam_search(
+    args: &Args,
+    builder: &T5ModelBuilder,
+    tokenizer: &Tokenizer,
+    model: T5Generator,
+    encoder_output: &Tensor,
+    prefix: Vec<u32>,
+) -> LuaResult<Vec<BeamSequence>> {
+    let prefix_len = prefix.len();
+    let eos = builder.config.eos_token_id as u32;
+    let finished = search_beams(args, prefix, eos, model, |model, token_ids, index| {
+        let logits = decode_step(model, builder, token_ids, index, encoder_output)?;
+        let logits = args.logits.penalize(logits, token_ids, prefix_len, &[eos])?;
+        candle_nn::ops::log_softmax(&logits, candle_core::D::Minus1)
+            .map_err(LuaError::external)?
+            .to_vec1()
+            .map_err(LuaError::external)
+    })?;
+    finished.into_iter()
+        .map(|(token_ids, score, finish_reason)| {
+            let generated = &token_ids[prefix_len..];
+            Ok(BeamSequence {
+                text: tokenizer.decode(generated, true).map_err(LuaError::external)?,
+                score,
+                tokens: generated.len(),
+                finish_reason: finish_reason.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// The search of `beam_search`, `step` giving the next-token logprobs of a beam's model
+/// after its `token_ids`, at decoding step `index`.
+///
+/// Follows the Transformers scorer: each step keeps the `num_beams` best continuations out of
+/// `2 * num_beams` candidates, finished hypotheses are scored by
+/// `logprob / length ^ length_penalty`, and the search ends once `num_beams` hypotheses are
+/// finished and either `early_stopping` is set or no live beam can still beat them.
+fn search_beams<M: Clone>(
+    args: &Args,
+    prefix: Vec<u32>,
+    eos: u32,
+    model: M,
+    mut step: impl FnMut(&mut M, &[u32], usize) -> LuaResult<Vec<f32>>,
+) -> LuaResult<Vec<Hypothesis>> {
+    let prefix_len = prefix.len();
+    let normalize = |logprob: f64, length: usize| logprob / (length.max(1) as f64).powf(args.length_penalty);
+    let mut beams = vec![Beam { token_ids: prefix, logprob: 0., model }];
+    let mut finished: Vec<Hypothesis> = Vec::new();
+
+    for index in 0..args.max_new_tokens {
+        let mut candidates: Vec<(usize, u32, f64)> = Vec::new();
+        for (beam_index, beam) in beams.iter_mut().enumerate() {
+            let logprobs = step(&mut beam.model, &beam.token_ids, index)?;
+            let mut ranked: Vec<(u32, f32)> = logprobs.into_iter()
+                .enumerate()
+                .filter(|(_, lp)| lp.is_finite())
+                .map(|(token, lp)| (token as u32, lp))
+                .collect();
+            ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+            ranked.truncate(2 * args.num_beams);
+            candidates.extend(ranked.into_iter().map(|(token, lp)| (beam_index, token, beam.logprob + lp as f64)));
+        }
+        candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+        let mut next_beams: Vec<Beam<M>> = Vec::with_capacity(args.num_beams);
+        for (rank, (beam_index, token, logprob)) in candidates.into_iter().enumerate() {
+            let parent = &beams[beam_index];
+            if token == eos {
+                // Like Transformers, an EOS only counts if it ranks among the top `num_beams`.
+                if rank < args.num_beams {
+                    let length = parent.token_ids.len() - prefix_len;
+                    finished.push((parent.token_ids.clone(), normalize(logprob, length), "eos"));
+                }
+            } else {
+                let mut token_ids = parent.token_ids.clone();
+                token_ids.push(token);
+                next_beams.push(Beam { token_ids, logprob, model: parent.model.clone() });
+            }
+            if next_beams.len() == args.num_beams {
+                break;
+            }
+        }
+        beams = next_beams;
+
+        finished.sort_by(|a, b| b.1.total_cmp(&a.1));
+        finished.truncate(args.num_beams);
+        if beams.is_empty() {
+            break;
+        }
+        if finished.len() == args.num_beams {
+            let worst_finished = finished.last().map(|f| f.1).unwrap_or(f64::MIN);
+            let best_running = beams.iter()
+                .map(|b| normalize(b.logprob, b.token_ids.len() - prefix_len))
+                .fold(f64::MIN, f64::max);
+            if args.early_stopping || best_running <= worst_finished {
+                break;
+            }
+        }
+    }
+
+    // Beams still running at `max_new_tokens` compete with the finished ones.
+    for beam in beams {
+        let length = beam.token_ids.len() - prefix_len;
+        finished.push((beam.token_ids, normalize(beam.logprob, length), "length"));
+    }
+    finished.sort_by(|a, b| b.1.total_cmp(&a.1));
+    finished.truncate(args.num_return_sequences.max(1));
+    Ok(finished)
+}
+
+/// The T5 weights, full precision from safetensors or quantized (q4/q8...) from GGUF.
+enum T5Weights {
+    Safetensors(HashMap<String, Tensor>),
+    /// `quantized_t5` has its own config type, parsed from the same `config.json`.
+    Gguf(quantized_var_builder::VarBuilder, quantized_t5::Config),
+}
+
+/// The encoder-only model, as built from either kind of weights.
+enum T5Encoder {
+    Full(t5::T5EncoderModel),
+    Quantized(quantized_t5::T5EncoderModel),
+}
+
+impl T5Encoder {
+    fn forward(&mut self, input_ids: &Tensor) -> candle_core::Result<Tensor> {
+        match self {
+            T5Encoder::Full(model) => model.forward(input_ids),
+            T5Encoder::Quantized(model) => model.forward(input_ids),
+        }
+    }
+}
+
+/// The conditional generation model, as built from either kind of weights.
+#[derive(Clone)]
+pub(crate) enum T5Generator {
+    Full(t5::T5ForConditionalGeneration),
+    Quantized(quantized_t5::T5ForConditionalGeneration),
+}
+
+impl T5Generator {
+    fn encode(&mut self, input_ids: &Tensor) -> candle_core::Result<Tensor> {
+        match self {
+            T5Generator::Full(model) => model.encode(input_ids),
+            T5Generator::Quantized(model) => model.encode(input_ids),
+        }
+    }
+
+    fn decode(&mut self, decoder_input_ids: &Tensor, encoder_output: &Tensor) -> candle_core::Result<Tensor> {
+        match self {
+            T5Generator::Full(model) => model.decode(decoder_input_ids, encoder_output),
+            T5Generator::Quantized(model) => model.decode(decoder_input_ids, encoder_output),
+        }
+    }
+}
+
+/// Holds the T5 weights once, as tensors, so the encoder-only and conditional generation
+/// models can both be built from them without copying the weight buffer again.
 struct T5ModelBuilder {
-    model_bytes: Vec<u8>,
+    weights: T5Weights,
     config: t5::Config,
     device: Device,
 }
 
 impl T5ModelBuilder {
-    pub fn load(args: &Args) -> AnyResult<(Self, Tokenizer)> {
+    /// Takes the weights out of `args`, so the raw buffer is freed as soon as it's parsed.
+    pub fn load(args: &mut Args) -> LuaResult<(Self, Tokenizer)> {
         let device = Device::Cpu;
-        let mut config: t5::Config = serde_json::from_str::<t5::Config>(&*args.config)
+        let mut config: t5::Config = serde_json::from_slice::<t5::Config>(&args.config)
             .map_err(|err| LuaError::external(err))?;
-        let model_bytes: Vec<u8> = args.model.clone().as_bytes().to_vec();
-        let mut tokenizer = Tokenizer::from_bytes(args.tokenizer.as_bytes())
+        let model_bytes: Vec<u8> = std::mem::take(&mut args.model);
+        // GGUF tensors stay quantized in memory, which is what lets larger checkpoints fit.
+        let weights = if is_gguf(&model_bytes) {
+            let vb = quantized_var_builder::VarBuilder::from_gguf_buffer(&model_bytes, &device)
+                .map_err(|err| LuaError::external(err))?;
+            let quantized_config = serde_json::from_slice::<quantized_t5::Config>(&args.config)
+                .map_err(|err| LuaError::external(err))?;
+            T5Weights::Gguf(vb, quantized_config)
+        } else {
+            let tensors = candle_core::safetensors::load_buffer(&model_bytes, &device)
+                .map_err(|err| LuaError::external(err))?;
+            T5Weights::Safetensors(tensors)
+        };
+        drop(model_bytes);
+        let mut tokenizer = Tokenizer::from_bytes(&args.tokenizer)
             .map_err(|err| LuaError::external(err))?;
         Ok((
             Self {
-                model_bytes,
+                weights,
                 config,
                 device
             },
@@ -107,34 +372,54 @@ impl T5ModelBuilder {
         ))
     }
 
-    pub fn build_encoder(&self) -> AnyResult<t5::T5EncoderModel> {
-        let vb = unsafe {
-            VarBuilder::from_buffered_safetensors(self.model_bytes.clone(), DTYPE, &self.device)
-                .map_err(|err| LuaError::external(err))?
+    /// Tensors are reference counted, so every `VarBuilder` shares the same storage.
+    fn var_builder(tensors: &HashMap<String, Tensor>, device: &Device) -> VarBuilder<'static> {
+        VarBuilder::from_tensors(tensors.clone(), DTYPE, device)
+    }
+
+    fn build_encoder(&self) -> LuaResult<T5Encoder> {
+        let model = match &self.weights {
+            T5Weights::Safetensors(tensors) => {
+                t5::T5EncoderModel::load(Self::var_builder(tensors, &self.device), &self.config)
+                    .map(T5Encoder::Full)
+            }
+            T5Weights::Gguf(vb, config) => {
+                quantized_t5::T5EncoderModel::load(vb.clone(), config)
+                    .map(T5Encoder::Quantized)
+            }
         };
-        let model: t5::T5EncoderModel = t5::T5EncoderModel::load(vb, &self.config)
-            .map_err(|err| LuaError::external(err))?;
-        Ok(model)
+        model.map_err(|err| LuaError::external(err))
     }
 
-    pub fn build_conditional_generation(&self) -> AnyResult<t5::T5ForConditionalGeneration> {
-        let vb = unsafe {
-            VarBuilder::from_buffered_safetensors(self.model_bytes.clone(), DTYPE, &Device::Cpu)
-                .map_err(|err| LuaError::external(err))?
+    fn build_conditional_generation(&self) -> LuaResult<T5Generator> {
+        let model = match &self.weights {
+            T5Weights::Safetensors(tensors) => {
+                t5::T5ForConditionalGeneration::load(Self::var_builder(tensors, &self.device), &self.config)
+                    .map(T5Generator::Full)
+            }
+            T5Weights::Gguf(vb, config) => {
+                quantized_t5::T5ForConditionalGeneration::load(vb.clone(), config)
+                    .map(T5Generator::Quantized)
+            }
         };
-        let model: t5::T5ForConditionalGeneration = t5::T5ForConditionalGeneration::load(vb, &self.config)
-            .map_err(|err| LuaError::external(err))?;
-        Ok(model)
+        model.map_err(|err| LuaError::external(err))
     }
 }
 
-fn __t5(args: Args) -> LuaResult<()> {
-    let (builder, mut tokenizer) = T5ModelBuilder::load(&args).map_err(LuaError::external)?;
+pub(crate) fn __t5<'lua>(lua: &'lua Lua, mut args: Args, mut stream: TokenStream<'lua>) -> LuaResult<T5Output> {
+    if args.num_beams > 1 && stream.is_active() {
+        return Err(LuaError::RuntimeError(
+            "on_token doesn't work with beam search, no token is final until the search ends".to_string()
+        ));
+    }
+    let (builder, mut tokenizer) = T5ModelBuilder::load(&mut args)?;
     let device = &builder.device;
-    let tokenizer = tokenizer.with_padding(None)
+    tokenizer.with_padding(None)
         .with_truncation(None)
         .map_err(LuaError::external)?;
-    let prompt = args.prompt;
+    // `with_padding` hands back the inner `TokenizerImpl`, the helpers take a `Tokenizer`.
+    let tokenizer = &tokenizer;
+    let prompt = std::mem::take(&mut args.prompt);
     let tokens = tokenizer
         .encode(prompt, true)
         .map_err(LuaError::external)?
@@ -145,27 +430,49 @@ fn __t5(args: Args) -> LuaResult<()> {
         .unsqueeze(0)
         .map_err(LuaError::external)?;
     if !args.decode {
-        let mut model = builder
-            .build_encoder()
-            .map_err(LuaError::external)?;
+        let mut model = builder.build_encoder()?;
         let start = std::time::Instant::now();
         let embedding = model
             .forward(&input_token_ids)
             .map_err(LuaError::external)?;
-        println!("{embedding}");
-        println!("Took {:?}", start.elapsed());
-    } else {
-        let mut model = builder
-            .build_conditional_generation()
+        // Mean pooling over the tokens, as for the bert sentence embeddings.
+        let (_n_sentence, n_tokens, _hidden_size) = embedding.dims3().map_err(LuaError::external)?;
+        let embedding = (embedding.sum(1).map_err(LuaError::external)? / (n_tokens as f64))
+            .map_err(LuaError::external)?;
+        let embedding = if args.normalize_embeddings {
+            normalize_l2(&embedding).map_err(LuaError::external)?
+        } else {
+            embedding
+        };
+        let embedding: Vec<f32> = embedding
+            .flatten_all()
+            .map_err(LuaError::external)?
+            .to_vec1()
             .map_err(LuaError::external)?;
+        let elapsed = start.elapsed().as_secs_f64();
+        Ok(T5Output {
+            text: None,
+            embedding: Some(embedding),
+            prompt_tokens: tokens.len(),
+            generated_tokens: 0,
+            finish_reason: None,
+            json: None,
+            logprobs: None,
+            seed: None,
+            elapsed,
+            tokens_per_second: tokens.len() as f64 / elapsed,
+            sequences: None,
+        })
+    } else {
+        let mut model = builder.build_conditional_generation()?;
         let mut output_token_ids = [builder
             .config
             .decoder_start_token_id
             .unwrap_or(builder.config.pad_token_id)
             as u32
         ].to_vec();
+        output_token_ids.extend(args.forced_bos_token_id);
         if let Some(decoder_prompt) = &args.decoder_prompt {
-            print!("{decoder_prompt}");
             output_token_ids.extend(
                 tokenizer
                     .encode(decoder_prompt.to_string(), false)
@@ -174,102 +481,283 @@ fn __t5(args: Args) -> LuaResult<()> {
                     .to_vec(),
             );
         }
-        let temperature = if args.temperature <= 0. {
-            None
-        } else {
-            Some(args.temperature)
-        };
-        let mut logits_processor = LogitsProcessor::new(
-            299792458,
-            temperature,
-            args.top_p
-        );
+        let prefix_len = output_token_ids.len();
+        let mut logits_processor = args.logits.logits_processor(args.seed);
+        let mut finish_reason = "length";
+        let mut stop_at: Option<usize> = None;
         let encoder_output = model.encode(&input_token_ids)
             .map_err(LuaError::external)?;
         let start = std::time::Instant::now();
 
-        for index in 0.. {
-            if output_token_ids.len() > 512 {
-                break;
-            }
-            let decoder_token_ids = if index == 0 || !builder.config.use_cache {
-                Tensor::new(output_token_ids.as_slice(), device)
-                    .map_err(LuaError::external)?
-                    .unsqueeze(0)
-                    .map_err(LuaError::external)?
-            } else {
-                let last_token = *output_token_ids.last().unwrap();
-                Tensor::new(&[last_token], device)
-                    .map_err(LuaError::external)?
-                    .unsqueeze(0)
-                    .map_err(LuaError::external)?
-            };
-            let logits = model
-                .decode(&decoder_token_ids, &encoder_output)
-                .map_err(LuaError::external)?
-                .squeeze(0)
-                .map_err(LuaError::external)?;
-            let logits = if args.repeat_penalty == 1. {
-                logits
-            } else {
-                let start_at = output_token_ids.len().saturating_sub(args.repeat_last_n);
-                candle_transformers::utils::apply_repeat_penalty(
-                    &logits,
-                    args.repeat_penalty,
-                    &output_token_ids[start_at..],
-                ).map_err(LuaError::external)?
+        if args.num_beams > 1 {
+            let sequences = beam_search(
+                &args, &builder, &tokenizer, model, &encoder_output, output_token_ids,
+            )?;
+            let dt = start.elapsed();
+            let best = &sequences[0];
+            ao_log(&format!(
+                "{} beams, best of {} tokens scored {:.3}",
+                args.num_beams,
+                best.tokens,
+                best.score,
+            ));
+            let generated_tokens: usize = sequences.iter().map(|s| s.tokens).sum();
+            return Ok(T5Output {
+                text: Some(best.text.clone()),
+                embedding: None,
+                prompt_tokens: tokens.len(),
+                generated_tokens: best.tokens,
+                finish_reason: Some(best.finish_reason.clone()),
+                json: None,
+                logprobs: None,
+                seed: None,
+                elapsed: dt.as_secs_f64(),
+                tokens_per_second: generated_tokens as f64 / dt.as_secs_f64(),
+                sequences: Some(sequences),
+            });
+        }
+
+        let eos_token_id = builder.config.eos_token_id as u32;
+        let mut constraint = args.constraint.clone();
+        let mut logprobs = (args.logprobs || args.top_logprobs > 0).then(Vec::new);
+        for index in 0..args.max_new_tokens {
+            let logits = decode_step(&mut model, &builder, &output_token_ids, index, &encoder_output)?;
+            let logits = args.logits.process(logits, &output_token_ids, prefix_len, &[eos_token_id])?;
+            let logits = match &constraint {
+                Some(constraint) => constraint.mask(&logits, &tokenizer, &output_token_ids, &[eos_token_id])?,
+                None => logits,
             };
 
             let next_token_id = logits_processor.sample(&logits).map_err(LuaError::external)?;
-            if next_token_id as usize == builder.config.eos_token_id {
+            if next_token_id == eos_token_id {
+                finish_reason = "eos";
                 break;
             }
+            if let Some(constraint) = &mut constraint {
+                constraint.advance(&tokenizer, &output_token_ids, next_token_id)?;
+            }
+            if let Some(logprobs) = &mut logprobs {
+                logprobs.push(TokenLogprob::from_logits(&logits, &tokenizer, next_token_id, args.top_logprobs)?);
+            }
             output_token_ids.push(next_token_id);
-            if let Some(text) = tokenizer.id_to_token(next_token_id) {
-                let text = text
-                    .replace('▁', " ")
-                    .replace("<0x0A>", "\n");
-                print!("{text}");
-                std::io::stdout().flush().map_err(LuaError::external)?;
+            if !args.stop.is_empty() {
+                let text = tokenizer.decode(&output_token_ids[prefix_len..], true).map_err(LuaError::external)?;
+                if let Some(at) = find_stop(&text, &args.stop) {
+                    stop_at = Some(at);
+                    finish_reason = "stop";
+                }
+            }
+            if stream.is_active() {
+                let logprob = candle_nn::ops::log_softmax(&logits, candle_core::D::Minus1)
+                    .and_then(|logprobs| logprobs.get(next_token_id as usize))
+                    .and_then(|logprob| logprob.to_scalar::<f32>())
+                    .map_err(LuaError::external)?;
+                let go_on = stream.push(lua, &tokenizer, &output_token_ids[prefix_len..], logprob, &args.stop)?;
+                if !go_on && stop_at.is_none() {
+                    finish_reason = "callback";
+                    break;
+                }
+            }
+            if stop_at.is_some() {
+                break;
+            }
+            if constraint.as_ref().map_or(false, |constraint| constraint.is_done()) {
+                finish_reason = "eos";
+                break;
             }
         }
         let dt = start.elapsed();
-        println!(
-            "\n{} tokens generated ({:.2} token/s)\n",
-            output_token_ids.len(),
-            output_token_ids.len() as f64 / dt.as_secs_f64(),
-        );
+        let generated = &output_token_ids[prefix_len..];
+        let mut text = tokenizer.decode(generated, true).map_err(LuaError::external)?;
+        if let Some(at) = stop_at {
+            text.truncate(at);
+        }
+        stream.finish(lua, &text)?;
+        ao_log(&format!(
+            "{} tokens generated ({:.2} token/s)",
+            generated.len(),
+            generated.len() as f64 / dt.as_secs_f64(),
+        ));
+        let json = constraint.as_ref().and_then(|constraint| constraint.parse(&text));
+        Ok(T5Output {
+            text: Some(text),
+            embedding: None,
+            prompt_tokens: tokens.len(),
+            generated_tokens: generated.len(),
+            finish_reason: Some(finish_reason.to_string()),
+            json,
+            logprobs,
+            seed: Some(args.seed),
+            elapsed: dt.as_secs_f64(),
+            tokens_per_second: generated.len() as f64 / dt.as_secs_f64(),
+            sequences: None,
+        })
     }
-    Ok(())
 }
 
-pub fn main(lua: &Lua, table: Table) -> LuaResult<()> {
-    let args = Args {
-        model: table.get("model")?,
-        config: table.get("config")?,
-        tokenizer: table.get("tokenizer")?,
-        device: Device::Cpu,
-        /// Enable decoding.
-        decode: table.get("decode").unwrap_or(true),
-        /// Use this prompt, otherwise compute sentence similarities.
-        // prompt: "Do cats eat fruit from trees that has fallen to the ground where they can reach it?".to_string(),
-        prompt: table.get("prompt")?,
-        /// If set along with --decode, will use this prompt to initialize the decoder.
-        // decoder_prompt: Option::from("Answer this question in English: ".to_string()), // Option<String>,
-        decoder_prompt: table.get("decoder_prompt")?,
-        /// L2 normalization for embeddings. default_value = "true"
-        normalize_embeddings: true,
-        /// The temperature used to generate samples. default_value_t = 0.8
-        temperature: table.get("temperature").unwrap_or(0.8f64),
-        /// Nucleus sampling probability cutoff.
-        top_p: table.get("top_p")?, // Option<f64>,
-        /// Penalty to be applied for repeating tokens, 1. means no penalty. default_value_t = 1.1
-        repeat_penalty: table.get("repeat_penalty").unwrap_or(1.1f32),
-        /// The context size to consider for the repeat penalty. default_value_t = 64
-        repeat_last_n: table.get("repeat_last_n").unwrap_or(64usize),
-    };
+impl Args {
+    /// Reads the options shared by every T5 entry point, see `generate`.
+    pub(crate) fn from_table(lua: &Lua, table: &Table) -> LuaResult<Self> {
+        let args = Args {
+            model: load_bytes(table.get("model")?, "model")?,
+            config: load_bytes(table.get("config")?, "config")?,
+            tokenizer: load_bytes(table.get("tokenizer")?, "tokenizer")?,
+            device: Device::Cpu,
+            /// Enable decoding.
+            decode: table.get("decode").unwrap_or(true),
+            /// Use this prompt, otherwise compute sentence similarities.
+            // prompt: "Do cats eat fruit from trees that has fallen to the ground where they can reach it?".to_string(),
+            prompt: table.get::<_, Option<String>>("prompt")?.unwrap_or_default(),
+            /// If set along with --decode, will use this prompt to initialize the decoder.
+            // decoder_prompt: Option::from("Answer this question in English: ".to_string()), // Option<String>,
+            decoder_prompt: table.get("decoder_prompt")?,
+            forced_bos_token_id: table.get("forced_bos_token_id")?,
+            /// L2 normalization for embeddings. default_value = "true"
+            normalize_embeddings: table.get("normalize_embeddings").unwrap_or(true),
+            logits: LogitsChain::from_table(table)?,
+            seed: table.get::<_, Option<u64>>("seed")?.unwrap_or_else(message_seed),
+            max_new_tokens: table.get("max_new_tokens").unwrap_or(512usize),
+            stop: match table.get::<_, LuaValue>("stop")? {
+                LuaValue::Nil => Vec::new(),
+                LuaValue::String(stop) => vec![stop.to_str()?.to_string()],
+                stop => Vec::<String>::from_lua(stop, lua)?,
+            },
+            num_beams: table.get("num_beams").unwrap_or(1usize),
+            length_penalty: table.get("length_penalty").unwrap_or(1.0f64),
+            early_stopping: table.get("early_stopping").unwrap_or(false),
+            num_return_sequences: table.get("num_return_sequences").unwrap_or(1usize),
+            constraint: Constraint::from_table(lua, table)?,
+            logprobs: table.get("logprobs").unwrap_or(false),
+            top_logprobs: table.get("top_logprobs").unwrap_or(0usize),
+        };
+        if args.num_return_sequences > args.num_beams.max(1) {
+            return Err(LuaError::RuntimeError(format!(
+                "num_return_sequences ({}) can't be larger than num_beams ({})",
+                args.num_return_sequences,
+                args.num_beams
+            )));
+        }
+        if args.constraint.is_some() && args.num_beams > 1 {
+            return Err(LuaError::RuntimeError(
+                "json_schema, json and choices don't work with beam search".to_string()
+            ));
+        }
+        if args.num_beams > 1 && (!args.stop.is_empty() || args.logprobs || args.top_logprobs > 0) {
+            return Err(LuaError::RuntimeError(
+                "stop, logprobs and top_logprobs don't work with beam search".to_string()
+            ));
+        }
+        Ok(args)
+    }
+}
+
+/// Lua entry point, `t5.generate(opts)`.
+///
+/// Runs conditional generation on `opts.prompt` and returns
+/// `{ text, prompt_tokens, generated_tokens, finish_reason, seed, elapsed, tokens_per_second }`,
+/// or with `decode = false` the mean-pooled encoder output as `embedding` instead of `text`.
+/// `model` may be safetensors or a quantized GGUF file (e.g. `lmz/candle-quantized-t5`),
+/// which is kept quantized in memory.
+///
+/// Decoding is controlled by `temperature`, `top_k`, `top_p`, `min_p`, `typical_p`,
+/// `repeat_penalty`, `repeat_last_n`, `frequency_penalty`, `presence_penalty`, `logit_bias`,
+/// `banned_token_ids`, `no_repeat_ngram_size`, `min_new_tokens`, `max_new_tokens`, `stop` (a
+/// string or list of strings) and `seed`, which defaults to a hash of the message id.
+/// With `num_beams > 1` beam search is used instead of sampling (`length_penalty`,
+/// `early_stopping`, `num_return_sequences`), and the beams come back in `sequences` with
+/// their scores; `stop`, `on_token`, `logprobs` and `top_logprobs` are rejected with it.
+/// `forced_bos_token_id` is placed right after the decoder start token, before
+/// `decoder_prompt`.
+///
+/// `on_token`, if set, is called after each sampled token with
+/// `{ index, id, token, text, logprob }`, `text` being the delta to append to the output so
+/// far.  Returning `false` from it ends generation with `finish_reason = "callback"`.  The
+/// streamed text stops where `stop` cuts the output; text which may start a stop string is
+/// held back, and sent with a repeat of the last event if generation ends without it.
+///
+/// `json_schema` (a table or JSON text) restricts the output to JSON matching the schema,
+/// `json = true` to any JSON value and `choices` to one of a list of strings; logits of the
+/// tokens which can't continue the output are masked before sampling.  With JSON, the
+/// decoded output is also returned as `json`, a Lua table.  There is no general grammar
+/// option, `choices` is the only constraint besides JSON.
+///
+/// `logprobs = true` returns the generated tokens as `logprobs`, `{ id, token, logprob }`
+/// under the same distribution as `on_token`; `top_logprobs = n` adds the `n` most likely
+/// tokens at each position as `top`.
+pub fn generate(lua: &Lua, table: Table) -> LuaResult<LuaValue> {
+    let args = Args::from_table(lua, &table)?;
+    let stream = TokenStream::new(table.get("on_token")?);
+    let output = __t5(lua, args, stream)?;
+    lua.to_value(&output)
+}
 
-    let embeddings = __t5(args).map_err(|err| LuaError::external(err))?;
+/// The log-probability of `target` given `source`, one decoder step per target token.
+fn score_pair(
+    builder: &T5ModelBuilder,
+    tokenizer: &Tokenizer,
+    mut model: T5Generator,
+    source: &str,
+    target: &str,
+    top_n: usize,
+) -> LuaResult<ScoreOutput> {
+    let source_ids = tokenizer.encode(source, true).map_err(LuaError::external)?.get_ids().to_vec();
+    let input_token_ids = Tensor::new(&source_ids[..], &builder.device)
+        .map_err(LuaError::external)?
+        .unsqueeze(0)
+        .map_err(LuaError::external)?;
+    let encoder_output = model.encode(&input_token_ids).map_err(LuaError::external)?;
+    // The target keeps its EOS, so a text which should go on scores lower than one which ends.
+    let target_ids = tokenizer.encode(target, true).map_err(LuaError::external)?.get_ids().to_vec();
+    let decoder_start_token_id = builder.config.decoder_start_token_id.unwrap_or(builder.config.pad_token_id);
+    let mut decoder_token_ids = vec![decoder_start_token_id as u32];
+    let mut scored = Vec::with_capacity(target_ids.len());
+    for (index, &id) in target_ids.iter().enumerate() {
+        let logits = decode_step(&mut model, builder, &decoder_token_ids, index, &encoder_output)?;
+        scored.push(TokenLogprob::from_logits(&logits, tokenizer, id, top_n)?);
+        decoder_token_ids.push(id);
+    }
+    Ok(ScoreOutput::new(scored))
+}
+
+/// Lua entry point, `t5.score(opts)`.
+///
+/// Scores `opts.target` as the output for `opts.source` instead of generating, or every
+/// `{ source, target }` of `opts.pairs`.  Returns
+/// `{ total_logprob, mean_logprob, perplexity, tokens = { { id, token, logprob }, ... } }`,
+/// or a list of them for `pairs`, with the target's EOS as the last token.  `top_logprobs`
+/// adds the most likely tokens at each position as `top`.  Useful to rank candidate answers
+/// or to classify by likelihood.
+pub fn score(lua: &Lua, table: Table) -> LuaResult<LuaValue> {
+    let (pairs, is_list) = score_pairs(&table, "source", "target")?;
+    let mut args = Args::from_table(lua, &table)?;
+    let (builder, mut tokenizer) = T5ModelBuilder::load(&mut args)?;
+    tokenizer.with_padding(None)
+        .with_truncation(None)
+        .map_err(LuaError::external)?;
+    let model = builder.build_conditional_generation()?;
+    let start = std::time::Instant::now();
+    let mut outputs = Vec::with_capacity(pairs.len());
+    for (source, target) in &pairs {
+        let source = source.as_deref()
+            .ok_or_else(|| LuaError::RuntimeError("each pair needs a source".to_string()))?;
+        // Each pair starts from an empty KV cache.
+        outputs.push(score_pair(&builder, &tokenizer, model.clone(), source, target, args.top_logprobs)?);
+    }
+    ao_log(&format!("{} pairs scored in {:.2}s", pairs.len(), start.elapsed().as_secs_f64()));
+    if is_list {
+        lua.to_value(&outputs)
+    } else {
+        lua.to_value(&outputs[0])
+    }
+}
+
+pub fn preload(lua: &Lua) -> LuaResult<()> {
+    let package: LuaTable = lua.globals().get("package")?;
+    let loaded: LuaTable = package.get("loaded")?;
+    let t5_module_table = lua.create_table()?;
+    t5_module_table.set("generate", lua.create_function(generate)?)?;
+    t5_module_table.set("score", lua.create_function(score)?)?;
+    loaded.set("t5", t5_module_table)?;
     Ok(())
 }
 
@@ -279,3 +767,115 @@ pub fn main(lua: &Lua, table: Table) -> LuaResult<()> {
 //     exports.set("t5", lua.create_function(_t5)?)?;
 //     Ok(exports)
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EOS: u32 = 0;
+
+    fn args(num_beams: usize, length_penalty: f64, early_stopping: bool) -> Args {
+        Args {
+            device: Device::Cpu,
+            model: Vec::new(),
+            config: Vec::new(),
+            tokenizer: Vec::new(),
+            decode: true,
+            prompt: String::new(),
+            decoder_prompt: None,
+            forced_bos_token_id: None,
+            normalize_embeddings: true,
+            logits: LogitsChain {
+                temperature: 0.,
+                top_k: None,
+                top_p: None,
+                min_p: None,
+                typical_p: None,
+                repeat_penalty: 1.,
+                repeat_last_n: 64,
+                frequency_penalty: 0.,
+                presence_penalty: 0.,
+                no_repeat_ngram_size: 0,
+                min_new_tokens: 0,
+                logit_bias: HashMap::new(),
+                banned_token_ids: Vec::new(),
+            },
+            seed: 0,
+            max_new_tokens: 4,
+            stop: Vec::new(),
+            num_beams,
+            length_penalty,
+            early_stopping,
+            num_return_sequences: num_beams,
+            constraint: None,
+            logprobs: false,
+            top_logprobs: 0,
+        }
+    }
+
+    /// A model over tokens `EOS`, 1 and 2: after the start token, 1 is likely but ends the
+    /// text right away, 2 is less likely but goes on for two more tokens at no cost.
+    fn short_or_long(_: &mut (), token_ids: &[u32], _: usize) -> LuaResult<Vec<f32>> {
+        let probs: [f32; 3] = match token_ids {
+            [_] => [0.0, 0.6, 0.4],
+            [_, 2] | [_, 2, 2] => [0.0, 0.0, 1.0],
+            _ => [1.0, 0.0, 0.0],
+        };
+        Ok(probs.iter().map(|p| p.ln()).collect())
+    }
+
+    /// Ends right away half of the time, otherwise 2 goes on at no cost until `max_new_tokens`.
+    fn eos_first(_: &mut (), token_ids: &[u32], _: usize) -> LuaResult<Vec<f32>> {
+        let probs: [f32; 3] = match token_ids {
+            [_] => [0.5, 0.3, 0.2],
+            [_, 1] => [0.5, 0.0, 0.5],
+            [_, 2, ..] => [0.0, 0.0, 1.0],
+            _ => [1.0, 0.0, 0.0],
+        };
+        Ok(probs.iter().map(|p| p.ln()).collect())
+    }
+
+    /// The generated tokens and score of each returned hypothesis, best first.
+    fn search(
+        args: &Args,
+        step: fn(&mut (), &[u32], usize) -> LuaResult<Vec<f32>>,
+    ) -> Vec<(Vec<u32>, f64)> {
+        search_beams(args, vec![9], EOS, (), step)
+            .unwrap()
+            .into_iter()
+            .map(|(token_ids, score, _)| (token_ids[1..].to_vec(), score))
+            .collect()
+    }
+
+    #[test]
+    fn test_beam_ranking() {
+        let beams = search(&args(2, 0.0, false), short_or_long);
+        assert_eq!(beams.len(), 2);
+        assert_eq!(beams[0].0, vec![1]);
+        assert!((beams[0].1 - 0.6f64.ln()).abs() < 1e-6);
+        assert_eq!(beams[1].0, vec![2, 2, 2]);
+        assert!((beams[1].1 - 0.4f64.ln()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_length_penalty() {
+        // Normalizing by the length favours the longer beam.
+        let beams = search(&args(2, 1.0, false), short_or_long);
+        assert_eq!(beams[0].0, vec![2, 2, 2]);
+        assert!((beams[0].1 - 0.4f64.ln() / 3.).abs() < 1e-6);
+        // A negative exponent favours the short one.
+        let beams = search(&args(2, -1.0, false), short_or_long);
+        assert_eq!(beams[0].0, vec![1]);
+        assert!((beams[1].1 - 0.4f64.ln() * 3.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_early_stopping() {
+        // Two hypotheses are finished after the second step, which early stopping keeps.
+        let beams = search(&args(2, 1.0, true), eos_first);
+        assert_eq!(beams.iter().map(|b| b.0.clone()).collect::<Vec<_>>(), vec![vec![], vec![2, 2]]);
+        // Otherwise the live beam of 2s goes on and ends up scoring better.
+        let beams = search(&args(2, 1.0, false), eos_first);
+        assert_eq!(beams.iter().map(|b| b.0.clone()).collect::<Vec<_>>(), vec![vec![2, 2, 2, 2], vec![]]);
+    }
+}
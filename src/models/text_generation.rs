@@ -0,0 +1,615 @@
+use std::collections::HashSet;
+
+use mlua::prelude::*;
+use candle_core::quantized::gguf_file;
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::{llama, mistral, phi, quantized_llama, quantized_phi};
+use serde_json::Value;
+use tokenizers::Tokenizer;
+
+use crate::models::chat_template::{self, ChatTemplate};
+use crate::models::sessions::{self, Session};
+use crate::models::common::{
+    find_stop, is_gguf, load_bytes, score_pairs, ScoreOutput, TokenLogprob, TokenStream,
+};
+use crate::models::logits::LogitsChain;
+use crate::models::constraints::Constraint;
+use crate::ao_log;
+use crate::utils::{message_seed, message_timestamp};
+
+
+const DTYPE: DType = DType::F32;
+
+/// Tokens which end a turn in the usual chat and base model vocabularies, used when
+/// `config.json` doesn't name the EOS token.
+const EOS_TOKENS: &[&str] = &["</s>", "<|endoftext|>", "<|eot_id|>", "<|im_end|>", "<|end|>"];
+
+/// A decoder-only model, with its KV cache.  Clones share the weights but not the cache.
+#[derive(Clone)]
+pub(crate) enum CausalLM {
+    Llama(llama::Llama, llama::Cache),
+    Mistral(mistral::Model),
+    Phi(phi::Model),
+    /// LLaMA-architecture GGUF files, which includes Mistral ones.
+    QuantizedLlama(quantized_llama::ModelWeights),
+    /// Phi-2 GGUF files.
+    QuantizedPhi(quantized_phi::ModelWeights),
+}
+
+impl CausalLM {
+    /// Runs `input_ids`, positioned at `index_pos`, and returns the logits of the last one.
+    fn forward(&mut self, input_ids: &Tensor, index_pos: usize) -> candle_core::Result<Tensor> {
+        let logits = match self {
+            CausalLM::Llama(model, cache) => model.forward(input_ids, index_pos, cache)?,
+            CausalLM::Mistral(model) => model.forward(input_ids, index_pos)?,
+            // Phi tracks the position in its own KV cache.
+            CausalLM::Phi(model) => model.forward(input_ids)?,
+            CausalLM::QuantizedLlama(model) => model.forward(input_ids, index_pos)?,
+            CausalLM::QuantizedPhi(model) => model.forward(input_ids, index_pos)?,
+        };
+        logits.flatten_all()?.to_dtype(DType::F32)
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            CausalLM::Llama(..) => "llama",
+            CausalLM::Mistral(_) => "mistral",
+            CausalLM::Phi(_) => "phi",
+            CausalLM::QuantizedLlama(_) => "quantized_llama",
+            CausalLM::QuantizedPhi(_) => "quantized_phi",
+        }
+    }
+}
+
+pub(crate) struct Args {
+    device: Device,
+    /// Safetensors or GGUF weights, see `common::load_bytes` for the accepted inputs.  Empty
+    /// when not given, which sessions allow once loaded.
+    model: Vec<u8>,
+    /// `config.json`, only required for safetensors weights.
+    config: Option<Vec<u8>>,
+    tokenizer: Vec<u8>,
+    prompt: String,
+    /// Let the tokenizer add BOS & co., off for chat prompts which already hold them.
+    add_special_tokens: bool,
+    /// An extra end of turn token, from the chat template's `eos_token`.
+    eos_token: Option<String>,
+    /// Sampling and penalties, see `LogitsChain::from_table`.
+    logits: LogitsChain,
+    /// Sampling seed, defaults to one derived from the message being handled.
+    seed: u64,
+    /// Maximum number of tokens to generate. default_value_t = 256
+    max_new_tokens: usize,
+    /// Generation ends as soon as the text contains one of these, which is cut off.
+    stop: Vec<String>,
+    /// Restricts the output to a JSON schema or a list of choices.
+    constraint: Option<Constraint>,
+    /// Return the log-probability of each generated token. default_value_t = false
+    logprobs: bool,
+    /// Also return this many of the most likely tokens at each position. default_value_t = 0
+    top_logprobs: usize,
+}
+
+impl Args {
+    fn from_table(lua: &Lua, table: &LuaTable) -> LuaResult<Self> {
+        let mut args = Self::from_options(lua, table)?;
+        args.load_weights(table)?;
+        Ok(args)
+    }
+
+    /// Reads every option but `model`, `config` and `tokenizer`, which are left empty.
+    fn from_options(lua: &Lua, table: &LuaTable) -> LuaResult<Self> {
+        Ok(Args {
+            device: Device::Cpu,
+            model: Vec::new(),
+            config: None,
+            tokenizer: Vec::new(),
+            prompt: table.get::<_, Option<String>>("prompt")?.unwrap_or_default(),
+            add_special_tokens: table.get::<_, Option<bool>>("add_special_tokens")?.unwrap_or(true),
+            eos_token: None,
+            logits: LogitsChain::from_table(table)?,
+            seed: table.get::<_, Option<u64>>("seed")?.unwrap_or_else(message_seed),
+            max_new_tokens: table.get("max_new_tokens").unwrap_or(256usize),
+            stop: match table.get::<_, LuaValue>("stop")? {
+                LuaValue::Nil => Vec::new(),
+                LuaValue::String(stop) => vec![stop.to_str()?.to_string()],
+                stop => Vec::<String>::from_lua(stop, lua)?,
+            },
+            constraint: Constraint::from_table(lua, table)?,
+            logprobs: table.get("logprobs").unwrap_or(false),
+            top_logprobs: table.get("top_logprobs").unwrap_or(0usize),
+        })
+    }
+
+    /// Reads `model`, `config` and `tokenizer`, which may be WeaveDrive reads or large
+    /// base64 decodes, so only when a model has to be loaded.
+    fn load_weights(&mut self, table: &LuaTable) -> LuaResult<()> {
+        if let Some(model) = table.get::<_, Option<LuaValue>>("model")? {
+            self.model = load_bytes(model, "model")?;
+        }
+        if let Some(config) = table.get::<_, Option<LuaValue>>("config")? {
+            self.config = Some(load_bytes(config, "config")?);
+        }
+        if let Some(tokenizer) = table.get::<_, Option<LuaValue>>("tokenizer")? {
+            self.tokenizer = load_bytes(tokenizer, "tokenizer")?;
+        }
+        Ok(())
+    }
+
+    fn config_json(&self) -> LuaResult<serde_json::Value> {
+        let config = self.config.as_ref()
+            .ok_or_else(|| LuaError::RuntimeError("config is required for safetensors weights".to_string()))?;
+        serde_json::from_slice(config).map_err(LuaError::external)
+    }
+
+    /// Loads the model, taking the weights out of `args` so the buffer is freed once parsed.
+    fn build_model(&mut self) -> LuaResult<(CausalLM, HashSet<u32>)> {
+        if self.model.is_empty() {
+            return Err(LuaError::RuntimeError("model is required".to_string()));
+        }
+        let model_bytes = std::mem::take(&mut self.model);
+        let mut eos_token_ids = HashSet::new();
+        let model = if is_gguf(&model_bytes) {
+            let mut cursor = std::io::Cursor::new(&model_bytes);
+            let content = gguf_file::Content::read(&mut cursor).map_err(LuaError::external)?;
+            if let Some(eos) = content.metadata.get("tokenizer.ggml.eos_token_id") {
+                eos_token_ids.extend(eos.to_u32().ok());
+            }
+            let architecture = content.metadata.get("general.architecture")
+                .and_then(|arch| arch.to_string().ok())
+                .cloned()
+                .unwrap_or_default();
+            match architecture.as_str() {
+                "llama" => quantized_llama::ModelWeights::from_gguf(content, &mut cursor, &self.device)
+                    .map(CausalLM::QuantizedLlama),
+                "phi2" => quantized_phi::ModelWeights::from_gguf(content, &mut cursor, &self.device)
+                    .map(CausalLM::QuantizedPhi),
+                other => return Err(LuaError::RuntimeError(
+                    format!("unsupported GGUF architecture: {:?} (expected llama or phi2)", other)
+                )),
+            }
+        } else {
+            let config_json = self.config_json()?;
+            eos_token_ids.extend(eos_token_ids_from_config(&config_json));
+            let config = self.config.as_deref().unwrap_or_default();
+            let vb = VarBuilder::from_buffered_safetensors(model_bytes, DTYPE, &self.device)
+                .map_err(LuaError::external)?;
+            match config_json.get("model_type").and_then(|m| m.as_str()) {
+                Some("llama") => {
+                    let config: llama::LlamaConfig = serde_json::from_slice(config).map_err(LuaError::external)?;
+                    let config = config.into_config(false);
+                    let cache = llama::Cache::new(true, DTYPE, &config, &self.device).map_err(LuaError::external)?;
+                    llama::Llama::load(vb, &config).map(|model| CausalLM::Llama(model, cache))
+                }
+                Some("mistral") => {
+                    let config: mistral::Config = serde_json::from_slice(config).map_err(LuaError::external)?;
+                    mistral::Model::new(&config, vb).map(CausalLM::Mistral)
+                }
+                Some("phi") => {
+                    let config: phi::Config = serde_json::from_slice(config).map_err(LuaError::external)?;
+                    phi::Model::new(&config, vb).map(CausalLM::Phi)
+                }
+                other => return Err(LuaError::RuntimeError(
+                    format!("unsupported model_type: {:?} (expected llama, mistral or phi)", other)
+                )),
+            }
+        };
+        let model = model.map_err(|err| {
+            ao_log(&format!("!! Error loading the causal LM\n{}", err));
+            LuaError::external(err)
+        })?;
+        Ok((model, eos_token_ids))
+    }
+}
+
+/// `eos_token_id` is a single id or, for recent chat models, a list of them.
+fn eos_token_ids_from_config(config: &serde_json::Value) -> Vec<u32> {
+    match config.get("eos_token_id") {
+        Some(serde_json::Value::Number(id)) => id.as_u64().map(|id| id as u32).into_iter().collect(),
+        Some(serde_json::Value::Array(ids)) => ids.iter().filter_map(|id| id.as_u64()).map(|id| id as u32).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// What `text_generation.generate` hands back to Lua.
+#[derive(serde::Serialize, Debug)]
+pub(crate) struct GenerationOutput {
+    /// The generated continuation, without the prompt.
+    pub(crate) text: String,
+    prompt_tokens: usize,
+    /// Prompt tokens found in the session's KV cache, which were not processed again.
+    cached_tokens: usize,
+    generated_tokens: usize,
+    /// Why decoding ended, `eos`, `length`, `stop` or `callback`.
+    finish_reason: String,
+    /// The decoded output, with `json_schema` or `json`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    json: Option<Value>,
+    /// The generated tokens with their log-probabilities, with `logprobs` or `top_logprobs`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logprobs: Option<Vec<TokenLogprob>>,
+    seed: u64,
+    model: &'static str,
+    elapsed: f64,
+    tokens_per_second: f64,
+}
+
+/// A model ready to generate, with its tokenizer and the ids which end generation.
+#[derive(Clone)]
+pub(crate) struct LoadedModel {
+    pub(crate) model: CausalLM,
+    pub(crate) tokenizer: Tokenizer,
+    eos_token_ids: HashSet<u32>,
+}
+
+impl LoadedModel {
+    pub(crate) fn load(args: &mut Args) -> LuaResult<Self> {
+        let (model, mut eos_token_ids) = args.build_model()?;
+        if args.tokenizer.is_empty() {
+            return Err(LuaError::RuntimeError("tokenizer is required".to_string()));
+        }
+        let tokenizer = Tokenizer::from_bytes(&args.tokenizer).map_err(LuaError::external)?;
+        if eos_token_ids.is_empty() {
+            eos_token_ids.extend(EOS_TOKENS.iter().filter_map(|token| tokenizer.token_to_id(token)));
+        }
+        Ok(LoadedModel { model, tokenizer, eos_token_ids })
+    }
+
+    pub(crate) fn encode(&self, args: &Args) -> LuaResult<Vec<u32>> {
+        let prompt_tokens = self.tokenizer.encode(args.prompt.as_str(), args.add_special_tokens)
+            .map_err(LuaError::external)?
+            .get_ids()
+            .to_vec();
+        if prompt_tokens.is_empty() {
+            return Err(LuaError::RuntimeError("the prompt is empty".to_string()));
+        }
+        Ok(prompt_tokens)
+    }
+}
+
+fn generate_text<'lua>(lua: &'lua Lua, mut args: Args, stream: TokenStream<'lua>) -> LuaResult<GenerationOutput> {
+    let mut loaded = LoadedModel::load(&mut args)?;
+    let prompt_tokens = loaded.encode(&args)?;
+    let (output, _) = decode(lua, &args, &mut loaded, prompt_tokens, 0, stream)?;
+    Ok(output)
+}
+
+/// The decoding loop.  The first `cached` prompt tokens must already be in the model's KV
+/// cache, from an earlier call on the same session.  Also returns the tokens which are in
+/// the KV cache afterwards, everything but the last sampled token.
+pub(crate) fn decode<'lua>(
+    lua: &'lua Lua,
+    args: &Args,
+    loaded: &mut LoadedModel,
+    prompt_tokens: Vec<u32>,
+    cached: usize,
+    mut stream: TokenStream<'lua>,
+) -> LuaResult<(GenerationOutput, Vec<u32>)> {
+    let LoadedModel { model, tokenizer, eos_token_ids } = loaded;
+    let mut eos_token_ids = eos_token_ids.clone();
+    if let Some(eos_token) = &args.eos_token {
+        eos_token_ids.extend(tokenizer.token_to_id(eos_token));
+    }
+    let mut constraint = args.constraint.clone();
+    let mut logprobs = (args.logprobs || args.top_logprobs > 0).then(Vec::new);
+    let eos_token_list: Vec<u32> = eos_token_ids.iter().copied().collect();
+
+    let mut logits_processor = args.logits.logits_processor(args.seed);
+
+    let start = std::time::Instant::now();
+    let prefix_len = prompt_tokens.len();
+    let mut tokens = prompt_tokens;
+    let mut index_pos = cached;
+    let mut finish_reason = "length";
+    let mut stop_at: Option<usize> = None;
+    for index in 0..args.max_new_tokens {
+        // After the prompt, only the newest token is fed, the rest is in the KV cache.
+        let context = if index == 0 { &tokens[cached..] } else { &tokens[tokens.len() - 1..] };
+        let input_ids = Tensor::new(context, &args.device)
+            .map_err(LuaError::external)?
+            .unsqueeze(0)
+            .map_err(LuaError::external)?;
+        let logits = model.forward(&input_ids, index_pos).map_err(LuaError::external)?;
+        index_pos += context.len();
+
+        let logits = args.logits.process(logits, &tokens, prefix_len, &eos_token_list)?;
+        let logits = match &constraint {
+            Some(constraint) => constraint.mask(&logits, tokenizer, &tokens, &eos_token_list)?,
+            None => logits,
+        };
+
+        let next_token = logits_processor.sample(&logits).map_err(LuaError::external)?;
+        if eos_token_ids.contains(&next_token) {
+            finish_reason = "eos";
+            break;
+        }
+        if let Some(constraint) = &mut constraint {
+            constraint.advance(tokenizer, &tokens, next_token)?;
+        }
+        if let Some(logprobs) = &mut logprobs {
+            logprobs.push(TokenLogprob::from_logits(&logits, tokenizer, next_token, args.top_logprobs)?);
+        }
+        tokens.push(next_token);
+        if !args.stop.is_empty() {
+            let text = tokenizer.decode(&tokens[prefix_len..], true).map_err(LuaError::external)?;
+            if let Some(at) = find_stop(&text, &args.stop) {
+                stop_at = Some(at);
+                finish_reason = "stop";
+            }
+        }
+        if stream.is_active() {
+            let logprob = candle_nn::ops::log_softmax(&logits, candle_core::D::Minus1)
+                .and_then(|logprobs| logprobs.get(next_token as usize))
+                .and_then(|logprob| logprob.to_scalar::<f32>())
+                .map_err(LuaError::external)?;
+            let go_on = stream.push(lua, tokenizer, &tokens[prefix_len..], logprob, &args.stop)?;
+            if !go_on && stop_at.is_none() {
+                finish_reason = "callback";
+                break;
+            }
+        }
+        if stop_at.is_some() {
+            break;
+        }
+        if constraint.as_ref().map_or(false, |constraint| constraint.is_done()) {
+            finish_reason = "eos";
+            break;
+        }
+    }
+    let dt = start.elapsed();
+    let generated = &tokens[prefix_len..];
+    let mut text = tokenizer.decode(generated, true).map_err(LuaError::external)?;
+    if let Some(at) = stop_at {
+        text.truncate(at);
+    }
+    stream.finish(lua, &text)?;
+    ao_log(&format!(
+        "{} tokens generated by {} ({:.2} token/s)",
+        generated.len(),
+        model.name(),
+        generated.len() as f64 / dt.as_secs_f64(),
+    ));
+    let json = constraint.as_ref().and_then(|constraint| constraint.parse(&text));
+    let output = GenerationOutput {
+        text,
+        prompt_tokens: prefix_len,
+        cached_tokens: cached,
+        generated_tokens: generated.len(),
+        finish_reason: finish_reason.to_string(),
+        json,
+        logprobs,
+        seed: args.seed,
+        model: model.name(),
+        elapsed: dt.as_secs_f64(),
+        tokens_per_second: generated.len() as f64 / dt.as_secs_f64(),
+    };
+    tokens.truncate(index_pos);
+    Ok((output, tokens))
+}
+
+/// Lua entry point, `text_generation.generate(opts)`.
+///
+/// Continues `opts.prompt` with a LLaMA, Mistral or Phi model.  `opts.model` is either
+/// safetensors, with the model's `config.json` as `opts.config`, or a quantized GGUF file
+/// (`llama` architecture, which covers Mistral, or `phi2`).  `opts.tokenizer` is always the
+/// `tokenizer.json`.
+///
+/// Takes the `t5.generate` sampling options: `temperature`, `top_k`, `top_p`, `min_p`,
+/// `typical_p`, `repeat_penalty`, `repeat_last_n`, `frequency_penalty`, `presence_penalty`,
+/// `logit_bias`, `banned_token_ids`, `no_repeat_ngram_size`, `min_new_tokens`,
+/// `max_new_tokens` (default 256), `stop`, `seed` and `on_token`.  Returns
+/// `{ text, prompt_tokens, generated_tokens, finish_reason, seed, model, elapsed,
+/// tokens_per_second }`.
+///
+/// `json_schema` (a table or JSON text) restricts the output to JSON matching the schema,
+/// `json = true` to any JSON value and `choices` to one of a list of strings.  With JSON,
+/// the decoded output is also returned as `json`, a Lua table.  There is no general grammar
+/// option, `choices` is the only constraint besides JSON.
+///
+/// `logprobs = true` returns the generated tokens as `logprobs`, `{ id, token, logprob }`
+/// under the same distribution as `on_token`; `top_logprobs = n` adds the `n` most likely
+/// tokens at each position as `top`.
+pub fn generate(lua: &Lua, table: LuaTable) -> LuaResult<LuaValue> {
+    let args = Args::from_table(lua, &table)?;
+    let stream = TokenStream::new(table.get("on_token")?);
+    let output = generate_text(lua, args, stream)?;
+    lua.to_value(&output)
+}
+
+/// The log-probability of `text` after `prompt`, or of all of `text` but its first token
+/// without one.  `model` must have an empty KV cache.
+fn score_text(
+    args: &Args,
+    mut model: CausalLM,
+    tokenizer: &Tokenizer,
+    prompt: Option<&str>,
+    text: &str,
+) -> LuaResult<ScoreOutput> {
+    let encode = |text: &str, add_special_tokens: bool| -> LuaResult<Vec<u32>> {
+        Ok(tokenizer.encode(text, add_special_tokens).map_err(LuaError::external)?.get_ids().to_vec())
+    };
+    let (context, target) = match prompt {
+        Some(prompt) => {
+            let context = encode(prompt, args.add_special_tokens)?;
+            // Tokenized together, so the boundary tokenizes as it would in a generation.
+            let full = encode(&format!("{}{}", prompt, text), args.add_special_tokens)?;
+            let target = if full.len() > context.len() && full.starts_with(&context) {
+                full[context.len()..].to_vec()
+            } else {
+                encode(text, false)?
+            };
+            (context, target)
+        }
+        None => {
+            let mut tokens = encode(text, args.add_special_tokens)?;
+            let target = tokens.split_off(1.min(tokens.len()));
+            (tokens, target)
+        }
+    };
+    if context.is_empty() || target.is_empty() {
+        return Err(LuaError::RuntimeError("nothing to score, the text is too short".to_string()));
+    }
+    let mut scored = Vec::with_capacity(target.len());
+    let mut input = context;
+    let mut index_pos = 0;
+    for &id in &target {
+        let input_ids = Tensor::new(&input[..], &args.device)
+            .and_then(|input_ids| input_ids.unsqueeze(0))
+            .map_err(LuaError::external)?;
+        let logits = model.forward(&input_ids, index_pos).map_err(LuaError::external)?;
+        index_pos += input.len();
+        scored.push(TokenLogprob::from_logits(&logits, tokenizer, id, args.top_logprobs)?);
+        input = vec![id];
+    }
+    Ok(ScoreOutput::new(scored))
+}
+
+/// Lua entry point, `text_generation.score(opts)`.
+///
+/// Scores `opts.continuation` after `opts.prompt` instead of generating it, or all of
+/// `opts.text` (every token but the first), or each `{ prompt, continuation }` of
+/// `opts.pairs`, where a lone string is scored as a `text`.  Returns
+/// `{ total_logprob, mean_logprob, perplexity, tokens = { { id, token, logprob }, ... } }`,
+/// or a list of them for `pairs`.  `top_logprobs` adds the most likely tokens at each
+/// position as `top`.  Takes the model options of `generate`.
+pub fn score(lua: &Lua, table: LuaTable) -> LuaResult<LuaValue> {
+    let (pairs, is_list) = match table.get::<_, Option<String>>("text")? {
+        Some(text) => (vec![(None, text)], false),
+        None => score_pairs(&table, "prompt", "continuation")?,
+    };
+    let mut args = Args::from_table(lua, &table)?;
+    let loaded = LoadedModel::load(&mut args)?;
+    let start = std::time::Instant::now();
+    let mut outputs = Vec::with_capacity(pairs.len());
+    for (prompt, text) in &pairs {
+        // Each text starts from an empty KV cache.
+        outputs.push(score_text(&args, loaded.model.clone(), &loaded.tokenizer, prompt.as_deref(), text)?);
+    }
+    ao_log(&format!("{} texts scored by {} in {:.2}s", pairs.len(), loaded.model.name(), start.elapsed().as_secs_f64()));
+    if is_list {
+        lua.to_value(&outputs)
+    } else {
+        lua.to_value(&outputs[0])
+    }
+}
+
+/// Lua entry point, `text_generation.apply_chat_template(messages, opts)`.
+///
+/// Renders `messages`, a list of `{ role, content }` tables, with the `chat_template` of
+/// `opts.tokenizer_config` and returns the prompt.  `opts.add_generation_prompt` (default
+/// true) ends it with the assistant's turn header, `opts.template` picks a named template
+/// and `opts.context` passes extra template variables such as `tools`.
+pub fn apply_chat_template(lua: &Lua, (messages, opts): (LuaValue, LuaTable)) -> LuaResult<String> {
+    let (prompt, _) = chat_template::render_from_table(lua, messages, &opts)?;
+    Ok(prompt)
+}
+
+/// One turn of a session: appends `messages` to its history, generates the reply over the
+/// whole conversation, reusing the KV cache for the part of it processed before.
+fn chat_turn<'lua>(
+    lua: &'lua Lua,
+    session: &mut Session,
+    mut args: Args,
+    messages: Vec<Value>,
+    opts: &LuaTable<'lua>,
+) -> LuaResult<GenerationOutput> {
+    let history_len = session.messages.len();
+    session.messages.extend(messages);
+    let turn = (|| {
+        let history = Value::Array(session.messages.clone());
+        args.prompt = session.template.render_with_options(lua, &history, opts)?;
+        args.add_special_tokens = opts.get::<_, Option<bool>>("add_special_tokens")?.unwrap_or(false);
+        args.eos_token = session.template.eos_token.clone();
+        let prompt_tokens = session.model.encode(&args)?;
+        let cached = session.reusable_prefix(&prompt_tokens);
+        let stream = TokenStream::new(opts.get("on_token")?);
+        decode(lua, &args, &mut session.model, prompt_tokens, cached, stream)
+    })();
+    match turn {
+        Ok((output, tokens)) => {
+            session.messages.push(serde_json::json!({ "role": "assistant", "content": output.text.clone() }));
+            if opts.get::<_, Option<bool>>("keep_cache")?.unwrap_or(true) {
+                session.cached_tokens = tokens;
+            } else {
+                session.clear_cache();
+            }
+            session.last_used = message_timestamp();
+            Ok(output)
+        }
+        Err(err) => {
+            // The KV cache may hold part of the failed turn.
+            session.messages.truncate(history_len);
+            session.clear_cache();
+            Err(err)
+        }
+    }
+}
+
+/// `chat` with `opts.session`.  The session is created on first use, from `model`,
+/// `tokenizer` and `tokenizer_config`, or from the model of another session with the same
+/// `model_id`; later turns only need the new messages.
+fn chat_session(lua: &Lua, name: String, messages: LuaValue, opts: LuaTable) -> LuaResult<LuaValue> {
+    let messages = chat_template::messages_from_lua(lua, messages)?;
+    // The weights are only read when no session or shared model has them loaded already.
+    let mut args = Args::from_options(lua, &opts)?;
+    let mut session = match sessions::take(&name) {
+        Some(session) => session,
+        None => {
+            let model_id: Option<String> = opts.get("model_id")?;
+            let model = match model_id.as_deref().and_then(sessions::find_model) {
+                Some(model) => model,
+                None => {
+                    args.load_weights(&opts)?;
+                    LoadedModel::load(&mut args)?
+                }
+            };
+            Session::new(model_id, model, ChatTemplate::from_table(&opts)?)
+        }
+    };
+    if opts.get::<_, Option<bool>>("reset")?.unwrap_or(false) {
+        session.messages.clear();
+        session.clear_cache();
+    }
+    let output = chat_turn(lua, &mut session, args, messages, &opts);
+    sessions::put(name, session);
+    lua.to_value(&output?)
+}
+
+/// Lua entry point, `text_generation.chat(messages, opts)`.
+///
+/// `generate` on the prompt rendered by `apply_chat_template`.  Special tokens come from the
+/// template, so the tokenizer doesn't add its own, and the template's `eos_token` also ends
+/// generation.
+///
+/// With `opts.session = name` the conversation is kept in the process: `messages` are only
+/// the new ones, the reply is appended to the history, and the model's KV cache is kept
+/// (unless `keep_cache = false`) so the next turn skips the tokens it already processed.
+/// `reset = true` starts the conversation over.  See `text_generation.sessions` to list,
+/// reset, delete and expire sessions and to set their limits.
+pub fn chat(lua: &Lua, (messages, opts): (LuaValue, LuaTable)) -> LuaResult<LuaValue> {
+    if let Some(name) = opts.get::<_, Option<String>>("session")? {
+        return chat_session(lua, name, messages, opts);
+    }
+    let (prompt, template) = chat_template::render_from_table(lua, messages, &opts)?;
+    let mut args = Args::from_table(lua, &opts)?;
+    args.prompt = prompt;
+    args.add_special_tokens = opts.get::<_, Option<bool>>("add_special_tokens")?.unwrap_or(false);
+    args.eos_token = template.eos_token;
+    let stream = TokenStream::new(opts.get("on_token")?);
+    let output = generate_text(lua, args, stream)?;
+    lua.to_value(&output)
+}
+
+pub fn preload(lua: &Lua) -> LuaResult<()> {
+    let package: LuaTable = lua.globals().get("package")?;
+    let loaded: LuaTable = package.get("loaded")?;
+    let text_generation_module_table = lua.create_table()?;
+    text_generation_module_table.set("generate", lua.create_function(generate)?)?;
+    text_generation_module_table.set("chat", lua.create_function(chat)?)?;
+    text_generation_module_table.set("score", lua.create_function(score)?)?;
+    text_generation_module_table.set("apply_chat_template", lua.create_function(apply_chat_template)?)?;
+    text_generation_module_table.set("sessions", sessions::create_table(lua)?)?;
+    loaded.set("text_generation", text_generation_module_table)?;
+    Ok(())
+}
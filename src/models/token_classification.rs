@@ -0,0 +1,373 @@
+use mlua::prelude::*;
+use candle_core::{Tensor, D};
+use candle_nn::Module;
+use candle_transformers::models::bert::BertModel;
+use tokenizers::Encoding;
+
+use crate::models::bert::Args;
+use crate::ao_log;
+
+
+/// How sub-word predictions are merged into entities, mirroring the strategies of the
+/// Transformers `token-classification` pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AggregationStrategy {
+    /// One result per token, no grouping.
+    None,
+    /// Group adjacent tokens sharing the same entity tag (B-/I- aware).
+    Simple,
+    /// Label each word with its first sub-token's prediction, then group.
+    First,
+    /// Label each word with its highest scoring sub-token's prediction, then group.
+    Max,
+}
+
+impl AggregationStrategy {
+    fn from_str(s: &str) -> LuaResult<Self> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(AggregationStrategy::None),
+            "simple" => Ok(AggregationStrategy::Simple),
+            "first" => Ok(AggregationStrategy::First),
+            "max" => Ok(AggregationStrategy::Max),
+            _ => Err(LuaError::RuntimeError(
+                format!("invalid aggregation_strategy: {} (expected none, simple, first or max)", s)
+            )),
+        }
+    }
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+struct Entity {
+    label: String,
+    score: f32,
+    word: String,
+    /// Character offset of the first character of the entity.
+    start: usize,
+    /// Character offset just past the last character of the entity.
+    end: usize,
+    /// Token index, only set when no aggregation is done.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    index: Option<usize>,
+}
+
+/// A single (non-special) token, or a whole word for the `first`/`max` strategies.
+struct Prediction {
+    label_id: usize,
+    score: f32,
+    start: usize,
+    end: usize,
+}
+
+/// Reads `id2label` from `config.json` into a dense, id-ordered vector of labels.
+fn id2label(config: &serde_json::Value) -> LuaResult<Vec<String>> {
+    let map = config.get("id2label")
+        .and_then(|m| m.as_object())
+        .ok_or_else(|| LuaError::RuntimeError("config.json has no id2label mapping".to_string()))?;
+    let mut labels = vec![String::new(); map.len()];
+    for (id, label) in map {
+        let id: usize = id.parse().map_err(LuaError::external)?;
+        let label = label.as_str()
+            .ok_or_else(|| LuaError::RuntimeError(format!("id2label entry {} is not a string", id)))?;
+        if id >= labels.len() {
+            return Err(LuaError::RuntimeError(format!("id2label has a gap, id {} is out of range", id)));
+        }
+        labels[id] = label.to_string();
+    }
+    Ok(labels)
+}
+
+/// Splits `B-PER` into (`true`, `PER`), `I-PER` into (`false`, `PER`) and `PER` into (`false`, `PER`).
+fn split_tag(label: &str) -> (bool, &str) {
+    if let Some(tag) = label.strip_prefix("B-") {
+        (true, tag)
+    } else if let Some(tag) = label.strip_prefix("I-") {
+        (false, tag)
+    } else {
+        (false, label)
+    }
+}
+
+fn substring(text: &str, start: usize, end: usize) -> String {
+    text.chars().skip(start).take(end.saturating_sub(start)).collect()
+}
+
+fn argmax(probs: &[f32]) -> (usize, f32) {
+    probs.iter()
+        .enumerate()
+        .fold((0, f32::MIN), |(best_i, best), (i, &p)| if p > best { (i, p) } else { (best_i, best) })
+}
+
+/// Collapses per-token predictions into per-word predictions using the encoding's word ids.
+fn aggregate_words(
+    encoding: &Encoding,
+    probs: &[Vec<f32>],
+    strategy: AggregationStrategy,
+) -> Vec<Prediction> {
+    let mut words: Vec<Prediction> = Vec::new();
+    let mut current_word: Option<u32> = None;
+    for (index, token_probs) in probs.iter().enumerate() {
+        if encoding.get_special_tokens_mask()[index] == 1 {
+            current_word = None;
+            continue;
+        }
+        let (start, end) = encoding.get_offsets()[index];
+        let (label_id, score) = argmax(token_probs);
+        let word_id = encoding.get_word_ids()[index];
+        match (word_id, current_word, words.last_mut()) {
+            (Some(id), Some(current), Some(word)) if id == current => {
+                word.end = end;
+                if strategy == AggregationStrategy::Max && score > word.score {
+                    word.label_id = label_id;
+                    word.score = score;
+                }
+            }
+            _ => {
+                words.push(Prediction { label_id, score, start, end });
+                current_word = word_id;
+            }
+        }
+    }
+    words
+}
+
+/// Groups adjacent predictions which share an entity tag, averaging their scores.
+fn group_entities(text: &str, predictions: Vec<Prediction>, labels: &[String]) -> Vec<Entity> {
+    let mut entities: Vec<Entity> = Vec::new();
+    let mut scores: Vec<f32> = Vec::new();
+    let flush = |entities: &mut Vec<Entity>, scores: &mut Vec<f32>| {
+        if let Some(entity) = entities.last_mut() {
+            if !scores.is_empty() {
+                entity.score = scores.iter().sum::<f32>() / scores.len() as f32;
+                entity.word = substring(text, entity.start, entity.end);
+            }
+        }
+        scores.clear();
+    };
+    let mut previous_tag: Option<String> = None;
+    for prediction in predictions {
+        let (is_begin, tag) = split_tag(&labels[prediction.label_id]);
+        let continues = !is_begin && previous_tag.as_deref() == Some(tag);
+        if continues {
+            if let Some(entity) = entities.last_mut() {
+                entity.end = prediction.end;
+            }
+        } else {
+            flush(&mut entities, &mut scores);
+            entities.push(Entity {
+                label: tag.to_string(),
+                score: prediction.score,
+                word: String::new(),
+                start: prediction.start,
+                end: prediction.end,
+                index: None,
+            });
+        }
+        scores.push(prediction.score);
+        previous_tag = Some(tag.to_string());
+    }
+    flush(&mut entities, &mut scores);
+    entities
+}
+
+/// Drops entities which are tagged as outside of any entity.
+fn without_outside(entities: Vec<Entity>) -> Vec<Entity> {
+    entities.into_iter().filter(|e| e.label != "O").collect()
+}
+
+fn classify(args: &Args, strategy: AggregationStrategy) -> LuaResult<Vec<Entity>> {
+    let mut tokenizer = args.build_tokenizer()?;
+    let tokenizer = tokenizer
+        .with_padding(None)
+        .with_truncation(None)
+        .map_err(LuaError::external)?;
+    let encoding = tokenizer.encode_char_offsets(&*args.prompt, true)
+        .map_err(LuaError::external)?;
+    let max_positions = args.max_positions()?;
+    if encoding.len() > max_positions {
+        return Err(LuaError::RuntimeError(format!(
+            "the prompt is {} tokens but the model accepts at most {}",
+            encoding.len(),
+            max_positions
+        )));
+    }
+
+    let config = args.build_config()?;
+    let labels = id2label(&args.config_json()?)?;
+    let vb = args.build_var_builder()?;
+    let model = BertModel::load(vb.clone(), &config)
+        .map_err(|err| {
+            ao_log(&format!("!! Error on BertModel::load()\n{}", err));
+            LuaError::external(err)
+        })?;
+    let classifier = candle_nn::linear(config.hidden_size, labels.len(), vb.pp("classifier"))
+        .map_err(|err| {
+            ao_log(&format!("!! Error loading the token classification head\n{}", err));
+            LuaError::external(err)
+        })?;
+
+    let token_ids = Tensor::new(encoding.get_ids(), &args.device)
+        .map_err(LuaError::external)?
+        .unsqueeze(0)
+        .map_err(LuaError::external)?;
+    let token_type_ids = token_ids.zeros_like().map_err(LuaError::external)?;
+    let hidden_states = model.forward(&token_ids, &token_type_ids)
+        .map_err(|err| {
+            ao_log(&format!("!! Error on model.forward\n {}", err));
+            LuaError::external(err)
+        })?;
+    let logits = classifier.forward(&hidden_states).map_err(LuaError::external)?;
+    let probs: Vec<Vec<f32>> = candle_nn::ops::softmax(&logits, D::Minus1)
+        .map_err(LuaError::external)?
+        .squeeze(0)
+        .map_err(LuaError::external)?
+        .to_vec2()
+        .map_err(LuaError::external)?;
+
+    let text = args.prompt.as_str();
+    let entities = match strategy {
+        AggregationStrategy::None => {
+            probs.iter()
+                .enumerate()
+                .filter(|(index, _)| encoding.get_special_tokens_mask()[*index] == 0)
+                .map(|(index, token_probs)| {
+                    let (label_id, score) = argmax(token_probs);
+                    let (start, end) = encoding.get_offsets()[index];
+                    Entity {
+                        label: labels[label_id].clone(),
+                        score,
+                        word: substring(text, start, end),
+                        start,
+                        end,
+                        index: Some(index),
+                    }
+                })
+                .collect()
+        }
+        AggregationStrategy::Simple => {
+            let tokens = probs.iter()
+                .enumerate()
+                .filter(|(index, _)| encoding.get_special_tokens_mask()[*index] == 0)
+                .map(|(index, token_probs)| {
+                    let (label_id, score) = argmax(token_probs);
+                    let (start, end) = encoding.get_offsets()[index];
+                    Prediction { label_id, score, start, end }
+                })
+                .collect();
+            group_entities(text, tokens, &labels)
+        }
+        AggregationStrategy::First | AggregationStrategy::Max => {
+            let words = aggregate_words(&encoding, &probs, strategy);
+            group_entities(text, words, &labels)
+        }
+    };
+    Ok(without_outside(entities))
+}
+
+/// Lua entry point, `bert.token_classification(opts)`.
+///
+/// Besides the usual `model`, `config` and `tokenizer`, `opts.prompt` is the text to tag
+/// and `opts.aggregation_strategy` one of `none`, `simple` (default), `first` or `max`.
+/// Returns a list of `{ label, score, word, start, end }` tables, offsets being characters.
+pub fn token_classification(lua: &Lua, table: LuaTable) -> LuaResult<LuaValue> {
+    let args = Args::from_table(&table)?;
+    let strategy: String = table.get("aggregation_strategy").unwrap_or(String::from("simple"));
+    let strategy = AggregationStrategy::from_str(&strategy)?;
+    let entities = classify(&args, strategy)?;
+    lua.to_value(&entities)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LABELS: [&str; 5] = ["O", "B-PER", "I-PER", "B-LOC", "I-LOC"];
+
+    fn labels() -> Vec<String> {
+        LABELS.iter().map(|label| label.to_string()).collect()
+    }
+
+    fn prediction(label_id: usize, score: f32, start: usize, end: usize) -> Prediction {
+        Prediction { label_id, score, start, end }
+    }
+
+    /// `[CLS] Jo ##hn lives [SEP]` over "John lives".
+    fn encoding() -> Encoding {
+        Encoding::new(
+            vec![101, 1, 2, 3, 102],
+            vec![0; 5],
+            ["[CLS]", "Jo", "##hn", "lives", "[SEP]"].iter().map(|t| t.to_string()).collect(),
+            vec![None, Some(0), Some(0), Some(1), None],
+            vec![(0, 0), (0, 2), (2, 4), (5, 10), (0, 0)],
+            vec![1, 0, 0, 0, 1],
+            vec![1; 5],
+            Vec::new(),
+            Default::default(),
+        )
+    }
+
+    fn probs() -> Vec<Vec<f32>> {
+        vec![
+            vec![1., 0., 0., 0., 0.],
+            vec![0.1, 0.8, 0.1, 0., 0.],
+            vec![0.05, 0.05, 0.9, 0., 0.],
+            vec![0.9, 0., 0., 0.1, 0.],
+            vec![1., 0., 0., 0., 0.],
+        ]
+    }
+
+    #[test]
+    fn test_split_tag() {
+        assert_eq!(split_tag("B-PER"), (true, "PER"));
+        assert_eq!(split_tag("I-PER"), (false, "PER"));
+        assert_eq!(split_tag("O"), (false, "O"));
+    }
+
+    #[test]
+    fn test_aggregate_words() {
+        let words = aggregate_words(&encoding(), &probs(), AggregationStrategy::First);
+        let words: Vec<_> = words.iter().map(|w| (w.label_id, w.score, w.start, w.end)).collect();
+        assert_eq!(words, vec![(1, 0.8, 0, 4), (0, 0.9, 5, 10)]);
+
+        let words = aggregate_words(&encoding(), &probs(), AggregationStrategy::Max);
+        let words: Vec<_> = words.iter().map(|w| (w.label_id, w.score, w.start, w.end)).collect();
+        assert_eq!(words, vec![(2, 0.9, 0, 4), (0, 0.9, 5, 10)]);
+    }
+
+    #[test]
+    fn test_group_entities() {
+        let text = "John lives in Paris Lyon";
+        let tokens = vec![
+            prediction(1, 0.8, 0, 2),
+            prediction(2, 0.9, 2, 4),
+            prediction(0, 0.9, 5, 10),
+            prediction(0, 0.7, 11, 13),
+            prediction(3, 0.6, 14, 19),
+            prediction(3, 0.5, 20, 24),
+        ];
+        let entities = group_entities(text, tokens, &labels());
+        let grouped: Vec<_> = entities.iter().map(|e| (e.label.as_str(), e.word.as_str(), e.start, e.end)).collect();
+        assert_eq!(grouped, vec![
+            ("PER", "John", 0, 4),
+            ("O", "lives in", 5, 13),
+            ("LOC", "Paris", 14, 19),
+            ("LOC", "Lyon", 20, 24),
+        ]);
+        assert!((entities[0].score - 0.85).abs() < 1e-6);
+        assert!((entities[1].score - 0.8).abs() < 1e-6);
+
+        let entities = without_outside(entities);
+        let labels: Vec<_> = entities.iter().map(|e| e.label.as_str()).collect();
+        assert_eq!(labels, vec!["PER", "LOC", "LOC"]);
+    }
+
+    #[test]
+    fn test_inside_tag_after_other_entity_starts_new_one() {
+        let entities = group_entities(
+            "Ada Paris",
+            vec![prediction(1, 0.9, 0, 3), prediction(4, 0.8, 4, 9)],
+            &labels(),
+        );
+        let grouped: Vec<_> = entities.iter().map(|e| (e.label.as_str(), e.word.as_str())).collect();
+        assert_eq!(grouped, vec![("PER", "Ada"), ("LOC", "Paris")]);
+    }
+}
@@ -0,0 +1,218 @@
+use mlua::prelude::*;
+use mlua::{UserData, UserDataMethods};
+use tokenizers::{Encoding, PaddingParams, PaddingStrategy, Tokenizer, TruncationParams};
+
+use crate::models::common::load_bytes;
+
+
+/// A `tokenizer.json` loaded once and kept in the Lua state as userdata, so handlers can count,
+/// encode and decode tokens without loading a model.
+pub struct LuaTokenizer {
+    tokenizer: Tokenizer,
+    /// The padding of `tokenizer.json`, or one with the usual pad token when it has none.
+    /// Each call replaces the tokenizer's padding, so it is read once at load.
+    padding: PaddingParams,
+}
+
+#[derive(serde::Serialize, Debug)]
+struct EncodingOutput {
+    ids: Vec<u32>,
+    tokens: Vec<String>,
+    /// Character offsets of each token into the input text, as two-element lists.
+    offsets: Vec<(usize, usize)>,
+    attention_mask: Vec<u32>,
+    special_tokens_mask: Vec<u32>,
+    type_ids: Vec<u32>,
+    /// The windows cut off by `max_length`, each overlapping the previous one by `stride`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    overflowing: Vec<EncodingOutput>,
+}
+
+impl From<&Encoding> for EncodingOutput {
+    fn from(encoding: &Encoding) -> Self {
+        EncodingOutput {
+            ids: encoding.get_ids().to_vec(),
+            tokens: encoding.get_tokens().to_vec(),
+            offsets: encoding.get_offsets().to_vec(),
+            attention_mask: encoding.get_attention_mask().to_vec(),
+            special_tokens_mask: encoding.get_special_tokens_mask().to_vec(),
+            type_ids: encoding.get_type_ids().to_vec(),
+            overflowing: encoding.get_overflowing().iter().map(EncodingOutput::from).collect(),
+        }
+    }
+}
+
+/// Options shared by `encode` and `encode_batch`.
+struct EncodeOptions {
+    add_special_tokens: bool,
+    /// Truncate each sequence to this many tokens, special tokens included.
+    max_length: Option<usize>,
+    /// Tokens shared between overflowing windows when truncating.
+    stride: usize,
+    /// `"longest"` pads to the longest sequence in the batch, a number pads to that length.
+    padding: Option<PaddingStrategy>,
+    pad_to_multiple_of: Option<usize>,
+}
+
+impl EncodeOptions {
+    fn from_table(table: Option<LuaTable>) -> LuaResult<Self> {
+        let table = match table {
+            Some(table) => table,
+            None => return Ok(EncodeOptions {
+                add_special_tokens: true,
+                max_length: None,
+                stride: 0,
+                padding: None,
+                pad_to_multiple_of: None,
+            }),
+        };
+        let padding = match table.get::<_, LuaValue>("padding")? {
+            LuaValue::Nil | LuaValue::Boolean(false) => None,
+            LuaValue::Boolean(true) => Some(PaddingStrategy::BatchLongest),
+            LuaValue::String(s) if s.to_str()? == "longest" => Some(PaddingStrategy::BatchLongest),
+            LuaValue::Integer(n) if n >= 0 => Some(PaddingStrategy::Fixed(n as usize)),
+            LuaValue::Number(n) if n >= 0. && n.fract() == 0. => Some(PaddingStrategy::Fixed(n as usize)),
+            other => return Err(LuaError::RuntimeError(
+                format!("invalid padding: {:?} (expected \"longest\", true or a non-negative length)", other)
+            )),
+        };
+        Ok(EncodeOptions {
+            add_special_tokens: table.get::<_, Option<bool>>("add_special_tokens")?.unwrap_or(true),
+            max_length: table.get("max_length")?,
+            stride: table.get::<_, Option<usize>>("stride")?.unwrap_or(0),
+            padding,
+            pad_to_multiple_of: table.get("pad_to_multiple_of")?,
+        })
+    }
+}
+
+impl LuaTokenizer {
+    pub fn from_bytes(bytes: &[u8]) -> LuaResult<Self> {
+        let tokenizer = Tokenizer::from_bytes(bytes).map_err(LuaError::external)?;
+        let padding = match tokenizer.get_padding() {
+            Some(padding) => padding.clone(),
+            None => {
+                // No padding configured, fall back to the usual pad token names.
+                let (pad_id, pad_token) = ["[PAD]", "<pad>", "<|endoftext|>"]
+                    .iter()
+                    .find_map(|token| tokenizer.token_to_id(token).map(|id| (id, token.to_string())))
+                    .unwrap_or((0, String::from("[PAD]")));
+                PaddingParams { pad_id, pad_token, ..Default::default() }
+            }
+        };
+        Ok(LuaTokenizer { tokenizer, padding })
+    }
+
+    /// Applies (or clears) truncation and padding for one call.
+    fn configure(&mut self, options: &EncodeOptions) -> LuaResult<()> {
+        if let Some(max_length) = options.max_length {
+            // tokenizers underflows or panics unless the window leaves room for new tokens.
+            self.tokenizer
+                .with_truncation(None)
+                .map_err(LuaError::external)?
+                .with_padding(None);
+            let n_special = self.tokenizer.encode("", options.add_special_tokens)
+                .map_err(LuaError::external)?
+                .len();
+            if max_length <= n_special || options.stride >= max_length - n_special {
+                return Err(LuaError::RuntimeError(format!(
+                    "max_length ({}) must be larger than the {} special tokens plus the stride ({})",
+                    max_length, n_special, options.stride
+                )));
+            }
+        }
+        let truncation = options.max_length.map(|max_length| TruncationParams {
+            max_length,
+            stride: options.stride,
+            ..Default::default()
+        });
+        let padding = options.padding.clone().map(|strategy| PaddingParams {
+            strategy,
+            pad_to_multiple_of: options.pad_to_multiple_of,
+            ..self.padding.clone()
+        });
+        self.tokenizer
+            .with_truncation(truncation)
+            .map_err(LuaError::external)?
+            .with_padding(padding);
+        Ok(())
+    }
+
+    fn encode(&mut self, text: String, options: &EncodeOptions) -> LuaResult<Encoding> {
+        self.configure(options)?;
+        self.tokenizer.encode_char_offsets(text, options.add_special_tokens)
+            .map_err(LuaError::external)
+    }
+
+    fn encode_batch(&mut self, texts: Vec<String>, options: &EncodeOptions) -> LuaResult<Vec<Encoding>> {
+        self.configure(options)?;
+        self.tokenizer.encode_batch_char_offsets(texts, options.add_special_tokens)
+            .map_err(LuaError::external)
+    }
+}
+
+impl UserData for LuaTokenizer {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        // tok:encode(text, opts?) -> { ids, tokens, offsets, attention_mask, ... }
+        methods.add_method_mut("encode", |lua, this, (text, opts): (String, Option<LuaTable>)| {
+            let options = EncodeOptions::from_table(opts)?;
+            let encoding = this.encode(text, &options)?;
+            lua.to_value(&EncodingOutput::from(&encoding))
+        });
+        // tok:encode_batch({ text, ... }, opts?) -> list of encodings
+        methods.add_method_mut("encode_batch", |lua, this, (texts, opts): (Vec<String>, Option<LuaTable>)| {
+            let options = EncodeOptions::from_table(opts)?;
+            let encodings = this.encode_batch(texts, &options)?;
+            let outputs: Vec<EncodingOutput> = encodings.iter().map(EncodingOutput::from).collect();
+            lua.to_value(&outputs)
+        });
+        // tok:decode(ids, skip_special_tokens?) -> text
+        methods.add_method("decode", |_, this, (ids, skip_special_tokens): (Vec<u32>, Option<bool>)| {
+            this.tokenizer.decode(&ids, skip_special_tokens.unwrap_or(true))
+                .map_err(LuaError::external)
+        });
+        // tok:count_tokens(text, add_special_tokens?) -> integer, never truncated
+        methods.add_method_mut("count_tokens", |_, this, (text, add_special_tokens): (String, Option<bool>)| {
+            let options = EncodeOptions {
+                add_special_tokens: add_special_tokens.unwrap_or(true),
+                ..EncodeOptions::from_table(None)?
+            };
+            Ok(this.encode(text, &options)?.len())
+        });
+        methods.add_method("token_to_id", |_, this, token: String| {
+            Ok(this.tokenizer.token_to_id(&token))
+        });
+        methods.add_method("id_to_token", |_, this, id: u32| {
+            Ok(this.tokenizer.id_to_token(id))
+        });
+        methods.add_method("vocab_size", |_, this, with_added_tokens: Option<bool>| {
+            Ok(this.tokenizer.get_vocab_size(with_added_tokens.unwrap_or(true)))
+        });
+        // tok:special_tokens() -> { ["[CLS]"] = 101, ... }
+        methods.add_method("special_tokens", |lua, this, ()| {
+            let special = lua.create_table()?;
+            for (id, token) in this.tokenizer.get_added_tokens_decoder() {
+                if token.special {
+                    special.set(token.content, id)?;
+                }
+            }
+            Ok(special)
+        });
+    }
+}
+
+/// `tokenizer.load(data)` accepts the `tokenizer.json` contents as raw JSON, base64 or a
+/// WeaveDrive reference, see `common::load_bytes`.
+fn load(_: &Lua, data: LuaValue) -> LuaResult<LuaTokenizer> {
+    let bytes = load_bytes(data, "tokenizer")?;
+    LuaTokenizer::from_bytes(&bytes)
+}
+
+pub fn preload(lua: &Lua) -> LuaResult<()> {
+    let package: LuaTable = lua.globals().get("package")?;
+    let loaded: LuaTable = package.get("loaded")?;
+    let tokenizer_module_table = lua.create_table()?;
+    tokenizer_module_table.set("load", lua.create_function(load)?)?;
+    loaded.set("tokenizer", tokenizer_module_table)?;
+    Ok(())
+}
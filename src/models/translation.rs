@@ -0,0 +1,417 @@
+use mlua::prelude::*;
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::marian;
+use tokenizers::Tokenizer;
+
+use crate::models::common::{load_bytes, TokenStream};
+use crate::models::logits::LogitsChain;
+use crate::models::t5;
+use crate::ao_log;
+use crate::utils::message_seed;
+
+
+/// ISO 639-1 code, ISO 639-3 code (used by multilingual Marian models) and English name.
+const LANGUAGES: &[(&str, &str, &str)] = &[
+    ("ar", "ara", "Arabic"),
+    ("bg", "bul", "Bulgarian"),
+    ("cs", "ces", "Czech"),
+    ("da", "dan", "Danish"),
+    ("de", "deu", "German"),
+    ("el", "ell", "Greek"),
+    ("en", "eng", "English"),
+    ("es", "spa", "Spanish"),
+    ("et", "est", "Estonian"),
+    ("fa", "fas", "Persian"),
+    ("fi", "fin", "Finnish"),
+    ("fr", "fra", "French"),
+    ("he", "heb", "Hebrew"),
+    ("hi", "hin", "Hindi"),
+    ("hu", "hun", "Hungarian"),
+    ("id", "ind", "Indonesian"),
+    ("it", "ita", "Italian"),
+    ("ja", "jpn", "Japanese"),
+    ("ko", "kor", "Korean"),
+    ("lt", "lit", "Lithuanian"),
+    ("lv", "lav", "Latvian"),
+    ("nl", "nld", "Dutch"),
+    ("no", "nor", "Norwegian"),
+    ("pl", "pol", "Polish"),
+    ("pt", "por", "Portuguese"),
+    ("ro", "ron", "Romanian"),
+    ("ru", "rus", "Russian"),
+    ("sk", "slk", "Slovak"),
+    ("sv", "swe", "Swedish"),
+    ("sw", "swa", "Swahili"),
+    ("th", "tha", "Thai"),
+    ("tr", "tur", "Turkish"),
+    ("uk", "ukr", "Ukrainian"),
+    ("vi", "vie", "Vietnamese"),
+    ("zh", "zho", "Chinese"),
+];
+
+/// A language given either as a code (`de`, `deu`) or an English name (`German`).
+#[derive(Debug, Clone)]
+struct Language {
+    /// As given by the caller.
+    code: String,
+    iso3: Option<&'static str>,
+    name: String,
+}
+
+impl Language {
+    fn parse(lang: &str) -> Self {
+        let lower = lang.to_lowercase();
+        match LANGUAGES.iter().find(|(iso1, iso3, name)| *iso1 == lower || *iso3 == lower || name.to_lowercase() == lower) {
+            Some((_, iso3, name)) => Language { code: lang.to_string(), iso3: Some(iso3), name: name.to_string() },
+            None => Language { code: lang.to_string(), iso3: None, name: lang.to_string() },
+        }
+    }
+
+    /// The candidate spellings of this language in tokenizer vocabularies.
+    fn codes(&self) -> Vec<String> {
+        let mut codes = vec![self.code.clone()];
+        codes.extend(self.iso3.map(str::to_string));
+        codes
+    }
+}
+
+/// Checkpoint families, which differ in how the target language is requested.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Family {
+    /// T5 and flan-t5, prompted with `translate English to German: `.
+    T5,
+    /// mT5 fine-tunes, which use a target language token as forced decoder BOS when their
+    /// vocabulary has one, and the T5 prefix otherwise.
+    Mt5,
+    /// MarianMT (opus-mt) models, trained on a language pair; multilingual ones select the
+    /// target with a `>>xxx<<` token at the start of the source text.
+    Marian,
+}
+
+impl Family {
+    fn from_str(s: &str) -> LuaResult<Self> {
+        match s.to_lowercase().as_str() {
+            "t5" | "flan-t5" | "flan_t5" => Ok(Family::T5),
+            "mt5" => Ok(Family::Mt5),
+            "marian" => Ok(Family::Marian),
+            _ => Err(LuaError::RuntimeError(
+                format!("invalid family: {} (expected t5, flan-t5, mt5 or marian)", s)
+            )),
+        }
+    }
+
+    /// Uses `model_type` from `config.json`.
+    fn from_config(config: &[u8]) -> LuaResult<Self> {
+        let config: serde_json::Value = serde_json::from_slice(config).map_err(LuaError::external)?;
+        match config.get("model_type").and_then(|m| m.as_str()) {
+            Some("marian") => Ok(Family::Marian),
+            Some("mt5") => Ok(Family::Mt5),
+            Some("t5") => Ok(Family::T5),
+            other => Err(LuaError::RuntimeError(
+                format!("unsupported model_type for translation: {:?}", other)
+            )),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Family::T5 => "t5",
+            Family::Mt5 => "mt5",
+            Family::Marian => "marian",
+        }
+    }
+}
+
+/// Finds the vocabulary token naming `lang`, in the spellings used by multilingual models.
+fn language_token(tokenizer: &Tokenizer, lang: &Language) -> Option<u32> {
+    lang.codes().iter()
+        .flat_map(|code| [format!(">>{code}<<"), format!("<2{code}>"), format!("__{code}__"), format!("<{code}>")])
+        .find_map(|token| tokenizer.token_to_id(&token))
+}
+
+#[derive(serde::Serialize, Debug)]
+struct TranslationOutput {
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    src_lang: Option<String>,
+    tgt_lang: String,
+    family: &'static str,
+    prompt_tokens: usize,
+    generated_tokens: usize,
+    finish_reason: String,
+    elapsed: f64,
+    tokens_per_second: f64,
+}
+
+/// The source text as is when the target language is a forced BOS token, otherwise with the
+/// T5 task prefix, which needs the source language.
+fn t5_prompt(
+    family: Family,
+    text: String,
+    src_lang: Option<&Language>,
+    tgt_lang: &Language,
+    forced_bos: bool,
+) -> LuaResult<String> {
+    if forced_bos {
+        return Ok(text);
+    }
+    let src_lang = src_lang.ok_or_else(|| LuaError::RuntimeError(
+        format!("src_lang is required for {} translation", family.name())
+    ))?;
+    Ok(format!("translate {} to {}: {}", src_lang.name, tgt_lang.name, text))
+}
+
+/// T5, flan-t5 and mT5 go through `t5::__t5` with a task prefix or forced BOS token.
+fn translate_t5<'lua>(
+    lua: &'lua Lua,
+    table: &LuaTable<'lua>,
+    family: Family,
+    text: String,
+    src_lang: Option<Language>,
+    tgt_lang: Language,
+    stream: TokenStream<'lua>,
+) -> LuaResult<TranslationOutput> {
+    let mut args = t5::Args::from_table(lua, table)?;
+    // Translation wants the most likely output unless sampling is asked for.
+    if table.get::<_, Option<f64>>("temperature")?.is_none() {
+        args.logits.temperature = 0.;
+    }
+    if table.get::<_, Option<f32>>("repeat_penalty")?.is_none() {
+        args.logits.repeat_penalty = 1.;
+    }
+    let forced_bos = match family {
+        Family::Mt5 => {
+            let tokenizer = Tokenizer::from_bytes(&args.tokenizer).map_err(LuaError::external)?;
+            language_token(&tokenizer, &tgt_lang)
+        }
+        _ => None,
+    };
+    args.prompt = t5_prompt(family, text, src_lang.as_ref(), &tgt_lang, forced_bos.is_some())?;
+    args.forced_bos_token_id = forced_bos.or(args.forced_bos_token_id);
+    let output = t5::__t5(lua, args, stream)?;
+    Ok(TranslationOutput {
+        text: output.text.unwrap_or_default().trim().to_string(),
+        src_lang: src_lang.map(|lang| lang.code),
+        tgt_lang: tgt_lang.code,
+        family: family.name(),
+        prompt_tokens: output.prompt_tokens,
+        generated_tokens: output.generated_tokens,
+        finish_reason: output.finish_reason.unwrap_or_default(),
+        elapsed: output.elapsed,
+        tokens_per_second: output.tokens_per_second,
+    })
+}
+
+/// MarianMT encoder-decoder, with a separate target tokenizer when the pair has one.
+fn translate_marian<'lua>(
+    lua: &'lua Lua,
+    table: &LuaTable<'lua>,
+    text: String,
+    src_lang: Option<Language>,
+    tgt_lang: Language,
+    mut stream: TokenStream<'lua>,
+) -> LuaResult<TranslationOutput> {
+    let device = Device::Cpu;
+    let config: marian::Config = serde_json::from_slice(&load_bytes(table.get("config")?, "config")?)
+        .map_err(LuaError::external)?;
+    let tokenizer = Tokenizer::from_bytes(load_bytes(table.get("tokenizer")?, "tokenizer")?)
+        .map_err(LuaError::external)?;
+    let target_tokenizer = match table.get::<_, LuaValue>("target_tokenizer")? {
+        LuaValue::Nil => None,
+        value => Some(Tokenizer::from_bytes(load_bytes(value, "target_tokenizer")?).map_err(LuaError::external)?),
+    };
+    let target_tokenizer = target_tokenizer.as_ref().unwrap_or(&tokenizer);
+    let model_bytes = load_bytes(table.get("model")?, "model")?;
+    let tensors = candle_core::safetensors::load_buffer(&model_bytes, &device)
+        .map_err(LuaError::external)?;
+    drop(model_bytes);
+    let vb = VarBuilder::from_tensors(tensors, DType::F32, &device);
+    let mut model = marian::MTModel::new(&config, vb)
+        .map_err(|err| {
+            ao_log(&format!("!! Error on MTModel::new()\n{}", err));
+            LuaError::external(err)
+        })?;
+
+    let text = match language_token(&tokenizer, &tgt_lang) {
+        Some(id) => format!("{} {}", tokenizer.id_to_token(id).unwrap_or_default(), text),
+        None => text,
+    };
+    let mut tokens = tokenizer.encode(text, true)
+        .map_err(LuaError::external)?
+        .get_ids()
+        .to_vec();
+    tokens.push(config.eos_token_id);
+    let input_ids = Tensor::new(&tokens[..], &device)
+        .map_err(LuaError::external)?
+        .unsqueeze(0)
+        .map_err(LuaError::external)?;
+
+    let mut chain = LogitsChain::from_table(table)?;
+    chain.temperature = table.get::<_, Option<f64>>("temperature")?.unwrap_or(0.);
+    chain.repeat_penalty = table.get::<_, Option<f32>>("repeat_penalty")?.unwrap_or(1.);
+    let seed = table.get::<_, Option<u64>>("seed")?.unwrap_or_else(message_seed);
+    let mut logits_processor = chain.logits_processor(seed);
+    let eos_token_ids = [config.eos_token_id, config.forced_eos_token_id];
+    let max_new_tokens: usize = table.get("max_new_tokens").unwrap_or(512usize);
+    let mut finish_reason = "length";
+
+    let start = std::time::Instant::now();
+    let encoder_xs = model.encoder().forward(&input_ids, 0).map_err(LuaError::external)?;
+    let mut token_ids = vec![config.decoder_start_token_id];
+    for index in 0..max_new_tokens {
+        // Later steps only feed the newest token, the rest is in the KV cache.
+        let context_size = if index >= 1 { 1 } else { token_ids.len() };
+        let start_pos = token_ids.len().saturating_sub(context_size);
+        let decoder_ids = Tensor::new(&token_ids[start_pos..], &device)
+            .map_err(LuaError::external)?
+            .unsqueeze(0)
+            .map_err(LuaError::external)?;
+        let logits = model.decode(&decoder_ids, &encoder_xs, start_pos)
+            .and_then(|logits| logits.squeeze(0))
+            .and_then(|logits| logits.get(logits.dim(0)? - 1))
+            .map_err(LuaError::external)?;
+        let logits = chain.process(logits, &token_ids, 1, &eos_token_ids)?;
+        let token = logits_processor.sample(&logits).map_err(LuaError::external)?;
+        if token == config.eos_token_id || token == config.forced_eos_token_id {
+            finish_reason = "eos";
+            break;
+        }
+        token_ids.push(token);
+        if stream.is_active() {
+            let logprob = candle_nn::ops::log_softmax(&logits, candle_core::D::Minus1)
+                .and_then(|logprobs| logprobs.get(token as usize))
+                .and_then(|logprob| logprob.to_scalar::<f32>())
+                .map_err(LuaError::external)?;
+            if !stream.push(lua, target_tokenizer, &token_ids[1..], logprob, &[])? {
+                finish_reason = "callback";
+                break;
+            }
+        }
+    }
+    let dt = start.elapsed();
+    let generated = &token_ids[1..];
+    let text = target_tokenizer.decode(generated, true).map_err(LuaError::external)?;
+    ao_log(&format!(
+        "{} tokens translated ({:.2} token/s)",
+        generated.len(),
+        generated.len() as f64 / dt.as_secs_f64(),
+    ));
+    Ok(TranslationOutput {
+        text: text.trim().to_string(),
+        src_lang: src_lang.map(|lang| lang.code),
+        tgt_lang: tgt_lang.code,
+        family: Family::Marian.name(),
+        prompt_tokens: tokens.len(),
+        generated_tokens: generated.len(),
+        finish_reason: finish_reason.to_string(),
+        elapsed: dt.as_secs_f64(),
+        tokens_per_second: generated.len() as f64 / dt.as_secs_f64(),
+    })
+}
+
+/// Lua entry point, `translation.translate(opts)`.
+///
+/// `opts.text` (or `opts.prompt`) is translated into `opts.tgt_lang` from `opts.src_lang`,
+/// both given as codes (`de`, `deu`) or English names.  `opts.family` (`t5`, `flan-t5`, `mt5`
+/// or `marian`) defaults to the `model_type` of `config.json`.  T5 models need `src_lang`
+/// for their `translate X to Y:` prefix; Marian pairs may pass a `target_tokenizer` when the
+/// target vocabulary has its own `tokenizer.json`.
+///
+/// Decoding is greedy unless `temperature` is set.  T5 models take every `t5.generate`
+/// option, Marian models `max_new_tokens`, the sampling and penalty options, `seed` and
+/// `on_token`.  Returns
+/// `{ text, src_lang, tgt_lang, family, prompt_tokens, generated_tokens, finish_reason,
+/// elapsed, tokens_per_second }`.
+pub fn translate(lua: &Lua, table: LuaTable) -> LuaResult<LuaValue> {
+    let text: String = match table.get::<_, Option<String>>("text")? {
+        Some(text) => text,
+        None => table.get("prompt")?,
+    };
+    let src_lang = table.get::<_, Option<String>>("src_lang")?.map(|lang| Language::parse(&lang));
+    let tgt_lang = Language::parse(&table.get::<_, String>("tgt_lang")?);
+    let family = match table.get::<_, Option<String>>("family")? {
+        Some(family) => Family::from_str(&family)?,
+        None => Family::from_config(&load_bytes(table.get("config")?, "config")?)?,
+    };
+    let stream = TokenStream::new(table.get("on_token")?);
+    let output = match family {
+        Family::Marian => translate_marian(lua, &table, text, src_lang, tgt_lang, stream)?,
+        _ => {
+            // `t5::Args::from_table` reads the text from `prompt`, on a copy of the options.
+            let options = lua.create_table()?;
+            for pair in table.pairs::<LuaValue, LuaValue>() {
+                let (key, value) = pair?;
+                options.set(key, value)?;
+            }
+            options.set("prompt", text.as_str())?;
+            translate_t5(lua, &options, family, text, src_lang, tgt_lang, stream)?
+        }
+    };
+    lua.to_value(&output)
+}
+
+pub fn preload(lua: &Lua) -> LuaResult<()> {
+    let package: LuaTable = lua.globals().get("package")?;
+    let loaded: LuaTable = package.get("loaded")?;
+    let translation_module_table = lua.create_table()?;
+    translation_module_table.set("translate", lua.create_function(translate)?)?;
+    loaded.set("translation", translation_module_table)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokenizers::models::wordlevel::WordLevel;
+
+    fn tokenizer(tokens: &[&str]) -> Tokenizer {
+        let vocab = std::iter::once("<unk>")
+            .chain(tokens.iter().copied())
+            .enumerate()
+            .map(|(id, token)| (token.to_string(), id as u32))
+            .collect();
+        Tokenizer::new(WordLevel::builder().vocab(vocab).unk_token("<unk>".to_string()).build().unwrap())
+    }
+
+    #[test]
+    fn test_language_parse() {
+        for lang in ["de", "deu", "German", "GERMAN"] {
+            let parsed = Language::parse(lang);
+            assert_eq!((parsed.code.as_str(), parsed.iso3, parsed.name.as_str()), (lang, Some("deu"), "German"));
+        }
+        let klingon = Language::parse("tlh");
+        assert_eq!((klingon.iso3, klingon.name.as_str()), (None, "tlh"));
+        assert_eq!(Language::parse("fr").codes(), vec!["fr", "fra"]);
+        assert_eq!(klingon.codes(), vec!["tlh"]);
+    }
+
+    #[test]
+    fn test_language_token() {
+        // Multilingual Marian models tag the target with ISO 639-3 codes.
+        let marian = tokenizer(&[">>fra<<", ">>deu<<"]);
+        assert_eq!(language_token(&marian, &Language::parse("fr")), Some(1));
+        assert_eq!(language_token(&marian, &Language::parse("German")), Some(2));
+        assert_eq!(language_token(&marian, &Language::parse("es")), None);
+        // mT5 fine-tunes spell it in several ways, the code as given goes first.
+        let mt5 = tokenizer(&["<2de>", "__fr__", "<es>", "<2deu>"]);
+        assert_eq!(language_token(&mt5, &Language::parse("de")), Some(1));
+        assert_eq!(language_token(&mt5, &Language::parse("fr")), Some(2));
+        assert_eq!(language_token(&mt5, &Language::parse("es")), Some(3));
+        assert_eq!(language_token(&mt5, &Language::parse("deu")), Some(4));
+    }
+
+    #[test]
+    fn test_family_and_prompt() {
+        assert_eq!(Family::from_str("Flan-T5").unwrap(), Family::T5);
+        assert!(Family::from_str("bart").is_err());
+        assert_eq!(Family::from_config(br#"{"model_type": "marian"}"#).unwrap(), Family::Marian);
+        assert!(Family::from_config(br#"{"model_type": "bart"}"#).is_err());
+
+        let (en, de) = (Language::parse("en"), Language::parse("de"));
+        let prompt = t5_prompt(Family::T5, "Hi".to_string(), Some(&en), &de, false).unwrap();
+        assert_eq!(prompt, "translate English to German: Hi");
+        assert_eq!(t5_prompt(Family::Mt5, "Hi".to_string(), None, &de, true).unwrap(), "Hi");
+        assert!(t5_prompt(Family::Mt5, "Hi".to_string(), None, &de, false).is_err());
+    }
+}
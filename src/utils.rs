@@ -1,4 +1,61 @@
 use mlua::prelude::*;
+use std::sync::Mutex;
+use lazy_static::lazy_static;
+
+lazy_static! {
+    /// Seed derived from the message currently being handled, see `set_message_seed`.
+    static ref MESSAGE_SEED: Mutex<u64> = Mutex::new(0);
+    /// `Timestamp` (ms) of the message currently being handled, see `set_message_seed`.
+    static ref MESSAGE_TIMESTAMP: Mutex<u64> = Mutex::new(0);
+}
+
+/// 64-bit FNV-1a, used instead of `DefaultHasher` so seeds stay the same across Rust releases.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| (hash ^ *b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Derives the default sampling seed from the incoming message JSON (its `Id`, or the whole
+/// message when it has none), so every model run is reproducible per message.  Also keeps
+/// the message `Timestamp`, the only clock a process can rely on.
+pub fn set_message_seed(msg_json: &str) {
+    let msg = serde_json::from_str::<serde_json::Value>(msg_json).ok();
+    let seed = match msg.as_ref().and_then(|msg| msg.get("Id")).and_then(|id| id.as_str()) {
+        Some(id) => fnv1a(id.as_bytes()),
+        None => fnv1a(msg_json.as_bytes()),
+    };
+    // AO sends the timestamp as a number or, in some message formats, a string.
+    let timestamp = match msg.as_ref().and_then(|msg| msg.get("Timestamp")) {
+        Some(serde_json::Value::Number(n)) => n.as_u64(),
+        Some(serde_json::Value::String(s)) => s.parse().ok(),
+        _ => None,
+    };
+    match MESSAGE_SEED.lock() {
+        Ok(mut guard) => *guard = seed,
+        Err(poisoned) => *poisoned.into_inner() = seed,
+    }
+    if let Some(timestamp) = timestamp {
+        match MESSAGE_TIMESTAMP.lock() {
+            Ok(mut guard) => *guard = timestamp,
+            Err(poisoned) => *poisoned.into_inner() = timestamp,
+        }
+    }
+}
+
+/// The seed for the message being handled, `0` outside of `handle`.
+pub fn message_seed() -> u64 {
+    match MESSAGE_SEED.lock() {
+        Ok(guard) => *guard,
+        Err(poisoned) => *poisoned.into_inner(),
+    }
+}
+
+/// The `Timestamp` of the latest message which had one, `0` before any.
+pub fn message_timestamp() -> u64 {
+    match MESSAGE_TIMESTAMP.lock() {
+        Ok(guard) => *guard,
+        Err(poisoned) => *poisoned.into_inner(),
+    }
+}
 
 pub fn preload_serde_json(lua: &Lua) -> LuaResult<()> {
     let serde_json_table = lua.create_table()?;
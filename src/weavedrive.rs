@@ -86,6 +86,33 @@ pub fn close(_fd: i32) -> i32 {
     0
 }
 
+/// Reads a whole WeaveDrive file (e.g. `/data/<tx id>`) into memory.
+///
+/// Returns `None` if the file cannot be opened or a read fails.
+pub fn read_to_end(filename: &str) -> Option<Vec<u8>> {
+    let fd = open(filename, "r");
+    if fd <= 0 {
+        return None;
+    }
+    let chunk_size = 1024 * 1024;
+    let mut buffer = Vec::new();
+    let mut chunk = vec![0u8; chunk_size];
+    loop {
+        let bytes_read = read(fd, &mut chunk);
+        if bytes_read < 0 {
+            close(fd);
+            return None;
+        }
+        if bytes_read == 0 {
+            break;
+        }
+        // A short read is not the end of the file, only a read of 0 bytes is.
+        buffer.extend_from_slice(&chunk[..bytes_read as usize]);
+    }
+    close(fd);
+    Some(buffer)
+}
+
 pub fn preload(lua: &Lua) -> LuaResult<()> {
     let wd_table = lua.create_table()?;
     wd_table.set("_version", "0.0.1")?;